@@ -81,7 +81,7 @@ pub fn flash_spi_master(
         hal::sercom::Sercom3Pad2<gpio::Pa20<gpio::PfD>>,
         hal::sercom::Sercom3Pad3<gpio::Pa21<gpio::PfD>>,
     >, gpio::Pb22<Output<PushPull>>) {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     let flash = SPIMaster3::new(
         &clocks.sercom3_core(&gclk0).unwrap(),
         48.mhz(),
@@ -117,9 +117,10 @@ pub fn i2c_master<F: Into<Hertz>>(
         hal::sercom::Sercom5Pad0<gpio::Pb2<gpio::PfD>>,
         hal::sercom::Sercom5Pad1<gpio::Pb3<gpio::PfD>>,
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom5_core(&gclk0).unwrap();
     I2CMaster5::new(
-        &clocks.sercom5_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom5,
         pm,
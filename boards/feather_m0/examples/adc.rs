@@ -30,7 +30,7 @@ fn main() -> ! {
     let mut pins = hal::Pins::new(peripherals.PORT);
     let mut delay = hal::delay::Delay::new(core.SYST, &mut clocks);
     let mut adc = Adc::adc(peripherals.ADC, &mut peripherals.PM, &mut clocks);
-    let mut a0 = pins.a0.into_function_b(&mut pins.port);
+    let mut a0 = pins.a0.into_analog(&mut pins.port);
 
     loop {
         let data: u16 = adc.read(&mut a0).unwrap();
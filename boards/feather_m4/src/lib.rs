@@ -109,9 +109,10 @@ pub fn spi_master<F: Into<Hertz>>(
         hal::sercom::Sercom1Pad3<gpio::Pb23<gpio::PfC>>,
         hal::sercom::Sercom1Pad1<gpio::Pa17<gpio::PfC>>
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom1_core(&gclk0).unwrap();
     SPIMaster1::new(
-        &clocks.sercom1_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         hal::hal::spi::Mode {
             phase: hal::hal::spi::Phase::CaptureOnFirstTransition,
@@ -137,9 +138,10 @@ pub fn i2c_master<F: Into<Hertz>>(
         hal::sercom::Sercom2Pad0<gpio::Pa12<gpio::PfC>>,
         hal::sercom::Sercom2Pad1<gpio::Pa13<gpio::PfC>>
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom2_core(&gclk0).unwrap();
     I2CMaster2::new(
-        &clocks.sercom2_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom2,
         mclk,
@@ -162,10 +164,11 @@ pub fn uart<F: Into<Hertz>>(
         hal::sercom::Sercom5Pad1<gpio::Pb17<PfC>>,
         hal::sercom::Sercom5Pad0<gpio::Pb16<PfC>>, (), ()
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom5_core(&gclk0).unwrap();
 
     UART5::new(
-        &clocks.sercom5_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom5,
         mclk,
@@ -182,7 +185,7 @@ pub fn usb_bus(
     dp: gpio::Pa25<Input<Floating>>,
     port: &mut Port,
 ) -> UsbBusWrapper<UsbBus> {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     dbgprint!("making usb clock");
     let usb_clock = &clocks.usb(&gclk0).unwrap();
     dbgprint!("got clock");
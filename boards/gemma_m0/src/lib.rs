@@ -55,10 +55,11 @@ pub fn uart<F: Into<Hertz>>(
     port: &mut Port,
 ) -> UART0<hal::sercom::Sercom0Pad1<gpio::Pa5<PfD>>, hal::sercom::Sercom0Pad0<gpio::Pa4<PfD>>, (), ()>
 {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom0_core(&gclk0).unwrap();
 
     UART0::new(
-        &clocks.sercom0_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom0,
         pm,
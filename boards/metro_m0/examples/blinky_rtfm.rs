@@ -56,7 +56,7 @@ const APP: () = {
             &mut device.SYSCTRL,
             &mut device.NVMCTRL,
         );
-        let gclk0 = clocks.gclk0();
+        let gclk0 = clocks.gclk0().unwrap();
         let mut pins = hal::Pins::new(device.PORT);
 
         let mut tc3 = hal::timer::TimerCounter::tc3_(
@@ -72,7 +72,6 @@ const APP: () = {
             &mut device.SYSCTRL,
             &mut device.NVMCTRL,
         );
-        let gclk0 = clocks.gclk0();
         let mut pins = hal::Pins::new(device.PORT);
 
         let mut delay = Delay::new(core.SYST, &mut clocks);
@@ -187,6 +186,7 @@ const APP: () = {
             Ok(val) => dbgprint!("read intmaska {:x}", val),
         };
 
+        let gclk0 = clocks.gclk0().unwrap();
         let mut tc3 = hal::timer::TimerCounter::tc3_(
             &clocks.tcc2_tc3(&gclk0).unwrap(),
             device.TC3,
@@ -125,9 +125,10 @@ pub fn spi_master<F: Into<Hertz>>(
         hal::sercom::Sercom4Pad2<gpio::Pb10<gpio::PfD>>,
         hal::sercom::Sercom4Pad3<gpio::Pb11<gpio::PfD>>
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom4_core(&gclk0).unwrap();
     SPIMaster4::new(
-        &clocks.sercom4_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         hal::hal::spi::Mode {
             phase: hal::hal::spi::Phase::CaptureOnFirstTransition,
@@ -158,7 +159,7 @@ pub fn flash_spi_master(
     >,
     hal::gpio::Pa13<hal::gpio::Output<hal::gpio::PushPull>>
 ) {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     let flash = SPIMaster5::new(
         &clocks.sercom5_core(&gclk0).unwrap(),
         48.mhz(),
@@ -191,9 +192,10 @@ pub fn i2c_master<F: Into<Hertz>>(
     scl: gpio::Pa23<Input<Floating>>,
     port: &mut Port,
 ) -> hal::sercom::I2CMaster3<hal::sercom::Sercom3Pad0<gpio::Pa22<gpio::PfC>>, hal::sercom::Sercom3Pad1<gpio::Pa23<gpio::PfC>>> {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom3_core(&gclk0).unwrap();
     I2CMaster3::new(
-        &clocks.sercom3_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom3,
         pm,
@@ -218,10 +220,11 @@ pub fn uart<F: Into<Hertz>>(
     (),
     (),
 > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom0_core(&gclk0).unwrap();
 
     UART0::new(
-        &clocks.sercom0_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom0,
         pm,
@@ -238,7 +241,7 @@ pub fn usb_bus(
     dp: gpio::Pa25<Input<Floating>>,
     port: &mut Port,
 ) -> UsbBusWrapper<UsbBus> {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     dbgprint!("making usb clock");
     let usb_clock = &clocks.usb(&gclk0).unwrap();
     dbgprint!("got clock");
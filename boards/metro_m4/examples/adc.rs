@@ -29,9 +29,9 @@ fn main() -> ! {
     let mut pins = hal::Pins::new(peripherals.PORT);
     let mut delay = hal::delay::Delay::new(core.SYST, &mut clocks);
     let mut adc0 = Adc::adc0(peripherals.ADC0, &mut peripherals.MCLK, &mut clocks);
-    let mut a0 = pins.a0.into_function_b(&mut pins.port);
+    let mut a0 = pins.a0.into_analog(&mut pins.port);
 
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     let rx: Sercom3Pad1<_> = pins
         .d0
         .into_pull_down_input(&mut pins.port)
@@ -7,7 +7,7 @@ extern crate embedded_hal;
 extern crate cortex_m_rt;
 extern crate nb;
 
-use crate::hal::clock::GenericClockController;
+use crate::hal::clock::{GclkConfig, GenericClockController};
 use crate::hal::pac::Peripherals;
 use crate::hal::pac::gclk::genctrl::SRC_A::DPLL0;
 use crate::hal::pac::gclk::pchctrl::GEN_A::GCLK2;
@@ -25,9 +25,13 @@ fn main() -> ! {
     );
     let mut pins = hal::Pins::new(peripherals.PORT);
 
-    let _gclk2 = clocks
-        .configure_gclk_divider_and_source(GCLK2, 12, DPLL0, false)
+    // 120MHz / 2^(2+1) = 15MHz
+    let gclk2 = clocks
+        .configure_gclk(GCLK2, GclkConfig::new(DPLL0).exponential_divider(2))
+        .unwrap();
+    let _gclk_out = clocks
+        .enable_gclk_out(&gclk2, pins.d13, &mut pins.port)
+        .ok()
         .unwrap();
-    pins.d13.into_function_m(&mut pins.port);
     loop {}
 }
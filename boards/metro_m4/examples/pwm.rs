@@ -26,7 +26,7 @@ fn main() -> ! {
     let mut pins = hal::Pins::new(peripherals.PORT);
 
     let d12 = pins.d12.into_function_e(&mut pins.port);
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     let mut pwm2 = Pwm2::new(
         &clocks.tc2_tc3(&gclk0).unwrap(),
         1.khz(),
@@ -32,7 +32,7 @@ fn main() -> ! {
 
     let mut delay = Delay::new(core.SYST, &mut clocks);
     let mut pins = hal::Pins::new(peripherals.PORT);
-    let gclk = clocks.gclk0();
+    let gclk = clocks.gclk0().unwrap();
 
     let mut spi: hal::sercom::SPIMaster3<
             hal::sercom::Sercom3Pad1<hal::gpio::Pa23<hal::gpio::PfC>>,
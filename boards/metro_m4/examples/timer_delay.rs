@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+extern crate cortex_m;
+extern crate cortex_m_rt;
+extern crate embedded_hal;
+extern crate metro_m4 as hal;
+extern crate panic_halt;
+
+use crate::hal::clock::GenericClockController;
+use crate::hal::delay::TimerDelay;
+use crate::hal::pac::{interrupt, Peripherals};
+use crate::hal::timer::TimerCounter;
+use cortex_m::peripheral::NVIC;
+use cortex_m_rt::entry;
+use hal::prelude::*;
+
+#[entry]
+fn main() -> ! {
+    let mut peripherals = Peripherals::take().unwrap();
+    let mut clocks = GenericClockController::with_external_32kosc(
+        peripherals.GCLK,
+        &mut peripherals.MCLK,
+        &mut peripherals.OSC32KCTRL,
+        &mut peripherals.OSCCTRL,
+        &mut peripherals.NVMCTRL,
+    );
+    let mut pins = hal::Pins::new(peripherals.PORT);
+    let mut red_led = pins.d13.into_open_drain_output(&mut pins.port);
+
+    let gclk0 = clocks.gclk0().unwrap();
+    let timer_clock = clocks.tc2_tc3(&gclk0).unwrap();
+    let timer = TimerCounter::tc3_(&timer_clock, peripherals.TC3, &mut peripherals.MCLK);
+
+    // The core sleeps until TC3 wakes it, so its interrupt has to reach
+    // the NVIC
+    unsafe { NVIC::unmask(interrupt::TC3) };
+    let mut delay = TimerDelay::new(timer).sleep_while_waiting(true);
+    loop {
+        delay.delay_ms(200u8);
+        red_led.set_high().unwrap();
+        delay.delay_ms(200u8);
+        red_led.set_low().unwrap();
+    }
+}
+
+#[interrupt]
+fn TC3() {
+    // TimerDelay has already cleared the flag by the time this runs
+}
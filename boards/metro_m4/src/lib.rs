@@ -126,9 +126,10 @@ pub fn spi_master<F: Into<Hertz>>(
         hal::sercom::Sercom2Pad0<gpio::Pa12<gpio::PfC>>,
         hal::sercom::Sercom2Pad1<gpio::Pa13<gpio::PfC>>
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom2_core(&gclk0).unwrap();
     SPIMaster2::new(
-        &clocks.sercom2_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         hal::hal::spi::Mode {
             phase: hal::hal::spi::Phase::CaptureOnFirstTransition,
@@ -154,9 +155,10 @@ pub fn i2c_master<F: Into<Hertz>>(
         hal::sercom::Sercom5Pad0<gpio::Pb2<gpio::PfD>>,
         hal::sercom::Sercom5Pad1<gpio::Pb3<gpio::PfD>>,
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom5_core(&gclk0).unwrap();
     I2CMaster5::new(
-        &clocks.sercom5_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom5,
         mclk,
@@ -179,10 +181,11 @@ pub fn uart<F: Into<Hertz>>(
         hal::sercom::Sercom3Pad1<gpio::Pa23<PfC>>,
         hal::sercom::Sercom3Pad0<gpio::Pa22<PfC>>, (), ()
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom3_core(&gclk0).unwrap();
 
     UART3::new(
-        &clocks.sercom3_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom3,
         mclk,
@@ -50,9 +50,10 @@ pub fn spi_master<F: Into<Hertz>>(
     hal::sercom::Sercom1Pad3<gpio::Pb23<gpio::PfC>>,
     hal::sercom::Sercom1Pad1<gpio::Pa17<gpio::PfC>>,
 > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom1_core(&gclk0).unwrap();
     SPIMaster1::new(
-        &clocks.sercom1_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         hal::hal::spi::Mode {
             phase: hal::hal::spi::Phase::CaptureOnFirstTransition,
@@ -92,7 +93,7 @@ pub fn display(
         atsamd_hal::samd51::pwm::Pwm2),
     (),
 > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().ok_or(())?;
     let tft_spi = SPIMaster4::new(
         &clocks.sercom4_core(&gclk0).ok_or(())?,
         16.mhz(),
@@ -114,7 +115,6 @@ pub fn display(
 
     let tft_dc = tft_dc.into_push_pull_output(port);
     let tft_reset = tft_reset.into_push_pull_output(port);
-    let gclk0 = clocks.gclk0();
 
     let mut display = st7735_lcd::ST7735::new(tft_spi, tft_dc, tft_reset, true, false);
     display.init(delay)?;
@@ -148,9 +148,10 @@ pub fn i2c_master<F: Into<Hertz>>(
     hal::sercom::Sercom2Pad0<gpio::Pa12<gpio::PfC>>,
     hal::sercom::Sercom2Pad1<gpio::Pa13<gpio::PfC>>,
 > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom2_core(&gclk0).unwrap();
     I2CMaster2::new(
-        &clocks.sercom2_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom2,
         mclk,
@@ -175,10 +176,11 @@ pub fn uart<F: Into<Hertz>>(
     (),
     (),
 > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom5_core(&gclk0).unwrap();
 
     UART5::new(
-        &clocks.sercom5_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom5,
         mclk,
@@ -131,9 +131,10 @@ pub fn spi_master<F: Into<Hertz>>(
         hal::sercom::Sercom0Pad2<gpio::Pa10<gpio::PfC>>,
         hal::sercom::Sercom0Pad3<gpio::Pa11<gpio::PfC>>
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom0_core(&gclk0).unwrap();
     SPIMaster0::new(
-        &clocks.sercom0_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         hal::hal::spi::Mode {
             phase: hal::hal::spi::Phase::CaptureOnFirstTransition,
@@ -159,9 +160,10 @@ pub fn i2c_master<F: Into<Hertz>>(
         hal::sercom::Sercom3Pad0<gpio::Pa22<gpio::PfC>>,
         hal::sercom::Sercom3Pad1<gpio::Pa23<gpio::PfC>>
     > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom3_core(&gclk0).unwrap();
     I2CMaster3::new(
-        &clocks.sercom3_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom3,
         pm,
@@ -179,7 +181,7 @@ pub fn usb_bus(
     dp: gpio::Pa25<Input<Floating>>,
     port: &mut Port,
 ) -> UsbBusWrapper<UsbBus> {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
     dbgprint!("making usb clock");
     let usb_clock = &clocks.usb(&gclk0).unwrap();
     dbgprint!("got clock");
@@ -111,10 +111,11 @@ pub fn uart<F: Into<Hertz>>(
     port: &mut Port,
 ) -> UART5<hal::sercom::Sercom5Pad1<gpio::Pb31<PfD>>, hal::sercom::Sercom5Pad0<gpio::Pb30<PfD>>, (), ()>
 {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom5_core(&gclk0).unwrap();
 
     UART5::new(
-        &clocks.sercom5_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom5,
         pm,
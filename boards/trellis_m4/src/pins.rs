@@ -198,9 +198,10 @@ impl Accelerometer {
         hal::sercom::Sercom2Pad0<Pa12<gpio::PfC>>,
         hal::sercom::Sercom2Pad1<Pa13<gpio::PfC>>,
     > {
-        let gclk0 = clocks.gclk0();
+        let gclk0 = clocks.gclk0().unwrap();
+        let clock = clocks.sercom2_core(&gclk0).unwrap();
         I2CMaster2::new(
-            &clocks.sercom2_core(&gclk0).unwrap(),
+            &clock,
             bus_speed.into(),
             sercom,
             mclk,
@@ -249,9 +250,10 @@ impl STEMMA {
         Sercom4Pad0<Pb8<PfD>>,
         Sercom4Pad1<Pb9<PfD>>,
     > {
-        let gclk0 = clocks.gclk0();
+        let gclk0 = clocks.gclk0().unwrap();
+        let clock = clocks.sercom4_core(&gclk0).unwrap();
         I2CMaster4::new(
-            &clocks.sercom4_core(&gclk0).unwrap(),
+            &clock,
             bus_speed.into(),
             sercom4,
             mclk,
@@ -272,10 +274,11 @@ impl STEMMA {
         mclk: &mut MCLK,
         port: &mut Port,
     ) -> UART4<Sercom4Pad1<Pb9<PfD>>, Sercom4Pad0<Pb8<PfD>>, (), ()> {
-        let gclk0 = clocks.gclk0();
+        let gclk0 = clocks.gclk0().unwrap();
+        let clock = clocks.sercom4_core(&gclk0).unwrap();
 
         UART4::new(
-            &clocks.sercom4_core(&gclk0).unwrap(),
+            &clock,
             baud.into(),
             sercom4,
             mclk,
@@ -71,10 +71,11 @@ pub fn uart<F: Into<Hertz>>(
     port: &mut Port,
 ) -> UART0<hal::sercom::Sercom0Pad3<gpio::Pa7<PfD>>, hal::sercom::Sercom0Pad2<gpio::Pa6<PfD>>, (), ()>
 {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom0_core(&gclk0).unwrap();
 
     UART0::new(
-        &clocks.sercom0_core(&gclk0).unwrap(),
+        &clock,
         baud.into(),
         sercom0,
         pm,
@@ -96,10 +97,11 @@ pub fn i2c_master<F: Into<Hertz>>(
     hal::sercom::Sercom2Pad0<gpio::Pa8<gpio::PfD>>,
     hal::sercom::Sercom2Pad1<gpio::Pa9<gpio::PfD>>,
 > {
-    let gclk0 = clocks.gclk0();
+    let gclk0 = clocks.gclk0().unwrap();
+    let clock = clocks.sercom2_core(&gclk0).unwrap();
 
     I2CMaster2::new(
-        &clocks.sercom2_core(&gclk0).unwrap(),
+        &clock,
         bus_speed.into(),
         sercom2,
         pm,
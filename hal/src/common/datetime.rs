@@ -0,0 +1,107 @@
+//! Calendar dates and times, as kept by the real time counter
+//!
+//! The RTC calendar of both chip families packs a `Datetime` into the
+//! same 32-bit layout, which `Datetime` converts to and from.
+
+/// The year that a `Datetime` year of 0 is stored as by the calendar.
+/// The calendar counts 64 years from here, and treats every year that
+/// is divisible by 4 as a leap year.
+pub const BASE_YEAR: u16 = 2000;
+
+/// A wall-clock date and time, in 24-hour format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Datetime {
+    /// The year, from `BASE_YEAR` to `BASE_YEAR + 63`
+    pub year: u16,
+    /// The month, from 1 to 12
+    pub month: u8,
+    /// The day of the month, from 1
+    pub day: u8,
+    /// The hours, from 0 to 23
+    pub hours: u8,
+    /// The minutes, from 0 to 59
+    pub minutes: u8,
+    /// The seconds, from 0 to 59
+    pub seconds: u8,
+}
+
+/// The field of a `Datetime` that is out of range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatetimeError {
+    Year,
+    Month,
+    Day,
+    Hours,
+    Minutes,
+    Seconds,
+}
+
+impl Datetime {
+    /// Checks that every field is within its range, including the
+    /// number of days in the month
+    pub fn validate(&self) -> Result<(), DatetimeError> {
+        if self.year < BASE_YEAR || self.year > BASE_YEAR + 63 {
+            return Err(DatetimeError::Year);
+        }
+        let days = match self.month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if self.year % 4 == 0 => 29,
+            2 => 28,
+            _ => return Err(DatetimeError::Month),
+        };
+        if self.day == 0 || self.day > days {
+            return Err(DatetimeError::Day);
+        }
+        if self.hours > 23 {
+            return Err(DatetimeError::Hours);
+        }
+        if self.minutes > 59 {
+            return Err(DatetimeError::Minutes);
+        }
+        if self.seconds > 59 {
+            return Err(DatetimeError::Seconds);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_bits(self) -> u32 {
+        (self.seconds as u32)
+            | (self.minutes as u32) << 6
+            | (self.hours as u32) << 12
+            | (self.day as u32) << 17
+            | (self.month as u32) << 22
+            | ((self.year - BASE_YEAR) as u32) << 26
+    }
+
+    pub(crate) fn from_bits(bits: u32) -> Self {
+        Self {
+            year: BASE_YEAR + (bits >> 26) as u16,
+            month: ((bits >> 22) & 0xf) as u8,
+            day: ((bits >> 17) & 0x1f) as u8,
+            hours: ((bits >> 12) & 0x1f) as u8,
+            minutes: ((bits >> 6) & 0x3f) as u8,
+            seconds: (bits & 0x3f) as u8,
+        }
+    }
+}
+
+/// The fields of a `Datetime` that have to match for the alarm to fire.
+/// Each variant also includes all of the fields of the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmMatch {
+    /// The alarm never fires
+    Off,
+    /// Fires once a minute
+    Seconds,
+    /// Fires once an hour
+    Minutes,
+    /// Fires once a day
+    Hours,
+    /// Fires once a month
+    Day,
+    /// Fires once a year
+    Month,
+    /// Fires once
+    Year,
+}
@@ -1,11 +1,18 @@
 //! Delays
+//!
+//! `Delay` busy-waits on the system timer (SysTick). `TimerDelay` waits
+//! on a timer counter or the RTC instead, which leaves SysTick free for
+//! an RTOS, and can sleep while waiting.
 
+use cortex_m::asm;
+use cortex_m::interrupt;
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
 
-use crate::clock::GenericClockController;
-use crate::time::Hertz;
+use crate::clock::{ClockGenId, GenericClockController};
+use crate::time::{Hertz, Microseconds, Milliseconds, Period};
 use hal::blocking::delay::{DelayMs, DelayUs};
+use hal::timer::CountDown;
 
 /// System timer (SysTick) as a delay provider
 pub struct Delay {
@@ -20,7 +27,31 @@ impl Delay {
 
         Delay {
             syst,
-            sysclock: clocks.gclk0().into(),
+            sysclock: clocks.gclk_freq(ClockGenId::GCLK0),
+        }
+    }
+
+    /// Blocks for the given period, which may be a span of time or a
+    /// frequency whose period is waited for
+    pub fn delay<P: Into<Period>>(&mut self, period: P) {
+        // The SysTick Reload Value register supports values between 1 and 0x00FFFFFF.
+        const MAX_RVR: u64 = 0x00FF_FFFF;
+
+        let mut total_rvr = period.into().ticks(self.sysclock);
+
+        while total_rvr != 0 {
+            let current_rvr = total_rvr.min(MAX_RVR);
+
+            self.syst.set_reload(current_rvr as u32);
+            self.syst.clear_current();
+            self.syst.enable_counter();
+
+            // Update the tracking variable while we are waiting...
+            total_rvr -= current_rvr;
+
+            while !self.syst.has_wrapped() {}
+
+            self.syst.disable_counter();
         }
     }
 
@@ -32,7 +63,7 @@ impl Delay {
 
 impl DelayMs<u32> for Delay {
     fn delay_ms(&mut self, ms: u32) {
-        self.delay_us(ms * 1_000);
+        self.delay(Milliseconds(ms));
     }
 }
 
@@ -50,39 +81,147 @@ impl DelayMs<u8> for Delay {
 
 impl DelayUs<u32> for Delay {
     fn delay_us(&mut self, us: u32) {
-        // The SysTick Reload Value register supports values between 1 and 0x00FFFFFF.
-        const MAX_RVR: u32 = 0x00FF_FFFF;
+        self.delay(Microseconds(us));
+    }
+}
 
-        let mut total_rvr = us * (self.sysclock.0 / 1_000_000);
+impl DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(us as u32)
+    }
+}
 
-        while total_rvr != 0 {
-            let current_rvr = if total_rvr <= MAX_RVR {
-                total_rvr
-            } else {
-                MAX_RVR
-            };
+impl DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(us as u32)
+    }
+}
 
-            self.syst.set_reload(current_rvr);
-            self.syst.clear_current();
-            self.syst.enable_counter();
+/// A timer that can wake the core with its interrupt when it expires
+pub trait InterruptDrivenTimer: CountDown<Time = Period> {
+    /// Enables the interrupt raised when the timer expires
+    fn enable_interrupt(&mut self);
 
-            // Update the tracking variable while we are waiting...
-            total_rvr -= current_rvr;
+    /// Disables the interrupt raised when the timer expires
+    fn disable_interrupt(&mut self);
+}
 
-            while !self.syst.has_wrapped() {}
+/// The longest span `TimerDelay` starts its timer for. Every timer
+/// reaches it: the 16-bit timer counters from clocks of up to 650MHz,
+/// and the RTC from clocks of 10Hz and up.
+const TIMER_DELAY_MAX_NANOS: u64 = 100_000_000;
+
+/// A timer counter or the RTC as a delay provider, leaving the system
+/// timer (SysTick) free.
+///
+/// Any of `TimerCounter`, `TimerCounter32` and the RTC in `Count32Mode`
+/// or `Count16Mode` can be used. The delays are as precise as the
+/// prescaler and the period of the timer allow.
+///
+/// By default the delay busy-waits. With `sleep_while_waiting` the core
+/// sleeps with WFI until the timer expires. The delay enables the
+/// interrupt of the timer for that, but it still has to be unmasked in
+/// the NVIC. Its handler runs each time the timer expires, which is at
+/// least every 100ms during long delays, and may be empty, as the delay
+/// has already dealt with the timer by then. Other interrupts are
+/// serviced as usual while the core waits.
+pub struct TimerDelay<T> {
+    timer: T,
+    sleep: bool,
+}
 
-            self.syst.disable_counter();
+impl<T> TimerDelay<T>
+where
+    T: InterruptDrivenTimer,
+{
+    /// Uses `timer` as a delay provider that busy-waits
+    pub fn new(timer: T) -> Self {
+        TimerDelay { timer, sleep: false }
+    }
+
+    /// Selects whether the core sleeps while waiting
+    pub fn sleep_while_waiting(mut self, sleep: bool) -> Self {
+        self.sleep = sleep;
+        self
+    }
+
+    /// Blocks for the given period, which may be a span of time or a
+    /// frequency whose period is waited for
+    pub fn delay<P: Into<Period>>(&mut self, period: P) {
+        let mut nanos = period.into().to_nanos();
+        while nanos != 0 {
+            let current = nanos.min(TIMER_DELAY_MAX_NANOS);
+            self.timer.start(Period::Nanoseconds(current));
+            if self.sleep {
+                // Starting a timer counter resets it, interrupt enables
+                // included, so enable the interrupt for every chunk
+                self.timer.enable_interrupt();
+                while !self.sleep_until_expired() {}
+            } else {
+                while self.timer.wait().is_err() {}
+            }
+            nanos -= current;
+        }
+        if self.sleep {
+            self.timer.disable_interrupt();
         }
     }
+
+    /// Sleeps until an interrupt wakes the core, and returns whether
+    /// the timer has expired.
+    /// The check and the WFI happen with interrupts masked, so that the
+    /// timer can't expire in between; a pending interrupt still wakes the
+    /// core. Expiring clears the flag of the timer, so its handler runs
+    /// once the interrupts are unmasked again and the NVIC no longer has
+    /// it pending for the next chunk of the delay.
+    fn sleep_until_expired(&mut self) -> bool {
+        let timer = &mut self.timer;
+        interrupt::free(|_| {
+            if timer.wait().is_ok() {
+                return true;
+            }
+            asm::wfi();
+            timer.wait().is_ok()
+        })
+    }
+
+    /// Releases the timer
+    pub fn free(self) -> T {
+        self.timer
+    }
 }
 
-impl DelayUs<u16> for Delay {
+impl<T: InterruptDrivenTimer> DelayMs<u32> for TimerDelay<T> {
+    fn delay_ms(&mut self, ms: u32) {
+        self.delay(Milliseconds(ms));
+    }
+}
+
+impl<T: InterruptDrivenTimer> DelayMs<u16> for TimerDelay<T> {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl<T: InterruptDrivenTimer> DelayMs<u8> for TimerDelay<T> {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl<T: InterruptDrivenTimer> DelayUs<u32> for TimerDelay<T> {
+    fn delay_us(&mut self, us: u32) {
+        self.delay(Microseconds(us));
+    }
+}
+
+impl<T: InterruptDrivenTimer> DelayUs<u16> for TimerDelay<T> {
     fn delay_us(&mut self, us: u16) {
         self.delay_us(us as u32)
     }
 }
 
-impl DelayUs<u8> for Delay {
+impl<T: InterruptDrivenTimer> DelayUs<u8> for TimerDelay<T> {
     fn delay_us(&mut self, us: u8) {
         self.delay_us(us as u32)
     }
@@ -7,15 +7,22 @@
 //! for the pin.   The pin configuration is reflected through the
 //! use of type states to make the interface (ideally, or at least practically)
 //! impossible to misuse.
-use crate::target_device::port::{DIRCLR, DIRSET, OUTCLR, OUTSET, PINCFG0_, PMUX0_};
+use crate::target_device::port::{
+    CTRL, DIRCLR, DIRSET, OUTCLR, OUTSET, OUTTGL, PINCFG0_, PMUX0_,
+};
 
 #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
 use crate::target_device::port::{PINCFG1_, PMUX1_};
 
 use crate::target_device::PORT;
+#[cfg(feature = "samd51")]
+use crate::target_device::{port::EVCTRL, EVSYS};
+use core::convert::TryFrom;
 use core::marker::PhantomData;
 use hal::digital::v2::OutputPin;
 
+#[cfg(feature = "unproven")]
+use crate::target_device::port::{IN, OUT};
 #[cfg(feature = "unproven")]
 use hal::digital::v2::{InputPin, StatefulOutputPin, ToggleableOutputPin};
 
@@ -52,6 +59,18 @@ pub struct PullDown;
 /// Pulled up Input
 pub struct PullUp;
 
+/// Represents a pin that is both an input and an output. It can be
+/// read while it is driven, and switched between driving the line and
+/// sensing it at run time, without changing its type.
+/// The MODE type is either `PushPull` or `OpenDrain`.
+pub struct IoPin<MODE> {
+    _mode: PhantomData<MODE>,
+}
+
+/// Represents a pin connected to the analog functions of the chip,
+/// the inputs and outputs of the ADC, DAC and AC
+pub struct Analog;
+
 /// Totem Pole aka Push-Pull
 pub struct PushPull;
 /// Open drain output
@@ -92,6 +111,31 @@ pub struct PfM;
 #[cfg(feature = "samd51")]
 pub struct PfN;
 
+/// One of the four event inputs of the PORT. Each input acts on at most
+/// one pin of each group, with no CPU involvement.
+#[cfg(feature = "samd51")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortEvent {
+    Ev0 = 0,
+    Ev1,
+    Ev2,
+    Ev3,
+}
+
+/// What an event arriving at an input of the PORT does to its pin
+#[cfg(feature = "samd51")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventAction {
+    /// The pin follows the level of the event
+    Output = 0,
+    /// The pin is driven high
+    Set,
+    /// The pin is driven low
+    Clear,
+    /// The pin is toggled
+    Toggle,
+}
+
 /// A trait that makes it easier to generically manage
 /// converting a pin from its current state into some
 /// other functional mode.  The configuration change
@@ -120,7 +164,9 @@ macro_rules! pin {
         $pinmux:ident,
         $out:ident,
         $outtgl:ident,
-        $in:ident
+        $in:ident,
+        $dir:ident,
+        $group:ident
     ) => {
         // Helper for pmux peripheral function configuration
         macro_rules! function {
@@ -291,6 +337,231 @@ macro_rules! pin {
 
                 $PinType { _mode: PhantomData }
             }
+
+            /// Configures the pin for its analog functions, as needed by
+            /// the ADC, DAC and AC. The digital input buffer is turned
+            /// off, which saves power while the voltage is in between
+            /// the logic levels.
+            pub fn into_analog(self, port: &mut Port) -> $PinType<Analog> {
+                // The analog functions are all routed through function B
+                self.into_function_b(port);
+
+                port.$dirclr().write(|bits| unsafe {
+                    bits.bits(1 << $pin_no);
+                    bits
+                });
+
+                port.$pincfg()[$pin_no].write(|bits| {
+                    bits.pmuxen().set_bit();
+                    bits.inen().clear_bit();
+                    bits.pullen().clear_bit();
+                    bits.drvstr().clear_bit();
+                    bits
+                });
+
+                $PinType { _mode: PhantomData }
+            }
+
+            /// Configures the pin as a push-pull input-output pin, which
+            /// starts out sensing the line. `set_driven` switches it to
+            /// driving the line.
+            pub fn into_push_pull_io(self, port: &mut Port) -> $PinType<IoPin<PushPull>> {
+                port.$dirclr().write(|bits| unsafe {
+                    bits.bits(1 << $pin_no);
+                    bits
+                });
+
+                port.$pincfg()[$pin_no].write(|bits| {
+                    bits.pmuxen().clear_bit();
+                    bits.inen().set_bit();
+                    bits.pullen().clear_bit();
+                    bits.drvstr().clear_bit();
+                    bits
+                });
+
+                $PinType { _mode: PhantomData }
+            }
+
+            /// Configures the pin as an open drain input-output pin, as
+            /// used by 1-Wire and similar buses. Setting it low drives
+            /// the line low, setting it high releases the line, and it
+            /// reads the level of the line either way. It starts out
+            /// released.
+            pub fn into_open_drain_io(self, port: &mut Port) -> $PinType<IoPin<OpenDrain>> {
+                port.$dirclr().write(|bits| unsafe {
+                    bits.bits(1 << $pin_no);
+                    bits
+                });
+
+                port.$pincfg()[$pin_no].write(|bits| {
+                    bits.pmuxen().clear_bit();
+                    bits.inen().set_bit();
+                    bits.pullen().clear_bit();
+                    bits.drvstr().clear_bit();
+                    bits
+                });
+
+                // While the pin is released, OUT selects a pull up
+                port.$outset().write(|bits| unsafe {
+                    bits.bits(1 << $pin_no);
+                    bits
+                });
+
+                $PinType { _mode: PhantomData }
+            }
+
+            /// Selects the high drive strength for the pin, which sources
+            /// and sinks more current when it drives the line
+            pub fn set_drive_strength(&mut self, port: &mut Port, high: bool) {
+                port.$pincfg()[$pin_no].modify(|_, bits| bits.drvstr().bit(high));
+            }
+
+            /// Samples the input continuously, rather than only when
+            /// the input register is read. This makes reads faster, and
+            /// is needed to read the pin through the IOBUS, but costs
+            /// power.
+            pub fn set_continuous_sampling(&mut self, port: &mut Port, continuous: bool) {
+                port.set_sampling(PinGroup::$group, 1 << $pin_no, continuous);
+            }
+        }
+
+        impl $PinType<IoPin<PushPull>> {
+            /// Switches between driving the line with the level last
+            /// set, and sensing it
+            pub fn set_driven(&mut self, driven: bool) {
+                unsafe {
+                    if driven {
+                        (*PORT::ptr()).$dirset.write(|bits| bits.bits(1 << $pin_no));
+                    } else {
+                        (*PORT::ptr()).$dirclr.write(|bits| bits.bits(1 << $pin_no));
+                    }
+                }
+            }
+
+            /// Returns true if the pin drives the line
+            pub fn is_driven(&self) -> bool {
+                unsafe { (((*PORT::ptr()).$dir.read().bits()) & (1 << $pin_no)) != 0 }
+            }
+        }
+
+        impl OutputPin for $PinType<IoPin<PushPull>> {
+            // TODO: switch to ! when it’s stable
+            type Error = ();
+
+            fn set_high(&mut self) -> Result<(), Self::Error> {
+                unsafe {
+                    (*PORT::ptr()).$outset.write(|bits| bits.bits(1 << $pin_no));
+                }
+                Ok(())
+            }
+
+            fn set_low(&mut self) -> Result<(), Self::Error> {
+                unsafe {
+                    (*PORT::ptr()).$outclr.write(|bits| bits.bits(1 << $pin_no));
+                }
+                Ok(())
+            }
+        }
+
+        #[cfg(feature = "unproven")]
+        impl StatefulOutputPin for $PinType<IoPin<PushPull>> {
+            fn is_set_high(&self) -> Result<bool, Self::Error> {
+                Ok(unsafe { (((*PORT::ptr()).$out.read().bits()) & (1 << $pin_no)) != 0 })
+            }
+
+            fn is_set_low(&self) -> Result<bool, Self::Error> {
+                Ok(unsafe { (((*PORT::ptr()).$out.read().bits()) & (1 << $pin_no)) == 0 })
+            }
+        }
+
+        impl $PinType<IoPin<OpenDrain>> {
+            /// Control state of the internal pull up, which pulls the
+            /// line up while the pin releases it
+            pub fn internal_pull_up(&mut self, port: &mut Port, on: bool) {
+                port.$pincfg()[$pin_no].modify(|_, bits| bits.pullen().bit(on));
+            }
+        }
+
+        impl OutputPin for $PinType<IoPin<OpenDrain>> {
+            // TODO: switch to ! when it’s stable
+            type Error = ();
+
+            /// Releases the line
+            fn set_high(&mut self) -> Result<(), Self::Error> {
+                unsafe {
+                    (*PORT::ptr()).$dirclr.write(|bits| bits.bits(1 << $pin_no));
+                    (*PORT::ptr()).$outset.write(|bits| bits.bits(1 << $pin_no));
+                }
+                Ok(())
+            }
+
+            /// Drives the line low
+            fn set_low(&mut self) -> Result<(), Self::Error> {
+                unsafe {
+                    (*PORT::ptr()).$outclr.write(|bits| bits.bits(1 << $pin_no));
+                    (*PORT::ptr()).$dirset.write(|bits| bits.bits(1 << $pin_no));
+                }
+                Ok(())
+            }
+        }
+
+        #[cfg(feature = "unproven")]
+        impl StatefulOutputPin for $PinType<IoPin<OpenDrain>> {
+            /// Returns true if the line is released
+            fn is_set_high(&self) -> Result<bool, Self::Error> {
+                Ok(unsafe { (((*PORT::ptr()).$dir.read().bits()) & (1 << $pin_no)) == 0 })
+            }
+
+            /// Returns true if the line is driven low
+            fn is_set_low(&self) -> Result<bool, Self::Error> {
+                Ok(unsafe { (((*PORT::ptr()).$dir.read().bits()) & (1 << $pin_no)) != 0 })
+            }
+        }
+
+        #[cfg(feature = "unproven")]
+        impl<MODE> InputPin for $PinType<IoPin<MODE>> {
+            // TODO: switch to ! when it’s stable
+            type Error = ();
+
+            fn is_high(&self) -> Result<bool, Self::Error> {
+                Ok(unsafe { (((*PORT::ptr()).$in.read().bits()) & (1 << $pin_no)) != 0 })
+            }
+
+            fn is_low(&self) -> Result<bool, Self::Error> {
+                Ok(unsafe { (((*PORT::ptr()).$in.read().bits()) & (1 << $pin_no)) == 0 })
+            }
+        }
+
+        impl<MODE> $PinType<MODE> {
+            /// Erases the number of the pin from its type, so that it can
+            /// be stored alongside other pins in the same mode
+            pub fn degrade(self) -> DynPin<MODE> {
+                DynPin {
+                    group: PinGroup::$group,
+                    num: $pin_no,
+                    _mode: PhantomData,
+                }
+            }
+        }
+
+        impl<MODE> From<$PinType<MODE>> for DynPin<MODE> {
+            fn from(pin: $PinType<MODE>) -> Self {
+                pin.degrade()
+            }
+        }
+
+        /// Succeeds if the `DynPin` is this pin, and hands it back
+        /// otherwise
+        impl<MODE> TryFrom<DynPin<MODE>> for $PinType<MODE> {
+            type Error = DynPin<MODE>;
+
+            fn try_from(pin: DynPin<MODE>) -> Result<Self, DynPin<MODE>> {
+                if pin.group == PinGroup::$group && pin.num == $pin_no {
+                    Ok($PinType { _mode: PhantomData })
+                } else {
+                    Err(pin)
+                }
+            }
         }
 
         impl $PinType<Output<OpenDrain>> {
@@ -314,6 +585,26 @@ macro_rules! pin {
                 self.toggle_impl();
             }
 
+            /// Lets `event` act on the pin, for example to toggle it on
+            /// each overflow of a timer with no jitter. An event input
+            /// acts on one pin of each group, so this takes `event` away
+            /// from any other pin of the group it acted on.
+            #[cfg(feature = "samd51")]
+            pub fn enable_event_action(
+                &mut self,
+                port: &mut Port,
+                event: PortEvent,
+                action: EventAction,
+            ) {
+                port.set_event_action(PinGroup::$group, event, $pin_no, Some(action));
+            }
+
+            /// Stops `event` from acting on the pin
+            #[cfg(feature = "samd51")]
+            pub fn disable_event_action(&mut self, port: &mut Port, event: PortEvent) {
+                port.set_event_action(PinGroup::$group, event, $pin_no, None);
+            }
+
             fn toggle_impl(&mut self) {
                 unsafe {
                     (*PORT::ptr()).$outtgl.write(|bits| {
@@ -391,12 +682,98 @@ macro_rules! pin {
     };
 }
 
+/// The number of channels of the event system
+#[cfg(feature = "samd51")]
+const NUM_EVENT_CHANNELS: u8 = 32;
+
 /// Opaque port reference
 pub struct Port {
-    _0: (),
+    /// The continuous sampling bits of each group, as CTRL can't be read
+    sampling: [u32; 2],
 }
 
 impl Port {
+    /// Returns the continuous sampling bits of a group
+    pub(crate) fn sampling(&self, group: PinGroup) -> u32 {
+        self.sampling[group as usize]
+    }
+
+    pub(crate) fn set_sampling(&mut self, group: PinGroup, mask: u32, continuous: bool) {
+        let index = group as usize;
+        if continuous {
+            self.sampling[index] |= mask;
+        } else {
+            self.sampling[index] &= !mask;
+        }
+        let sampling = self.sampling[index];
+        self.ctrl(group).write(|bits| unsafe { bits.sampling().bits(sampling) });
+    }
+
+    /// Connects `event` to the event system channel `channel`, so
+    /// that the events of the channel reach the pins `event` acts on.
+    /// The generator of the channel, such as a timer overflow or an RTC
+    /// compare match, is configured in the EVSYS by the application,
+    /// along with the event output of the generating peripheral.
+    /// The channel is handed back if the EVSYS doesn't have it.
+    #[cfg(feature = "samd51")]
+    pub fn connect_event_channel(
+        &mut self,
+        evsys: &mut EVSYS,
+        event: PortEvent,
+        channel: u8,
+    ) -> Result<(), u8> {
+        if channel >= NUM_EVENT_CHANNELS {
+            return Err(channel);
+        }
+        // The users PORT_EV0 to PORT_EV3 follow RTC_TAMPER, and channel
+        // n is selected by writing n + 1
+        evsys.user[1 + event as usize]
+            .write(|bits| unsafe { bits.channel().bits(channel + 1) });
+        Ok(())
+    }
+
+    /// Disconnects `event` from its event system channel
+    #[cfg(feature = "samd51")]
+    pub fn disconnect_event_channel(&mut self, evsys: &mut EVSYS, event: PortEvent) {
+        evsys.user[1 + event as usize].write(|bits| unsafe { bits.channel().bits(0) });
+    }
+
+    /// Replaces the pin, action and enable bit of `event` in the event
+    /// control register of `group`. Disabling only happens while the
+    /// input still acts on `pin`.
+    #[cfg(feature = "samd51")]
+    fn set_event_action(
+        &mut self,
+        group: PinGroup,
+        event: PortEvent,
+        pin: u32,
+        action: Option<EventAction>,
+    ) {
+        let shift = 8 * event as u32;
+        self.evctrl(group).modify(|r, bits| unsafe {
+            let current = (r.bits() >> shift) & 0xff;
+            let new = match action {
+                Some(action) => 0x80 | (action as u32) << 5 | pin,
+                None if current & 0x1f == pin => 0,
+                None => current,
+            };
+            bits.bits(r.bits() & !(0xff << shift) | new << shift)
+        });
+    }
+    #[cfg(feature = "samd51")]
+    fn evctrl(&mut self, group: PinGroup) -> &EVCTRL {
+        match group {
+            PinGroup::A => unsafe { &(*PORT::ptr()).evctrl0 },
+            PinGroup::B => unsafe { &(*PORT::ptr()).evctrl1 },
+        }
+    }
+    fn ctrl(&mut self, group: PinGroup) -> &CTRL {
+        match group {
+            PinGroup::A => unsafe { &(*PORT::ptr()).ctrl0 },
+            #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+            PinGroup::B => unsafe { &(*PORT::ptr()).ctrl1 },
+        }
+    }
     fn dirset0(&mut self) -> &DIRSET {
         unsafe { &(*PORT::ptr()).dirset0 }
     }
@@ -442,6 +819,144 @@ impl Port {
     }
 }
 
+/// The group of the PORT a pin belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinGroup {
+    /// The pins named PAxx
+    A,
+    /// The pins named PBxx
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+    B,
+}
+
+impl PinGroup {
+    #[cfg(feature = "unproven")]
+    fn out(self) -> &'static OUT {
+        match self {
+            PinGroup::A => unsafe { &(*PORT::ptr()).out0 },
+            #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+            PinGroup::B => unsafe { &(*PORT::ptr()).out1 },
+        }
+    }
+    fn outset(self) -> &'static OUTSET {
+        match self {
+            PinGroup::A => unsafe { &(*PORT::ptr()).outset0 },
+            #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+            PinGroup::B => unsafe { &(*PORT::ptr()).outset1 },
+        }
+    }
+    fn outclr(self) -> &'static OUTCLR {
+        match self {
+            PinGroup::A => unsafe { &(*PORT::ptr()).outclr0 },
+            #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+            PinGroup::B => unsafe { &(*PORT::ptr()).outclr1 },
+        }
+    }
+    fn outtgl(self) -> &'static OUTTGL {
+        match self {
+            PinGroup::A => unsafe { &(*PORT::ptr()).outtgl0 },
+            #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+            PinGroup::B => unsafe { &(*PORT::ptr()).outtgl1 },
+        }
+    }
+    #[cfg(feature = "unproven")]
+    fn in_(self) -> &'static IN {
+        match self {
+            PinGroup::A => unsafe { &(*PORT::ptr()).in0 },
+            #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
+            PinGroup::B => unsafe { &(*PORT::ptr()).in1 },
+        }
+    }
+}
+
+/// A pin whose group and number are only known at run time, obtained
+/// with `degrade`. Pins in the same mode share this type, so they can
+/// be kept in arrays and picked at run time. `TryFrom` turns it back
+/// into the pin with the matching name.
+pub struct DynPin<MODE> {
+    group: PinGroup,
+    num: u8,
+    _mode: PhantomData<MODE>,
+}
+
+impl<MODE> DynPin<MODE> {
+    /// The group of the PORT the pin belongs to
+    pub fn group(&self) -> PinGroup {
+        self.group
+    }
+
+    /// The number of the pin within its group
+    pub fn num(&self) -> u8 {
+        self.num
+    }
+
+    fn mask(&self) -> u32 {
+        1 << self.num
+    }
+}
+
+impl<MODE> DynPin<Output<MODE>> {
+    /// Toggle the logic level of the pin; if it is currently
+    /// high, set it low and vice versa.
+    pub fn toggle(&mut self) {
+        let mask = self.mask();
+        self.group.outtgl().write(|bits| unsafe { bits.bits(mask) });
+    }
+}
+
+impl<MODE> OutputPin for DynPin<Output<MODE>> {
+    // TODO: switch to ! when it’s stable
+    type Error = ();
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        let mask = self.mask();
+        self.group.outset().write(|bits| unsafe { bits.bits(mask) });
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        let mask = self.mask();
+        self.group.outclr().write(|bits| unsafe { bits.bits(mask) });
+        Ok(())
+    }
+}
+
+#[cfg(feature = "unproven")]
+impl<MODE> StatefulOutputPin for DynPin<Output<MODE>> {
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.out().read().bits() & self.mask() != 0)
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.out().read().bits() & self.mask() == 0)
+    }
+}
+
+#[cfg(feature = "unproven")]
+impl<MODE> ToggleableOutputPin for DynPin<Output<MODE>> {
+    // TODO: switch to ! when it’s stable
+    type Error = ();
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        DynPin::toggle(self);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "unproven")]
+impl<MODE> InputPin for DynPin<Input<MODE>> {
+    // TODO: switch to ! when it’s stable
+    type Error = ();
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.in_().read().bits() & self.mask() != 0)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.in_().read().bits() & self.mask() == 0)
+    }
+}
+
 macro_rules! port {
     ([
        $($PinTypeA:ident: ($pin_identA:ident, $pin_noA:expr),)+
@@ -471,7 +986,7 @@ impl GpioExt for PORT {
     /// Split the PORT peripheral into discrete pins
     fn split(self) -> Parts {
         Parts {
-            port: Port {_0: ()},
+            port: Port { sampling: [0; 2] },
             $(
                 $pin_identA: $PinTypeA { _mode: PhantomData },
             )+
@@ -485,12 +1000,12 @@ impl GpioExt for PORT {
 
 $(
     pin!($PinTypeA, $pin_identA, $pin_noA, dirset0, dirclr0,
-        pincfg0, outset0, outclr0, pmux0, out0, outtgl0, in0);
+        pincfg0, outset0, outclr0, pmux0, out0, outtgl0, in0, dir0, A);
 )+
 $(
     #[cfg(any(feature = "samd21g18a", feature="samd21j18a", feature = "samd51"))]
     pin!($PinTypeB, $pin_identB, $pin_noB, dirset1, dirclr1,
-        pincfg1, outset1, outclr1, pmux1, out1, outtgl1, in1);
+        pincfg1, outset1, outclr1, pmux1, out1, outtgl1, in1, dir1, B);
 )+
 
     };
@@ -1,5 +1,8 @@
+pub mod datetime;
 pub mod delay;
 pub mod gpio;
+pub mod monotonic;
+pub mod parallel;
 #[macro_use]
 pub mod pad;
 pub mod prelude;
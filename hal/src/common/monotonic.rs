@@ -0,0 +1,223 @@
+//! A monotonic time base for scheduling
+//!
+//! `Instant` and `Duration` count the ticks of a free running hardware
+//! counter. The 32-bit counter is extended to 64 bits by counting its
+//! overflows, so the time base doesn't wrap around in practice.
+//!
+//! The time bases themselves live with their hardware: the pairs of
+//! timer counters running as `TimerCounter32` provide one in `timer`
+//! (for example `Tc4Tc5Monotonic`), and the RTC provides `RtcMonotonic`
+//! in `rtc`. With the `rtfm` feature they implement `rtfm::Monotonic`,
+//! which lets `schedule` work on cores without a DWT cycle counter.
+//!
+//! Overflows are counted whenever the time is read, and by the
+//! interrupt handler of the counter, which has to call `on_interrupt`
+//! of the time base. As long as either happens at least once per
+//! period of the 32-bit counter, no overflow is missed.
+use core::convert::TryFrom;
+use core::num::TryFromIntError;
+use core::ops;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::interrupt;
+
+use crate::time::Hertz;
+
+/// A point in time, in ticks of a time base since it was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self { ticks }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// The time elapsed from `earlier` to `self`, or zero if `earlier`
+    /// is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_sub(earlier.ticks))
+    }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        Self::from_ticks(self.ticks + duration.ticks)
+    }
+}
+
+impl ops::AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        self.ticks += duration.ticks;
+    }
+}
+
+impl ops::Sub<Duration> for Instant {
+    type Output = Self;
+
+    fn sub(self, duration: Duration) -> Self {
+        Self::from_ticks(self.ticks - duration.ticks)
+    }
+}
+
+impl ops::SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        self.ticks -= duration.ticks;
+    }
+}
+
+impl ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A span of time, in ticks of a time base
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    ticks: u64,
+}
+
+impl Duration {
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self { ticks }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+}
+
+impl ops::Add for Duration {
+    type Output = Self;
+
+    fn add(self, other: Duration) -> Self {
+        Self::from_ticks(self.ticks + other.ticks)
+    }
+}
+
+impl ops::AddAssign for Duration {
+    fn add_assign(&mut self, other: Duration) {
+        self.ticks += other.ticks;
+    }
+}
+
+impl ops::Sub for Duration {
+    type Output = Self;
+
+    fn sub(self, other: Duration) -> Self {
+        Self::from_ticks(self.ticks - other.ticks)
+    }
+}
+
+impl ops::SubAssign for Duration {
+    fn sub_assign(&mut self, other: Duration) {
+        self.ticks -= other.ticks;
+    }
+}
+
+/// The scheduler converts durations to ticks of the system timer, which
+/// only has 24 bits, so durations that don't fit 32 bits are rejected.
+impl TryFrom<Duration> for u32 {
+    type Error = TryFromIntError;
+
+    fn try_from(duration: Duration) -> Result<u32, TryFromIntError> {
+        u32::try_from(duration.ticks)
+    }
+}
+
+/// The state a time base shares with its interrupt handler
+pub(crate) struct TimeBase {
+    overflows: AtomicU32,
+    freq: AtomicU32,
+    numerator: AtomicU32,
+    denominator: AtomicU32,
+}
+
+impl TimeBase {
+    pub(crate) const fn new() -> Self {
+        Self {
+            overflows: AtomicU32::new(0),
+            freq: AtomicU32::new(0),
+            numerator: AtomicU32::new(1),
+            denominator: AtomicU32::new(1),
+        }
+    }
+
+    /// Records the tick rate of the counter, and the ratio of the
+    /// system clock to it in reduced form
+    pub(crate) fn set_freq(&self, freq: Hertz, sysclock: Hertz) {
+        let (mut a, mut b) = (sysclock.0, freq.0);
+        while b != 0 {
+            let r = a % b;
+            a = b;
+            b = r;
+        }
+        let gcd = a.max(1);
+        self.freq.store(freq.0, Ordering::Relaxed);
+        self.numerator.store(sysclock.0 / gcd, Ordering::Relaxed);
+        self.denominator.store(freq.0.max(1) / gcd, Ordering::Relaxed);
+    }
+
+    pub(crate) fn freq(&self) -> Hertz {
+        Hertz(self.freq.load(Ordering::Relaxed))
+    }
+
+    /// The ratio of the system clock to the tick rate, as numerator and
+    /// denominator
+    #[cfg(feature = "rtfm")]
+    pub(crate) fn ratio(&self) -> (u32, u32) {
+        (
+            self.numerator.load(Ordering::Relaxed),
+            self.denominator.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Reads the 32-bit counter with `read`, and extends it to 64 bits.
+    /// `take_overflow` checks and clears the overflow flag of the counter.
+    pub(crate) fn now<R, O>(&self, read: R, take_overflow: O) -> Instant
+    where
+        R: Fn() -> u32,
+        O: Fn() -> bool,
+    {
+        interrupt::free(|_| {
+            let mut count = read();
+            let mut overflows = self.overflows.load(Ordering::Relaxed);
+            if take_overflow() {
+                overflows = overflows.wrapping_add(1);
+                self.overflows.store(overflows, Ordering::Relaxed);
+                // The counter may have overflowed after it was read
+                count = read();
+            }
+            Instant::from_ticks((overflows as u64) << 32 | count as u64)
+        })
+    }
+
+    /// Restarts the time at zero; `reset` zeroes the counter and clears
+    /// its overflow flag
+    pub(crate) fn reset<R: FnOnce()>(&self, reset: R) {
+        interrupt::free(|_| {
+            reset();
+            self.overflows.store(0, Ordering::Relaxed);
+        })
+    }
+
+    /// Converts a period in milliseconds to ticks
+    pub(crate) fn millis(&self, ms: u32) -> Duration {
+        Duration::from_ticks(ms as u64 * self.freq().0 as u64 / 1_000)
+    }
+
+    /// Converts a period in microseconds to ticks
+    pub(crate) fn micros(&self, us: u32) -> Duration {
+        Duration::from_ticks(us as u64 * self.freq().0 as u64 / 1_000_000)
+    }
+}
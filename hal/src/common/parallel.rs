@@ -0,0 +1,242 @@
+//! Reading and writing runs of pins at once
+//!
+//! A `ParallelBus` claims a contiguous run of pins within one group of
+//! the PORT, for example PA16 to PA23 for the data lines of an 8-bit
+//! 8080-style display, and moves all of them in one access through the
+//! single-cycle IOBUS of the PORT. Writes go through `OUTTGL`, so every
+//! line of the bus changes at the same moment, and the other pins of
+//! the group are left alone even if an interrupt changes them meanwhile.
+//!
+//! The pins are passed in as an array of `DynPin`s, from the lowest
+//! numbered pin up, and bit 0 of the values read and written is the
+//! first pin. The optional write and read strobes are active low, as
+//! on 8080-style buses. They are pulsed as quickly as the core can
+//! toggle them, so devices slower than that need their own timing,
+//! built from `write`, `read` and pins driven by the application.
+use crate::gpio::{DynPin, Output, PinGroup, Port, PushPull};
+use crate::target_device::port::{RegisterBlock, DIRCLR, DIRSET, IN, OUT, OUTCLR, OUTSET, OUTTGL};
+
+/// The pins a bus and its strobes are made of
+pub type BusPin = DynPin<Output<PushPull>>;
+
+/// The PORT registers on the IOBUS. The samd51 device crates don't
+/// describe that alias, but it lives at the same address as on the
+/// samd21.
+fn iobus() -> &'static RegisterBlock {
+    #[cfg(not(feature = "samd51"))]
+    let port = crate::target_device::PORT_IOBUS::ptr();
+    #[cfg(feature = "samd51")]
+    let port = 0x6000_0000 as *const RegisterBlock;
+    unsafe { &*port }
+}
+
+/// The registers of one group of the PORT
+struct Group {
+    dirset: &'static DIRSET,
+    dirclr: &'static DIRCLR,
+    out: &'static OUT,
+    outset: &'static OUTSET,
+    outclr: &'static OUTCLR,
+    outtgl: &'static OUTTGL,
+    in_: &'static IN,
+}
+
+impl Group {
+    fn new(group: PinGroup) -> Self {
+        let port = iobus();
+        match group {
+            PinGroup::A => Group {
+                dirset: &port.dirset0,
+                dirclr: &port.dirclr0,
+                out: &port.out0,
+                outset: &port.outset0,
+                outclr: &port.outclr0,
+                outtgl: &port.outtgl0,
+                in_: &port.in0,
+            },
+            #[cfg(any(feature = "samd21g18a", feature = "samd21j18a", feature = "samd51"))]
+            PinGroup::B => Group {
+                dirset: &port.dirset1,
+                dirclr: &port.dirclr1,
+                out: &port.out1,
+                outset: &port.outset1,
+                outclr: &port.outclr1,
+                outtgl: &port.outtgl1,
+                in_: &port.in1,
+            },
+        }
+    }
+}
+
+/// The arrays of pins a `ParallelBus` can be made of
+pub trait BusPins {
+    fn pins(&self) -> &[BusPin];
+}
+
+macro_rules! bus_pins {
+    ($($width:expr)+) => {
+        $(
+            impl BusPins for [BusPin; $width] {
+                fn pins(&self) -> &[BusPin] {
+                    self
+                }
+            }
+        )+
+    };
+}
+
+bus_pins!(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32);
+
+/// An active-low strobe line
+struct Strobe {
+    group: Group,
+    mask: u32,
+    pin: BusPin,
+}
+
+impl Strobe {
+    fn new(pin: BusPin) -> Self {
+        let group = Group::new(pin.group());
+        let mask = 1 << pin.num();
+        group.outset.write(|w| unsafe { w.bits(mask) });
+        Strobe { group, mask, pin }
+    }
+
+    fn assert(&self) {
+        self.group.outclr.write(|w| unsafe { w.bits(self.mask) });
+    }
+
+    fn release(&self) {
+        self.group.outset.write(|w| unsafe { w.bits(self.mask) });
+    }
+}
+
+/// A contiguous run of pins of one group, read and written at once
+pub struct ParallelBus<PINS> {
+    group: Group,
+    pin_group: PinGroup,
+    shift: u32,
+    mask: u32,
+    /// The pins of the bus that were sampled continuously already
+    sampled: u32,
+    pins: PINS,
+    write_strobe: Option<Strobe>,
+    read_strobe: Option<Strobe>,
+}
+
+impl<PINS: BusPins> ParallelBus<PINS> {
+    /// Claims the pins for a bus, driving them as outputs. The pins are
+    /// handed back if they aren't in one group, or aren't consecutive
+    /// from the first one up.
+    /// The IOBUS only sees the pins that are sampled continuously, so
+    /// that is enabled for the pins of the bus until it is freed.
+    pub fn new(pins: PINS, port: &mut Port) -> Result<Self, PINS> {
+        let (group, first) = match pins.pins().first() {
+            Some(pin) => (pin.group(), pin.num()),
+            None => return Err(pins),
+        };
+        let contiguous = pins
+            .pins()
+            .iter()
+            .enumerate()
+            .all(|(i, pin)| pin.group() == group && pin.num() as usize == first as usize + i);
+        if !contiguous {
+            return Err(pins);
+        }
+
+        let width = pins.pins().len() as u32;
+        let mask = (u32::max_value() >> (32 - width)) << first;
+        let sampled = port.sampling(group) & mask;
+        port.set_sampling(group, mask, true);
+        Ok(ParallelBus {
+            group: Group::new(group),
+            pin_group: group,
+            shift: first as u32,
+            mask,
+            sampled,
+            pins,
+            write_strobe: None,
+            read_strobe: None,
+        })
+    }
+
+    /// Adds an active-low write strobe, pulsed by `write_strobed` once
+    /// the value is on the bus
+    pub fn with_write_strobe(mut self, pin: BusPin) -> Self {
+        self.write_strobe = Some(Strobe::new(pin));
+        self
+    }
+
+    /// Adds an active-low read strobe, held low by `read_strobed` while
+    /// the bus is sampled
+    pub fn with_read_strobe(mut self, pin: BusPin) -> Self {
+        self.read_strobe = Some(Strobe::new(pin));
+        self
+    }
+
+    /// The number of pins of the bus
+    pub fn width(&self) -> usize {
+        self.pins.pins().len()
+    }
+
+    /// Drives the low `width` bits of `value` onto the bus. All the
+    /// lines change at once.
+    pub fn write(&mut self, value: u32) {
+        let out = self.group.out.read().bits();
+        let toggle = (out ^ (value << self.shift)) & self.mask;
+        self.group.outtgl.write(|w| unsafe { w.bits(toggle) });
+    }
+
+    /// Samples the bus, returning the state of its pins in the low
+    /// `width` bits. While the bus is driven, that's the value written.
+    pub fn read(&self) -> u32 {
+        (self.group.in_.read().bits() & self.mask) >> self.shift
+    }
+
+    /// Stops driving the bus, so that another device can drive it
+    pub fn release(&mut self) {
+        self.group.dirclr.write(|w| unsafe { w.bits(self.mask) });
+    }
+
+    /// Drives the bus again with the value last written
+    pub fn drive(&mut self) {
+        self.group.dirset.write(|w| unsafe { w.bits(self.mask) });
+    }
+
+    /// Writes `value` and pulses the write strobe, if there is one
+    pub fn write_strobed(&mut self, value: u32) {
+        self.write(value);
+        if let Some(strobe) = &self.write_strobe {
+            strobe.assert();
+            strobe.release();
+        }
+    }
+
+    /// Releases the bus and samples it while the read strobe, if there
+    /// is one, is low. The bus is driven again afterwards.
+    pub fn read_strobed(&mut self) -> u32 {
+        self.release();
+        if let Some(strobe) = &self.read_strobe {
+            strobe.assert();
+        }
+        let value = self.read();
+        if let Some(strobe) = &self.read_strobe {
+            strobe.release();
+        }
+        self.drive();
+        value
+    }
+
+    /// Releases the pins of the bus, driven again, and the strobes.
+    /// Continuous sampling is turned back off for the pins that weren't
+    /// sampled continuously before the bus claimed them.
+    pub fn free(mut self, port: &mut Port) -> (PINS, Option<BusPin>, Option<BusPin>) {
+        self.drive();
+        port.set_sampling(self.pin_group, self.mask & !self.sampled, false);
+        (
+            self.pins,
+            self.write_strobe.map(|strobe| strobe.pin),
+            self.read_strobe.map(|strobe| strobe.pin),
+        )
+    }
+}
@@ -1,4 +1,10 @@
 //! Time units
+//!
+//! Frequencies and spans of time convert into a `Period`, which is what
+//! the timers, the PWM drivers and `Delay` accept, so `250.ms()` and
+//! `4.hz()` can be used interchangeably there.
+use core::cmp::Ordering;
+use core::convert::TryFrom;
 
 /// Bits per second
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -16,6 +22,22 @@ pub struct KiloHertz(pub u32);
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct MegaHertz(pub u32);
 
+/// Nanoseconds
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Nanoseconds(pub u32);
+
+/// Microseconds
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Microseconds(pub u32);
+
+/// Milliseconds
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Milliseconds(pub u32);
+
+/// Seconds
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Seconds(pub u32);
+
 /// Extension trait that adds convenience methods to the `u32` type
 pub trait U32Ext {
     /// Wrap in `Bps`
@@ -29,6 +51,18 @@ pub trait U32Ext {
 
     /// Wrap in `MegaHertz`
     fn mhz(self) -> MegaHertz;
+
+    /// Wrap in `Nanoseconds`
+    fn ns(self) -> Nanoseconds;
+
+    /// Wrap in `Microseconds`
+    fn us(self) -> Microseconds;
+
+    /// Wrap in `Milliseconds`
+    fn ms(self) -> Milliseconds;
+
+    /// Wrap in `Seconds`
+    fn s(self) -> Seconds;
 }
 
 impl U32Ext for u32 {
@@ -47,6 +81,22 @@ impl U32Ext for u32 {
     fn mhz(self) -> MegaHertz {
         MegaHertz(self)
     }
+
+    fn ns(self) -> Nanoseconds {
+        Nanoseconds(self)
+    }
+
+    fn us(self) -> Microseconds {
+        Microseconds(self)
+    }
+
+    fn ms(self) -> Milliseconds {
+        Milliseconds(self)
+    }
+
+    fn s(self) -> Seconds {
+        Seconds(self)
+    }
 }
 
 impl Into<Hertz> for KiloHertz {
@@ -66,3 +116,232 @@ impl Into<KiloHertz> for MegaHertz {
         KiloHertz(self.0 * 1_000)
     }
 }
+
+/// The reasons a conversion between time units can fail
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConversionError {
+    /// The result doesn't fit the target unit
+    Overflow,
+    /// 0Hz has no period, a period of 0 has no frequency, and periods
+    /// longer than 2s round to 0Hz
+    Zero,
+}
+
+macro_rules! duration {
+    ($($Type:ident: $ns:expr,)+) => {
+        $(
+
+impl $Type {
+    /// The span of time in nanoseconds, which can't overflow a `u64`
+    pub fn to_nanos(self) -> u64 {
+        self.0 as u64 * $ns
+    }
+}
+
+/// The period of a frequency, rounded to the nearest unit
+impl TryFrom<Hertz> for $Type {
+    type Error = ConversionError;
+
+    fn try_from(freq: Hertz) -> Result<Self, ConversionError> {
+        if freq.0 == 0 {
+            return Err(ConversionError::Zero);
+        }
+        let units = (1_000_000_000 / $ns + freq.0 as u64 / 2) / freq.0 as u64;
+        u32::try_from(units)
+            .map($Type)
+            .map_err(|_| ConversionError::Overflow)
+    }
+}
+
+/// The frequency with this period, rounded to the nearest Hertz
+impl TryFrom<$Type> for Hertz {
+    type Error = ConversionError;
+
+    fn try_from(period: $Type) -> Result<Self, ConversionError> {
+        if period.0 == 0 {
+            return Err(ConversionError::Zero);
+        }
+        let nanos = period.to_nanos();
+        match (1_000_000_000 + nanos / 2) / nanos {
+            0 => Err(ConversionError::Zero),
+            freq => Ok(Hertz(freq as u32)),
+        }
+    }
+}
+
+impl From<$Type> for Period {
+    fn from(period: $Type) -> Self {
+        Period::Nanoseconds(period.to_nanos())
+    }
+}
+
+        )+
+    }
+}
+
+duration! {
+    Nanoseconds: 1,
+    Microseconds: 1_000,
+    Milliseconds: 1_000_000,
+    Seconds: 1_000_000_000,
+}
+
+macro_rules! convert_duration {
+    ($($From:ident => $To:ident: $factor:expr,)+) => {
+        $(
+
+/// Converting to a finer unit can overflow
+impl TryFrom<$From> for $To {
+    type Error = ConversionError;
+
+    fn try_from(period: $From) -> Result<Self, ConversionError> {
+        period
+            .0
+            .checked_mul($factor)
+            .map($To)
+            .ok_or(ConversionError::Overflow)
+    }
+}
+
+        )+
+    }
+}
+
+convert_duration! {
+    Seconds => Milliseconds: 1_000,
+    Seconds => Microseconds: 1_000_000,
+    Seconds => Nanoseconds: 1_000_000_000,
+    Milliseconds => Microseconds: 1_000,
+    Milliseconds => Nanoseconds: 1_000_000,
+    Microseconds => Nanoseconds: 1_000,
+}
+
+/// A period given either as a span of time or as a frequency, as
+/// accepted by the timers, the PWM drivers and `Delay`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Period {
+    /// A span of time in nanoseconds
+    Nanoseconds(u64),
+    /// The period of a frequency
+    Frequency(Hertz),
+}
+
+impl Period {
+    /// The period that `ticks` ticks of a clock running at `freq` take,
+    /// rounded to the nearest nanosecond. A clock of 0Hz never ticks,
+    /// which gives the period of 0Hz.
+    pub fn from_ticks(ticks: u64, freq: Hertz) -> Self {
+        if freq.0 == 0 {
+            return Period::Frequency(Hertz(0));
+        }
+        let freq = freq.0 as u64;
+        // Whole seconds first, so that the multiplications can't overflow
+        let nanos = (ticks / freq).saturating_mul(1_000_000_000)
+            + ((ticks % freq) * 1_000_000_000 + freq / 2) / freq;
+        Period::Nanoseconds(nanos)
+    }
+
+    /// The number of ticks of a clock running at `freq` in the period,
+    /// as a fraction `(numerator, denominator)`.
+    /// The fraction is exact unless the period is so long that it would
+    /// overflow, in which case it is rounded down to a whole tick.
+    pub(crate) fn ticks_ratio(self, freq: Hertz) -> (u64, u64) {
+        match self {
+            Period::Nanoseconds(nanos) => {
+                // Cancel out the common factors of the clock frequency
+                // and a second before multiplying
+                let divisor = gcd(freq.0 as u64, 1_000_000_000);
+                let freq = freq.0 as u64 / divisor;
+                let second = 1_000_000_000 / divisor;
+                match freq.checked_mul(nanos) {
+                    Some(numerator) => (numerator, second),
+                    None => {
+                        let whole = (nanos / second).saturating_mul(freq);
+                        (whole.saturating_add(nanos % second * freq / second), 1)
+                    }
+                }
+            }
+            Period::Frequency(period_freq) => (freq.0 as u64, period_freq.0 as u64),
+        }
+    }
+
+    /// The number of ticks of a clock running at `freq` in the period,
+    /// rounded to the nearest tick. The period of 0Hz saturates.
+    pub fn ticks(self, freq: Hertz) -> u64 {
+        let (numerator, denominator) = self.ticks_ratio(freq);
+        if denominator == 0 {
+            return u64::max_value();
+        }
+        numerator.saturating_add(denominator / 2) / denominator
+    }
+
+    /// The length of the period in nanoseconds, rounded to the nearest
+    /// nanosecond. The period of 0Hz saturates.
+    pub fn to_nanos(self) -> u64 {
+        self.ticks(Hertz(1_000_000_000))
+    }
+
+    /// Half of the period
+    pub(crate) fn half(self) -> Self {
+        match self {
+            Period::Nanoseconds(nanos) => Period::Nanoseconds(nanos / 2),
+            Period::Frequency(freq) => Period::Frequency(Hertz(freq.0.saturating_mul(2))),
+        }
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+/// Compares the fractions `a / b` and `c / d`, neither of which may
+/// have a denominator of 0, without multiplying them out
+pub(crate) fn cmp_ratios(mut a: u64, mut b: u64, mut c: u64, mut d: u64) -> Ordering {
+    let mut reversed = false;
+    loop {
+        let ordering = (a / b).cmp(&(c / d));
+        if ordering != Ordering::Equal {
+            return if reversed { ordering.reverse() } else { ordering };
+        }
+        // The whole parts are equal, so compare what is left over. With
+        // r = a % b and s = c % d, r / b < s / d exactly when b / r > d / s.
+        let (r, s) = (a % b, c % d);
+        let ordering = match (r, s) {
+            (0, 0) => Ordering::Equal,
+            (0, _) => Ordering::Less,
+            (_, 0) => Ordering::Greater,
+            _ => {
+                a = b;
+                b = r;
+                c = d;
+                d = s;
+                reversed = !reversed;
+                continue;
+            }
+        };
+        return if reversed { ordering.reverse() } else { ordering };
+    }
+}
+
+impl From<Hertz> for Period {
+    fn from(freq: Hertz) -> Self {
+        Period::Frequency(freq)
+    }
+}
+
+impl From<KiloHertz> for Period {
+    fn from(freq: KiloHertz) -> Self {
+        Period::Frequency(freq.into())
+    }
+}
+
+impl From<MegaHertz> for Period {
+    fn from(freq: MegaHertz) -> Self {
+        Period::Frequency(freq.into())
+    }
+}
@@ -1,10 +1,11 @@
 use crate::hal::adc::{Channel, OneShot};
 use crate::target_device::{ADC, PM};
 use crate::clock::GenericClockController;
-use crate::gpio::{
-    Pa2, Pa3, Pa4, Pa5, Pa6, Pa7, Pa8, Pa9, Pa10, Pa11, Pb0, Pb1, Pb2, Pb3, Pb4, 
-    Pb5, Pb6, Pb7, Pb8, Pb9, PfB
-};
+use crate::gpio::{Analog, Pa2, Pa3, Pa4, Pa5, Pa6, Pa7, Pa8, Pa9, Pa10, Pa11};
+#[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+use crate::gpio::{Pb2, Pb3, Pb8, Pb9};
+#[cfg(feature = "samd21j18a")]
+use crate::gpio::{Pb0, Pb1, Pb4, Pb5, Pb6, Pb7};
 
 pub struct Adc<ADC> {
     adc: ADC
@@ -14,7 +15,7 @@ impl Adc<ADC> {
     pub fn adc(adc: ADC, pm: &mut PM, clocks: &mut GenericClockController) -> Self {
         pm.apbcmask.modify(|_, w| w.adc_().set_bit());
         // set to 1/(1/(48000000/32) * 6) = 250000 SPS
-        let gclk0 = clocks.gclk0();
+        let gclk0 = clocks.gclk0().expect("gclk0 is in use");
         clocks.adc(&gclk0).expect("adc clock setup failed");
         while adc.status.read().syncbusy().bit_is_set() {}
         adc.ctrla.modify(|_, w| w.swrst().set_bit());
@@ -85,11 +86,14 @@ where
     }
 }
  
+/// The pins of each chip variant and the ADC inputs they are wired to.
+/// A pin has to be in `Analog` mode to be read.
 macro_rules! adc_pins {
-    ($($pin:ident: $chan:expr),+) => {
+    ($($(#[$attr:meta])* $pin:ident: $chan:expr),+) => {
         $(
 
-impl Channel<ADC> for $pin<PfB> {
+$(#[$attr])*
+impl Channel<ADC> for $pin<Analog> {
    type ID = u8;
    fn channel() -> u8 { $chan }
 }
@@ -100,19 +104,29 @@ impl Channel<ADC> for $pin<PfB> {
 adc_pins! {
     Pa2: 0,
     Pa3: 1,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
     Pb8: 2,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
     Pb9: 3,
     Pa4: 4,
     Pa5: 5,
     Pa6: 6,
     Pa7: 7,
+    #[cfg(feature = "samd21j18a")]
     Pb0: 8,
+    #[cfg(feature = "samd21j18a")]
     Pb1: 9,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
     Pb2: 10,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
     Pb3: 11,
+    #[cfg(feature = "samd21j18a")]
     Pb4: 12,
+    #[cfg(feature = "samd21j18a")]
     Pb5: 13,
+    #[cfg(feature = "samd21j18a")]
     Pb6: 14,
+    #[cfg(feature = "samd21j18a")]
     Pb7: 15,
     Pa8: 16,
     Pa9: 17,
@@ -0,0 +1,246 @@
+//! Measuring periods and pulse widths with the timer counters
+//!
+//! In capture mode a timer counter restarts at the start of each period
+//! of its event input, and captures the period and the pulse width into
+//! its two compare/capture registers. The input has to reach the timer
+//! counter through the event system, typically from an external
+//! interrupt line; routing it is up to the application.
+use crate::clock;
+use crate::time::Hertz;
+use crate::timer::divider_for;
+
+use crate::target_device::{PM, TC3, TC4, TC5};
+#[cfg(feature = "samd21j18a")]
+use crate::target_device::{TC6, TC7};
+
+/// Which compare/capture register receives the period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// The period is captured in CC0 and the pulse width in CC1
+    PeriodPulseWidth,
+    /// The pulse width is captured in CC0 and the period in CC1
+    PulseWidthPeriod,
+}
+
+/// The quantities captured by the timer counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Period,
+    PulseWidth,
+}
+
+/// A single measurement of the input signal, in ticks of the counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    /// The time from one start edge to the next
+    pub period: u16,
+    /// The time from the start edge to the opposite edge
+    pub pulse_width: u16,
+}
+
+/// The reasons a measurement can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureError {
+    /// The period was longer than the counter can count; lower the
+    /// resolution to measure slower signals. This is also reported for
+    /// the first measurement after the input has been idle.
+    Overflow,
+    /// A new measurement arrived before the previous one was read
+    Missed,
+}
+
+macro_rules! capture {
+    ($($TYPE:ident: ($TC:ident, $clock:ident, $apmask:ident, $apbits:ident),)+) => {
+        $(
+
+pub struct $TYPE {
+    /// The frequency of the attached clock, before the prescaler
+    clock_freq: Hertz,
+    divider: u16,
+    mode: CaptureMode,
+    tc: $TC,
+}
+
+impl $TYPE {
+    /// Configures the timer counter to capture its event input.
+    /// `resolution` is the desired tick rate of the counter; the closest
+    /// rate the prescaler can produce at or below it is used. Slower
+    /// ticks measure longer periods at a coarser resolution.
+    pub fn new<R: Into<Hertz>>(
+        clock: &clock::$clock,
+        resolution: R,
+        tc: $TC,
+        mode: CaptureMode,
+        pm: &mut PM,
+    ) -> Self {
+        pm.$apmask.modify(|_, w| w.$apbits().set_bit());
+        {
+            let count = tc.count16();
+            count.ctrla.write(|w| w.swrst().set_bit());
+            while count.status.read().syncbusy().bit_is_set() {}
+            // the SVD erroneously marks swrst as write-only, so we
+            // need to manually read the bit here
+            while count.ctrla.read().bits() & 1 != 0 {}
+
+            count.evctrl.write(|w| {
+                match mode {
+                    CaptureMode::PeriodPulseWidth => w.evact().ppw(),
+                    CaptureMode::PulseWidthPeriod => w.evact().pwp(),
+                };
+                w.tcei().set_bit()
+            });
+            count.ctrlc.write(|w| {
+                w.cpten0().set_bit();
+                w.cpten1().set_bit()
+            });
+            while count.status.read().syncbusy().bit_is_set() {}
+        }
+
+        let mut capture = Self {
+            clock_freq: clock.freq(),
+            divider: 1,
+            mode,
+            tc,
+        };
+        capture.set_resolution(resolution);
+        capture
+    }
+
+    /// Changes the tick rate of the counter, as described for `new`
+    pub fn set_resolution<R: Into<Hertz>>(&mut self, resolution: R) {
+        self.divider = divider_for(self.clock_freq.0, resolution.into());
+        let count = self.tc.count16();
+        count.ctrla.modify(|_, w| w.enable().clear_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+        count.ctrla.modify(|_, w| {
+            match self.divider {
+                1 => w.prescaler().div1(),
+                2 => w.prescaler().div2(),
+                4 => w.prescaler().div4(),
+                8 => w.prescaler().div8(),
+                16 => w.prescaler().div16(),
+                64 => w.prescaler().div64(),
+                256 => w.prescaler().div256(),
+                1024 => w.prescaler().div1024(),
+                _ => unreachable!(),
+            };
+            w.enable().set_bit()
+        });
+        while count.status.read().syncbusy().bit_is_set() {}
+    }
+
+    /// The tick rate of the counter
+    pub fn resolution(&self) -> Hertz {
+        Hertz(self.clock_freq.0 / self.divider as u32)
+    }
+
+    /// Selects which edge of the input starts a period.
+    /// By default that's the rising edge, and the pulse width is the
+    /// time the input is high.
+    pub fn invert(&mut self, invert: bool) {
+        let count = self.tc.count16();
+        count.ctrla.modify(|_, w| w.enable().clear_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+        count.evctrl.modify(|_, w| w.tcinv().bit(invert));
+        count.ctrla.modify(|_, w| w.enable().set_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+    }
+
+    /// Returns the latest measurement, once both the period and the pulse
+    /// width of it have been captured
+    pub fn read(&mut self) -> nb::Result<Measurement, CaptureError> {
+        let count = self.tc.count16();
+        let flags = count.intflag.read();
+        if flags.err().bit_is_set() {
+            // Writing a 1 clears the flag
+            count.intflag.write(|w| w.err().set_bit());
+            return Err(nb::Error::Other(CaptureError::Missed));
+        }
+        if flags.mc0().bit_is_clear() || flags.mc1().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // reading the registers clears the capture flags
+        let cc0 = count.cc[0].read().cc().bits();
+        let cc1 = count.cc[1].read().cc().bits();
+        if flags.ovf().bit_is_set() {
+            count.intflag.write(|w| w.ovf().set_bit());
+            return Err(nb::Error::Other(CaptureError::Overflow));
+        }
+
+        Ok(match self.mode {
+            CaptureMode::PeriodPulseWidth => Measurement {
+                period: cc0,
+                pulse_width: cc1,
+            },
+            CaptureMode::PulseWidthPeriod => Measurement {
+                period: cc1,
+                pulse_width: cc0,
+            },
+        })
+    }
+
+    /// Returns the frequency of the input signal, computed from the
+    /// latest measurement
+    pub fn read_frequency(&mut self) -> nb::Result<Hertz, CaptureError> {
+        let measurement = self.read()?;
+        Ok(Hertz(self.resolution().0 / (measurement.period as u32).max(1)))
+    }
+}
+
+#[cfg(feature = "unproven")]
+impl crate::hal::Capture for $TYPE {
+    type Error = CaptureError;
+    type Channel = Channel;
+    type Time = Hertz;
+    type Capture = u16;
+
+    fn capture(&mut self, channel: Channel) -> nb::Result<u16, CaptureError> {
+        let measurement = self.read()?;
+        Ok(match channel {
+            Channel::Period => measurement.period,
+            Channel::PulseWidth => measurement.pulse_width,
+        })
+    }
+
+    /// The period and the pulse width are captured together, so this
+    /// stops the capture of both channels
+    fn disable(&mut self, _channel: Channel) {
+        let count = self.tc.count16();
+        count.ctrla.modify(|_, w| w.enable().clear_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+    }
+
+    /// The period and the pulse width are captured together, so this
+    /// starts the capture of both channels
+    fn enable(&mut self, _channel: Channel) {
+        let count = self.tc.count16();
+        count.ctrla.modify(|_, w| w.enable().set_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+    }
+
+    fn get_resolution(&self) -> Hertz {
+        self.resolution()
+    }
+
+    fn set_resolution<R>(&mut self, resolution: R)
+    where
+        R: Into<Hertz>,
+    {
+        $TYPE::set_resolution(self, resolution)
+    }
+}
+
+)+}}
+
+capture! {
+    Capture3: (TC3, Tcc2Tc3Clock, apbcmask, tc3_),
+    Capture4: (TC4, Tc4Tc5Clock, apbcmask, tc4_),
+    Capture5: (TC5, Tc4Tc5Clock, apbcmask, tc5_),
+}
+
+#[cfg(feature = "samd21j18a")]
+capture! {
+    Capture6: (TC6, Tc6Tc7Clock, apbcmask, tc6_),
+    Capture7: (TC7, Tc6Tc7Clock, apbcmask, tc7_),
+}
@@ -3,27 +3,85 @@
 //! before you can set up most of the peripherals on the atsamd21 device.
 //! The other types in this module are used to enforce at compile time
 //! that the peripherals have been correctly configured.
+use core::cell::Cell;
+use core::marker::PhantomData;
+use cortex_m::interrupt::{self, Mutex};
 use crate::target_device::gclk::clkctrl::GEN_A::*;
 use crate::target_device::gclk::clkctrl::ID_A::*;
 use crate::target_device::gclk::genctrl::SRC_A::*;
+use crate::gpio::{self, IntoFunction, Port};
 use crate::target_device::{self, GCLK, NVMCTRL, PM, SYSCTRL};
 use crate::time::{Hertz, U32Ext};
 
 pub type ClockId = target_device::gclk::clkctrl::ID_A;
 pub type ClockGenId = target_device::gclk::clkctrl::GEN_A;
 pub type ClockSource = target_device::gclk::genctrl::SRC_A;
+pub type DpllFilter = target_device::sysctrl::dpllctrlb::FILTER_A;
+
+/// The number of clock generators
+const NUM_GCLKS: usize = 9;
+/// The number of peripheral clock channels
+const NUM_CHANNELS: usize = 37;
+
+/// The clock generators that a `GClock` is currently alive for
+static LIVE_GCLKS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+/// The peripheral channels that a clock token is currently alive for
+static LIVE_CHANNELS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Marks `idx` as alive in `live`. Returns false if it already was.
+fn claim(live: &Mutex<Cell<u64>>, idx: usize) -> bool {
+    interrupt::free(|cs| {
+        let live = live.borrow(cs);
+        let claimed = live.get() & (1 << idx) == 0;
+        live.set(live.get() | (1 << idx));
+        claimed
+    })
+}
+
+fn release(live: &Mutex<Cell<u64>>, idx: usize) {
+    interrupt::free(|cs| {
+        let live = live.borrow(cs);
+        live.set(live.get() & !(1 << idx));
+    })
+}
 
 /// Represents a configured clock generator.
 /// Can be converted into the effective clock frequency.
 /// Its primary purpose is to be passed in to methods
 /// such as `GenericClockController::tcc2_tc3` to configure
 /// the clock for a peripheral.
-#[derive(Clone, Copy)]
+/// There is at most one `GClock` for each clock generator at a time.
+/// The peripheral clock tokens borrow it, so it can't be handed to
+/// `GenericClockController::reconfigure_gclk` or `disable_gclk` while
+/// any of them is alive. Once it is dropped, it can be obtained again
+/// from the controller.
 pub struct GClock {
     gclk: ClockGenId,
     freq: Hertz,
 }
 
+impl GClock {
+    /// Claims the handle of a clock generator, unless one is alive
+    fn claim(gclk: ClockGenId, freq: Hertz) -> Option<Self> {
+        if claim(&LIVE_GCLKS, u8::from(gclk) as usize) {
+            Some(Self { gclk, freq })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the frequency of the clock generator
+    pub fn freq(&self) -> Hertz {
+        self.freq
+    }
+}
+
+impl Drop for GClock {
+    fn drop(&mut self) {
+        release(&LIVE_GCLKS, u8::from(self.gclk) as usize);
+    }
+}
+
 impl Into<Hertz> for GClock {
     fn into(self) -> Hertz {
         self.freq
@@ -53,18 +111,31 @@ impl State {
         src: ClockSource,
         improve_duty_cycle: bool,
     ) {
+        let config = GclkConfig::new(src)
+            .divider(divider)
+            .improve_duty_cycle(improve_duty_cycle);
+        self.configure_gclk(gclk, &config);
+    }
+
+    fn configure_gclk(&mut self, gclk: ClockGenId, config: &GclkConfig) {
+        let (divsel, div) = match config.divider {
+            GclkDivider::Linear(divider) => (false, divider),
+            GclkDivider::Exponential(exponent) => (true, exponent as u16),
+        };
         self.gclk.gendiv.write(|w| unsafe {
             w.id().bits(u8::from(gclk));
-            w.div().bits(divider)
+            w.div().bits(div)
         });
         self.wait_for_sync();
 
         self.gclk.genctrl.write(|w| unsafe {
             w.id().bits(u8::from(gclk));
-            w.src().bits(u8::from(src));
-            // divide directly by divider, rather than exponential
-            w.divsel().clear_bit();
-            w.idc().bit(improve_duty_cycle);
+            w.src().bits(u8::from(config.source));
+            w.divsel().bit(divsel);
+            w.idc().bit(config.improve_duty_cycle);
+            w.oe().bit(config.output_enable);
+            w.oov().bit(config.output_off_value);
+            w.runstdby().bit(config.run_standby);
             w.genen().set_bit()
         });
         self.wait_for_sync();
@@ -78,6 +149,22 @@ impl State {
         });
         self.wait_for_sync();
     }
+
+    fn disable_clock(&mut self, clock: ClockId) {
+        self.gclk.clkctrl.write(|w| unsafe {
+            w.id().bits(u8::from(clock));
+            w.clken().clear_bit()
+        });
+        self.wait_for_sync();
+    }
+
+    fn disable_gclk(&mut self, gclk: ClockGenId) {
+        self.gclk.genctrl.write(|w| unsafe {
+            w.id().bits(u8::from(gclk));
+            w.genen().clear_bit()
+        });
+        self.wait_for_sync();
+    }
 }
 
 /// `GenericClockController` encapsulates the GCLK hardware.
@@ -88,8 +175,89 @@ impl State {
 /// gclk0 at 48Mhz.
 pub struct GenericClockController {
     state: State,
-    gclks: [Hertz; 8],
-    used_clocks: u64,
+    tree: ClockTree,
+}
+
+/// The generators, peripheral channels and sources that make up the
+/// clock tree. The controller keeps track of the tree it has configured,
+/// and `ClockPlan` solves plans against a copy of it.
+#[derive(Clone, Copy)]
+struct ClockTree {
+    xosc32k_enabled: bool,
+    gclks: [Hertz; NUM_GCLKS],
+    gclk_configs: [Option<GclkConfig>; NUM_GCLKS],
+    channels: [Option<ClockGenId>; NUM_CHANNELS],
+    /// The peripheral channels that have been routed for a clock token
+    token_channels: u64,
+    /// The number of GCLK_IO pins each clock generator is output on
+    gclk_outs: [u8; NUM_GCLKS],
+    xosc_freq: Hertz,
+    gclk_in_freqs: [Hertz; NUM_GCLKS],
+    dpll_freq: Hertz,
+}
+
+impl ClockTree {
+    /// The clock tree that `GenericClockController` sets up: gclk0 at
+    /// 48Mhz from the DFLL48M, which is referenced from gclk1 at 32Khz
+    fn new(xosc32k_enabled: bool) -> Self {
+        let src_32k = if xosc32k_enabled { XOSC32K } else { OSC32K };
+        let mut tree = Self {
+            xosc32k_enabled,
+            gclks: [Hertz(0); NUM_GCLKS],
+            gclk_configs: [None; NUM_GCLKS],
+            channels: [None; NUM_CHANNELS],
+            token_channels: 0,
+            gclk_outs: [0; NUM_GCLKS],
+            xosc_freq: Hertz(0),
+            gclk_in_freqs: [Hertz(0); NUM_GCLKS],
+            dpll_freq: Hertz(0),
+        };
+        tree.gclks[0] = OSC48M_FREQ;
+        tree.gclks[1] = tree.source_freq(src_32k, 1);
+        tree.gclk_configs[0] = Some(GclkConfig::new(DFLL48M).improve_duty_cycle(true));
+        tree.gclk_configs[1] = Some(GclkConfig::new(src_32k));
+        tree.channels[u8::from(DFLL48) as usize] = Some(GCLK1);
+        tree
+    }
+
+    /// Returns the frequency of a clock generator source, or zero if
+    /// it isn't running.
+    fn source_freq(&self, src: ClockSource, idx: usize) -> Hertz {
+        match src {
+            XOSC32K if self.xosc32k_enabled => XOSC32K_FREQ,
+            OSC32K if !self.xosc32k_enabled => OSC32K_FREQ,
+            XOSC32K | OSC32K => Hertz(0),
+            OSCULP32K => OSC32K_FREQ,
+            GCLKGEN1 => self.gclks[1],
+            OSC8M => 8.mhz().into(),
+            DFLL48M => OSC48M_FREQ,
+            DPLL96M => self.dpll_freq,
+            XOSC => self.xosc_freq,
+            GCLKIN => self.gclk_in_freqs[idx],
+        }
+    }
+
+    /// Returns true if a peripheral channel, a GCLK_IO pin or another
+    /// clock generator depends on the clock generator.
+    fn gclk_in_use(&self, gclk: ClockGenId) -> bool {
+        self.channels.contains(&Some(gclk))
+            || self.gclk_outs[u8::from(gclk) as usize] != 0
+            || (gclk == GCLK1
+                && self.gclk_configs.iter().flatten().any(|config| config.source == GCLKGEN1))
+    }
+
+    /// Forgets the routing of the peripheral channels whose clock token
+    /// has been dropped. They stay routed in hardware, so the peripherals
+    /// keep running, but no longer hold on to their clock generator.
+    fn release_dropped_tokens(&mut self) {
+        let live = interrupt::free(|cs| LIVE_CHANNELS.borrow(cs).get());
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            if self.token_channels & !live & (1 << idx) != 0 {
+                *channel = None;
+            }
+        }
+        self.token_channels &= live;
+    }
 }
 
 impl GenericClockController {
@@ -124,7 +292,7 @@ impl GenericClockController {
     ) -> Self {
         let mut state = State { gclk };
 
-        set_flash_to_half_auto_wait_state(nvmctrl);
+        set_flash_wait_states(nvmctrl, flash_wait_states(OSC48M_FREQ));
         enable_gclk_apb(pm);
         if use_external_crystal {
             enable_external_32kosc(sysctrl);
@@ -161,60 +329,135 @@ impl GenericClockController {
 
         Self {
             state,
-            gclks: [
-                OSC48M_FREQ,
-                OSC32K_FREQ,
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-            ],
-            used_clocks: 1u64 << u8::from(DFLL48M),
+            tree: ClockTree::new(use_external_crystal),
         }
     }
 
-    /// Returns a `GClock` for gclk0, the system clock generator at 48Mhz
-    pub fn gclk0(&mut self) -> GClock {
-        GClock {
-            gclk: GCLK0,
-            freq: self.gclks[0],
-        }
+    /// Returns a `GClock` for gclk0, the system clock generator at 48Mhz.
+    /// Returns `None` while another `GClock` for gclk0 is alive.
+    pub fn gclk0(&mut self) -> Option<GClock> {
+        self.get_gclk(GCLK0)
     }
 
     /// Returns a `GClock` for gclk1, the 32Khz oscillator.
-    pub fn gclk1(&mut self) -> GClock {
-        GClock {
-            gclk: GCLK1,
-            freq: self.gclks[1],
-        }
+    /// Returns `None` while another `GClock` for gclk1 is alive.
+    pub fn gclk1(&mut self) -> Option<GClock> {
+        self.get_gclk(GCLK1)
     }
 
     /// Returns the `GClock` for the specified clock generator.
-    /// If that clock generator has not yet been configured,
-    /// returns None.
+    /// If that clock generator has not yet been configured, or
+    /// another `GClock` for it is alive, returns None.
     pub fn get_gclk(&mut self, gclk: ClockGenId) -> Option<GClock> {
-        let idx = u8::from(gclk) as usize;
-        if self.gclks[idx].0 == 0 {
+        let freq = self.gclk_freq(gclk);
+        if freq.0 == 0 {
             None
         } else {
-            Some(GClock {
-                gclk,
-                freq: self.gclks[idx],
-            })
+            GClock::claim(gclk, freq)
+        }
+    }
+
+    /// Returns the frequency of the specified clock generator, or zero
+    /// if it is not running. Unlike `get_gclk`, this works while the
+    /// `GClock` for the generator is alive.
+    pub fn gclk_freq(&self, gclk: ClockGenId) -> Hertz {
+        self.tree.gclks[u8::from(gclk) as usize]
+    }
+
+    /// Enables the external high speed oscillator (XOSC) so that it
+    /// can be used as the `XOSC` source of a clock generator.
+    /// `freq` is the frequency of the crystal or clock signal attached
+    /// to XIN; the oscillator gain and startup time are selected from it.
+    /// The XIN (and XOUT when using a crystal) pins must not be used
+    /// for anything else.
+    pub fn enable_xosc<F: Into<Hertz>>(
+        &mut self,
+        sysctrl: &mut SYSCTRL,
+        freq: F,
+        mode: XoscMode,
+    ) {
+        let freq = freq.into();
+        enable_xosc(sysctrl, freq, mode);
+        self.tree.xosc_freq = freq;
+    }
+
+    /// Keeps the 32kHz oscillator that the controller was created with
+    /// running in standby, so that clock generators configured with
+    /// `GclkConfig::run_standby` can keep clocking peripherals such
+    /// as the RTC from it.
+    pub fn run_32kosc_in_standby(&mut self, sysctrl: &mut SYSCTRL, run_standby: bool) {
+        if self.tree.xosc32k_enabled {
+            sysctrl.xosc32k.modify(|_, w| w.runstdby().bit(run_standby));
+        } else {
+            sysctrl.osc32k.modify(|_, w| w.runstdby().bit(run_standby));
+        }
+    }
+
+    /// Records the frequency of the external clock applied to the
+    /// GCLK_IO pin of the specified clock generator, so that it can
+    /// be used as the `GCLKIN` source of that generator.
+    /// The pin itself must be switched to the GCLK_IO peripheral function
+    /// by the application.
+    pub fn set_gclk_in_freq<F: Into<Hertz>>(&mut self, gclk: ClockGenId, freq: F) {
+        self.tree.gclk_in_freqs[u8::from(gclk) as usize] = freq.into();
+    }
+
+    /// Configures and enables the fractional digital phase locked loop
+    /// (FDPLL), then waits for it to lock.
+    /// The resulting `DpllClock` carries the exact output frequency, which
+    /// is also used for any clock generator configured with the `DPLL96M`
+    /// source.
+    /// Returns `None` if the FDPLL has already been configured, if the
+    /// selected reference is not running, if the XOSC divider doesn't fit
+    /// in its register, or if the reference or output frequency is outside
+    /// of the range supported by the hardware.
+    pub fn configure_dpll(
+        &mut self,
+        sysctrl: &mut SYSCTRL,
+        config: DpllConfig,
+    ) -> Option<DpllClock> {
+        if self.tree.dpll_freq.0 != 0 {
+            return None;
+        }
+        let (ldr, ldrfrac, freq) = config.solve(config.reference_freq(&self.tree))?;
+        if let DpllReference::Gclk(_) = config.reference {
+            if self.tree.channels[u8::from(FDPLL) as usize].is_some() {
+                return None;
+            }
         }
+        self.enable_dpll(sysctrl, &config, ldr, ldrfrac, freq);
+        Some(DpllClock { freq })
+    }
+
+    /// Routes the reference to the FDPLL and enables it, once the
+    /// configuration has been checked
+    fn enable_dpll(
+        &mut self,
+        sysctrl: &mut SYSCTRL,
+        config: &DpllConfig,
+        ldr: u16,
+        ldrfrac: u8,
+        freq: Hertz,
+    ) {
+        if let DpllReference::Gclk(gclk) = config.reference {
+            self.tree.channels[u8::from(FDPLL) as usize] = Some(gclk);
+            self.state.enable_clock_generator(FDPLL, gclk);
+        }
+        configure_and_enable_dpll(sysctrl, config, ldr, ldrfrac);
+        self.tree.dpll_freq = freq;
     }
 
     /// Configures a clock generator with the specified divider and
     /// source.
     /// `divider` is a linear divider to be applied to the clock
-    /// source.  While the hardware also supports an exponential divider,
-    /// this function doesn't expose that functionality at this time.
+    /// source; use `configure_gclk` for the exponential divider.
     /// `improve_duty_cycle` is a boolean that, when set to true, enables
-    /// a 5o/50 duty cycle for odd divider values.
+    /// a 50/50 duty cycle for odd divider values.
     /// Returns a `GClock` for the configured clock generator.
-    /// Returns `None` if the clock generator has already been configured.
+    /// Returns `None` if the clock generator has already been configured,
+    /// if `src` is `XOSC` or `GCLKIN` and its frequency has not been
+    /// set up via `enable_xosc` or `set_gclk_in_freq`, or if the divider
+    /// doesn't fit in the division factor of the generator.
     pub fn configure_gclk_divider_and_source(
         &mut self,
         gclk: ClockGenId,
@@ -222,25 +465,393 @@ impl GenericClockController {
         src: ClockSource,
         improve_duty_cycle: bool,
     ) -> Option<GClock> {
-        let idx = u8::from(gclk) as usize;
-        if self.gclks[idx].0 != 0 {
+        let config = GclkConfig::new(src)
+            .divider(divider)
+            .improve_duty_cycle(improve_duty_cycle);
+        self.configure_gclk(gclk, config)
+    }
+
+    /// Configures a clock generator from a `GclkConfig`, which also gives
+    /// access to the exponential divider, the GCLK_IO output and running
+    /// in standby.
+    /// Returns `None` under the same conditions as
+    /// `configure_gclk_divider_and_source`.
+    pub fn configure_gclk(&mut self, gclk: ClockGenId, config: GclkConfig) -> Option<GClock> {
+        if self.gclk_freq(gclk).0 != 0 {
             return None;
         }
-        self.state
-            .set_gclk_divider_and_source(gclk, divider, src, improve_duty_cycle);
-        let freq: Hertz = match src {
-            XOSC32K | OSC32K | OSCULP32K => OSC32K_FREQ,
-            GCLKGEN1 => self.gclks[1],
-            OSC8M => 8.mhz().into(),
-            DFLL48M => OSC48M_FREQ,
-            DPLL96M => 96.mhz().into(),
-            GCLKIN | XOSC => unimplemented!(),
+        let freq = self.apply_gclk_config(gclk, config)?;
+        GClock::claim(gclk, freq)
+    }
+
+    /// Retunes a clock generator that is already running, for example
+    /// to switch it to a low power source before going to sleep.
+    /// When retuning gclk0, the NVM read wait states are adjusted to the
+    /// new CPU frequency.
+    /// The peripheral clock tokens borrow the `GClock`, so none of them
+    /// can be alive; the peripheral channels of dropped tokens stay routed
+    /// and follow the new frequency. Channels routed by a `ClockPlan` or
+    /// to clock the DFLL48M or FDPLL, an output enabled on a pin (see
+    /// `disable_gclk_out`), and if `gclk` is gclk1 other generators fed
+    /// from it, are checked at run time. gclk0 must not end up faster
+    /// than the 48MHz that the CPU supports.
+    /// Returns the updated `GClock` on success; otherwise the passed in
+    /// `GClock` is handed back unchanged.
+    pub fn reconfigure_gclk(
+        &mut self,
+        nvmctrl: &mut NVMCTRL,
+        mut gclk: GClock,
+        config: GclkConfig,
+    ) -> Result<GClock, GClock> {
+        self.tree.release_dropped_tokens();
+        if self.tree.gclk_in_use(gclk.gclk) || (gclk.gclk == GCLK1 && config.source == GCLKGEN1) {
+            return Err(gclk);
+        }
+        let freq = if gclk.gclk == GCLK0 {
+            let freq = config.divider.divide(self.tree.source_freq(config.source, 0));
+            if freq.0 > OSC48M_FREQ.0 {
+                return Err(gclk);
+            }
+            // Use enough wait states for both the old and the new frequency
+            // while switching over, then settle on those for the new one.
+            let old_freq = self.tree.gclks[0];
+            set_flash_wait_states(nvmctrl, flash_wait_states(Hertz(freq.0.max(old_freq.0))));
+            let freq = self.apply_gclk_config(GCLK0, config);
+            set_flash_wait_states(nvmctrl, flash_wait_states(self.tree.gclks[0]));
+            freq
+        } else {
+            self.apply_gclk_config(gclk.gclk, config)
+        };
+        match freq {
+            Some(freq) => {
+                gclk.freq = freq;
+                Ok(gclk)
+            }
+            None => Err(gclk),
+        }
+    }
+
+    /// Stops a clock generator so that it no longer draws power.
+    /// gclk0 drives the CPU and can't be stopped. The other conditions
+    /// are the same as for `reconfigure_gclk`.
+    /// On failure the passed in `GClock` is handed back.
+    pub fn disable_gclk(&mut self, gclk: GClock) -> Result<(), GClock> {
+        self.tree.release_dropped_tokens();
+        if gclk.gclk == GCLK0 || self.tree.gclk_in_use(gclk.gclk) {
+            return Err(gclk);
+        }
+        let idx = u8::from(gclk.gclk) as usize;
+        self.state.disable_gclk(gclk.gclk);
+        self.tree.gclks[idx] = Hertz(0);
+        self.tree.gclk_configs[idx] = None;
+        Ok(())
+    }
+
+    /// Switches a pin to the GCLK_IO function and enables the output
+    /// of the clock generator on it, for example to clock an external
+    /// ADC or audio codec.
+    /// The pin is handed back if it is not connected to the GCLK_IO
+    /// signal of the generator.
+    /// The generator can't be reconfigured or disabled until the output
+    /// has been handed back to `disable_gclk_out`.
+    pub fn enable_gclk_out<P: GclkIo>(
+        &mut self,
+        gclk: &GClock,
+        pin: P,
+        port: &mut Port,
+    ) -> Result<GclkOut<P::Pin>, P> {
+        let idx = u8::from(gclk.gclk) as usize;
+        let config = match self.tree.gclk_configs[idx] {
+            Some(config) if pin.gclk() == gclk.gclk => config.output_enable(true),
+            _ => return Err(pin),
         };
-        self.gclks[idx] = Hertz(freq.0 / divider as u32);
-        Some(GClock { gclk, freq })
+        self.state.configure_gclk(gclk.gclk, &config);
+        self.tree.gclk_configs[idx] = Some(config);
+        self.tree.gclk_outs[idx] += 1;
+        Ok(GclkOut {
+            gclk: gclk.gclk,
+            pin: pin.into_gclk_io(port),
+        })
+    }
+
+    /// Disables the output of a clock generator once it is no longer
+    /// output on any pin; the pin then drives the output off value of
+    /// the generator. Returns the pin.
+    pub fn disable_gclk_out<PIN>(&mut self, out: GclkOut<PIN>) -> PIN {
+        let idx = u8::from(out.gclk) as usize;
+        self.tree.gclk_outs[idx] -= 1;
+        if self.tree.gclk_outs[idx] == 0 {
+            if let Some(config) = self.tree.gclk_configs[idx] {
+                let config = config.output_enable(false);
+                self.state.configure_gclk(out.gclk, &config);
+                self.tree.gclk_configs[idx] = Some(config);
+            }
+        }
+        out.pin
+    }
+
+    /// Disables the peripheral channel of a clock token and unroutes
+    /// it from its clock generator, so that the channel can be routed
+    /// to another generator and the generator can be reconfigured.
+    /// The peripheral(s) clocked from the channel stop working until
+    /// a new clock token has been obtained for them.
+    pub fn disable_clock<C: PeripheralClock>(&mut self, clock: C) {
+        let id = clock.id();
+        self.state.disable_clock(id);
+        self.tree.channels[u8::from(id) as usize] = None;
+        self.tree.token_channels &= !(1 << u8::from(id));
+    }
+
+    /// Configures a clock generator and returns its new frequency
+    fn apply_gclk_config(&mut self, gclk: ClockGenId, config: GclkConfig) -> Option<Hertz> {
+        let idx = u8::from(gclk) as usize;
+        let freq = self.tree.source_freq(config.source, idx);
+        if freq.0 == 0 || !config.divider.fits(gclk) {
+            return None;
+        }
+        self.state.configure_gclk(gclk, &config);
+        let freq = config.divider.divide(freq);
+        self.tree.gclks[idx] = freq;
+        self.tree.gclk_configs[idx] = Some(config);
+        Some(freq)
+    }
+
+    /// Applies a `ClockPlan`: enables the planned oscillators and the
+    /// FDPLL, configures the planned clock generators and routes the
+    /// planned peripheral channels to them.
+    /// The plan is solved against the clock tree the controller has
+    /// configured so far (see `ClockPlan::solve_for`) before any hardware
+    /// is touched, so on error nothing has been changed.
+    /// The peripheral clock tokens for the planned channels can then be
+    /// obtained as usual, passing in the planned `GClock`.
+    pub fn apply_plan(
+        &mut self,
+        sysctrl: &mut SYSCTRL,
+        plan: &ClockPlan,
+    ) -> Result<ClockSolution, PlanError> {
+        self.tree.release_dropped_tokens();
+        let solution = plan.solve_for(self)?;
+
+        // Everything has been checked, nothing below can fail
+        if let Some((freq, mode)) = plan.xosc {
+            self.enable_xosc(sysctrl, freq, mode);
+        }
+        for step in solution.steps.iter().flatten() {
+            match *step {
+                PlanStep::Dpll(ldr, ldrfrac) => {
+                    if let Some(config) = plan.dpll {
+                        self.enable_dpll(sysctrl, &config, ldr, ldrfrac, solution.tree.dpll_freq);
+                    }
+                }
+                PlanStep::Gclk(gclk) => {
+                    if let Some(config) = solution.tree.gclk_configs[u8::from(gclk) as usize] {
+                        self.state.configure_gclk(gclk, &config);
+                    }
+                }
+            }
+        }
+        for &(id, gclk) in plan.channels.iter().flatten() {
+            if self.tree.channels[u8::from(id) as usize].is_none() {
+                self.state.enable_clock_generator(id, gclk);
+            }
+        }
+        self.tree = solution.tree;
+
+        Ok(solution)
+    }
+}
+
+/// The division factor of a clock generator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GclkDivider {
+    /// Divide the source by the given factor. A factor of 0 is treated
+    /// the same as 1.
+    Linear(u16),
+    /// Divide the source by 2^(n+1). This allows for larger division
+    /// factors than the linear divider, but only in powers of two.
+    Exponential(u8),
+}
+
+impl GclkDivider {
+    /// Returns the frequency that a source frequency is divided down to.
+    pub fn divide(self, freq: Hertz) -> Hertz {
+        match self {
+            GclkDivider::Linear(divider) => Hertz(freq.0 / (divider as u32).max(1)),
+            GclkDivider::Exponential(exponent) => {
+                Hertz(freq.0.checked_shr(exponent as u32 + 1).unwrap_or(0))
+            }
+        }
+    }
+
+    /// Returns true if the divider fits in the division factor of the
+    /// clock generator.
+    fn fits(self, gclk: ClockGenId) -> bool {
+        let max = gclk_max_divider(gclk);
+        match self {
+            GclkDivider::Linear(divider) => divider as u32 <= max,
+            // The exponent is limited to the number of division factor bits
+            GclkDivider::Exponential(exponent) => exponent as u32 <= 32 - max.leading_zeros(),
+        }
+    }
+}
+
+/// The configuration of a clock generator, for use with
+/// `GenericClockController::configure_gclk` and
+/// `GenericClockController::reconfigure_gclk`.
+/// By default the source is not divided, the duty cycle is not
+/// improved, the GCLK_IO output is disabled and driven low, and the
+/// generator stops in standby.
+#[derive(Clone, Copy, Debug)]
+pub struct GclkConfig {
+    source: ClockSource,
+    divider: GclkDivider,
+    improve_duty_cycle: bool,
+    output_enable: bool,
+    output_off_value: bool,
+    run_standby: bool,
+}
+
+impl GclkConfig {
+    /// Clock generator configuration that runs from `source`.
+    pub fn new(source: ClockSource) -> Self {
+        Self {
+            source,
+            divider: GclkDivider::Linear(1),
+            improve_duty_cycle: false,
+            output_enable: false,
+            output_off_value: false,
+            run_standby: false,
+        }
+    }
+
+    /// Divide the source linearly by `divider`.
+    pub fn divider(mut self, divider: u16) -> Self {
+        self.divider = GclkDivider::Linear(divider);
+        self
+    }
+
+    /// Divide the source by 2^(exponent+1).
+    pub fn exponential_divider(mut self, exponent: u8) -> Self {
+        self.divider = GclkDivider::Exponential(exponent);
+        self
+    }
+
+    /// When set, odd linear divider values produce a 50/50 duty cycle.
+    pub fn improve_duty_cycle(mut self, improve_duty_cycle: bool) -> Self {
+        self.improve_duty_cycle = improve_duty_cycle;
+        self
+    }
+
+    /// When set, the generator drives its GCLK_IO pin, if that pin is
+    /// switched to the GCLK_IO function. See also
+    /// `GenericClockController::enable_gclk_out`.
+    pub fn output_enable(mut self, output_enable: bool) -> Self {
+        self.output_enable = output_enable;
+        self
+    }
+
+    /// The level of the GCLK_IO pin while the generator is stopped
+    /// or its output is disabled.
+    pub fn output_off_value(mut self, high: bool) -> Self {
+        self.output_off_value = high;
+        self
+    }
+
+    /// When set, the generator keeps running in standby sleep mode.
+    pub fn run_standby(mut self, run_standby: bool) -> Self {
+        self.run_standby = run_standby;
+        self
+    }
+}
+
+/// Implemented for the pins that can carry the GCLK_IO signal of a
+/// clock generator. You should not implement this trait yourself; only
+/// the implementations in the clock module make sense.
+pub trait GclkIo {
+    /// The pin switched to the GCLK_IO function
+    type Pin;
+    /// Returns the clock generator whose GCLK_IO signal is on this pin
+    fn gclk(&self) -> ClockGenId;
+    /// Switch the pin to the GCLK_IO function
+    fn into_gclk_io(self, port: &mut Port) -> Self::Pin;
+}
+
+/// A clock generator output on its GCLK_IO pin, as returned by
+/// `GenericClockController::enable_gclk_out`.
+pub struct GclkOut<PIN> {
+    gclk: ClockGenId,
+    pin: PIN,
+}
+
+impl<PIN> GclkOut<PIN> {
+    /// Returns the clock generator that drives the pin
+    pub fn gclk(&self) -> ClockGenId {
+        self.gclk
     }
 }
 
+macro_rules! gclk_io {
+    ($($(#[$attr:meta])* $PinType:ident: ($gclk:ident, $Pf:ident),)+) => {
+        $(
+        $(#[$attr])*
+        impl<MODE> GclkIo for gpio::$PinType<MODE> {
+            type Pin = gpio::$PinType<gpio::$Pf>;
+
+            fn gclk(&self) -> ClockGenId {
+                $gclk
+            }
+
+            fn into_gclk_io(self, port: &mut Port) -> Self::Pin {
+                self.into_function(port)
+            }
+        }
+        )+
+    };
+}
+
+gclk_io!(
+    Pa10: (GCLK4, PfH),
+    Pa11: (GCLK5, PfH),
+    Pa14: (GCLK0, PfH),
+    Pa15: (GCLK1, PfH),
+    Pa16: (GCLK2, PfH),
+    Pa17: (GCLK3, PfH),
+    Pa20: (GCLK4, PfH),
+    Pa21: (GCLK5, PfH),
+    Pa22: (GCLK6, PfH),
+    Pa23: (GCLK7, PfH),
+    Pa27: (GCLK0, PfH),
+    Pa28: (GCLK0, PfH),
+    Pa30: (GCLK0, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb10: (GCLK4, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb11: (GCLK5, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb12: (GCLK6, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb13: (GCLK7, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb14: (GCLK0, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb15: (GCLK1, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb16: (GCLK2, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb17: (GCLK3, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb22: (GCLK0, PfH),
+    #[cfg(any(feature = "samd21g18a", feature="samd21j18a"))]
+    Pb23: (GCLK1, PfH),
+);
+
+/// Implemented by the peripheral clock tokens, so that they can be
+/// handed back to `GenericClockController::disable_clock`.
+pub trait PeripheralClock {
+    /// Returns the peripheral channel of the token
+    fn id(&self) -> ClockId;
+}
+
 macro_rules! clock_generator {
     ($(($id:ident, $Type:ident, $clock:ident),)+) => {
 
@@ -252,22 +863,36 @@ $(
 /// The peripheral initialization code will typically require passing
 /// in this object to prove at compile time that the clock has been
 /// correctly initialized.
+/// The token borrows the `GClock` it was configured from. Dropping it
+/// leaves the peripheral(s) clocked, but lets the controller route the
+/// channel to another clock generator and reconfigure this one.
 #[derive(Debug)]
-pub struct $Type {
+pub struct $Type<'a> {
     freq: Hertz,
+    _gclk: PhantomData<&'a GClock>,
 }
 
-impl $Type {
+impl<'a> $Type<'a> {
     /// Returns the frequency of the configured clock
     pub fn freq(&self) -> Hertz {
         self.freq
     }
 }
-impl Into<Hertz> for $Type {
+impl<'a> Into<Hertz> for $Type<'a> {
     fn into(self) -> Hertz {
         self.freq
     }
 }
+impl<'a> PeripheralClock for $Type<'a> {
+    fn id(&self) -> ClockId {
+        $clock
+    }
+}
+impl<'a> Drop for $Type<'a> {
+    fn drop(&mut self) {
+        release(&LIVE_CHANNELS, u8::from($clock) as usize);
+    }
+}
 )+
 
 impl GenericClockController {
@@ -282,18 +907,26 @@ impl GenericClockController {
     /// the peripheral initialization code will typically require that this
     /// clock token be passed in to ensure that the clock has been initialized
     /// appropriately.
-    /// Returns `None` is the specified generic clock has already been
-    /// configured.
-    pub fn $id(&mut self, generator: &GClock) -> Option<$Type> {
-        let bits : u64 = 1<<u8::from($clock) as u64;
-        if (self.used_clocks & bits) != 0 {
+    /// Returns `None` while a token for the specified generic clock is
+    /// alive, or if it has been routed to a different clock generator
+    /// by a `ClockPlan` or by a token that is still alive.
+    pub fn $id<'a>(&mut self, generator: &'a GClock) -> Option<$Type<'a>> {
+        let idx = u8::from($clock) as usize;
+        self.tree.release_dropped_tokens();
+        match self.tree.channels[idx] {
+            Some(gclk) if gclk != generator.gclk => return None,
+            _ => (),
+        }
+        if !claim(&LIVE_CHANNELS, idx) {
             return None;
         }
-        self.used_clocks |= bits;
-
-        self.state.enable_clock_generator($clock, generator.gclk);
-        let freq = self.gclks[u8::from(generator.gclk) as usize];
-        Some($Type{freq})
+        if self.tree.channels[idx].is_none() {
+            self.state.enable_clock_generator($clock, generator.gclk);
+            self.tree.channels[idx] = Some(generator.gclk);
+            self.tree.token_channels |= 1 << idx;
+        }
+        let freq = self.tree.gclks[u8::from(generator.gclk) as usize];
+        Some($Type{freq, _gclk: PhantomData})
     }
     )+
 }
@@ -313,15 +946,557 @@ clock_generator!(
     (usb, UsbClock, USB),
     (rtc, RtcClock, RTC),
     (adc, AdcClock, ADC),
+    (fdpll32k, Fdpll32kClock, FDPLL32K),
+    (wdt, WdtClock, WDT),
+    (eic, EicClock, EIC),
+    (evsys0, Evsys0Clock, EVSYS_0),
+    (evsys1, Evsys1Clock, EVSYS_1),
+    (evsys2, Evsys2Clock, EVSYS_2),
+    (evsys3, Evsys3Clock, EVSYS_3),
+    (evsys4, Evsys4Clock, EVSYS_4),
+    (evsys5, Evsys5Clock, EVSYS_5),
+    (evsys6, Evsys6Clock, EVSYS_6),
+    (evsys7, Evsys7Clock, EVSYS_7),
+    (evsys8, Evsys8Clock, EVSYS_8),
+    (evsys9, Evsys9Clock, EVSYS_9),
+    (evsys10, Evsys10Clock, EVSYS_10),
+    (evsys11, Evsys11Clock, EVSYS_11),
+    (sercomx_slow, SercomXSlowClock, SERCOMX_SLOW),
+    (tcc0_tcc1, Tcc0Tcc1Clock, TCC0_TCC1),
+    (ac_dig, AcDigClock, AC_DIG),
+    (ac_ana, AcAnaClock, AC_ANA),
+    (dac, DacClock, DAC),
+    (i2s0, I2S0Clock, I2S_0),
+    (i2s1, I2S1Clock, I2S_1),
 );
 
+/// The reference clock of the fractional digital phase locked loop (FDPLL).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DpllReference {
+    /// The external 32kHz crystal oscillator; only available when the
+    /// controller was created with `with_external_32kosc`.
+    Xosc32k,
+    /// The external high speed oscillator, divided by `2 * (div + 1)`.
+    /// The divider is 11 bits wide, so `div` can be at most 2047.
+    Xosc { div: u16 },
+    /// The output of an already configured clock generator.
+    Gclk(ClockGenId),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum DpllRatio {
+    Ratio { ldr: u16, ldrfrac: u8 },
+    OutputFreq(Hertz),
+}
+
+/// Describes how the FDPLL should be configured.
+/// The output frequency is `f_ref * (ldr + 1 + ldrfrac / 16)`; it can
+/// either be set explicitly via `with_ratio` or computed from the desired
+/// output frequency via `with_output_freq`.
+#[derive(Clone, Copy, Debug)]
+pub struct DpllConfig {
+    reference: DpllReference,
+    ratio: DpllRatio,
+    filter: DpllFilter,
+    lock_bypass: bool,
+}
+
+impl DpllConfig {
+    /// Configure the FDPLL with an explicit integer (`ldr`) and
+    /// fractional (`ldrfrac`, in 1/16ths) loop divider ratio.
+    pub fn with_ratio(reference: DpllReference, ldr: u16, ldrfrac: u8) -> Self {
+        Self {
+            reference,
+            ratio: DpllRatio::Ratio { ldr, ldrfrac },
+            filter: DpllFilter::DEFAULT,
+            lock_bypass: false,
+        }
+    }
+
+    /// Configure the FDPLL to run as close as possible to `freq`.
+    pub fn with_output_freq<F: Into<Hertz>>(reference: DpllReference, freq: F) -> Self {
+        Self {
+            reference,
+            ratio: DpllRatio::OutputFreq(freq.into()),
+            filter: DpllFilter::DEFAULT,
+            lock_bypass: false,
+        }
+    }
+
+    /// Select the proportional integral loop filter.
+    pub fn filter(mut self, filter: DpllFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// When set, the FDPLL output is always enabled, even before the
+    /// loop has locked.
+    pub fn lock_bypass(mut self, lock_bypass: bool) -> Self {
+        self.lock_bypass = lock_bypass;
+        self
+    }
+
+    /// Returns the frequency of the reference clock, or zero if
+    /// the reference is not running.
+    fn reference_freq(&self, tree: &ClockTree) -> Hertz {
+        match self.reference {
+            DpllReference::Xosc32k if tree.xosc32k_enabled => XOSC32K_FREQ,
+            DpllReference::Xosc32k => Hertz(0),
+            DpllReference::Xosc { div } => Hertz(tree.xosc_freq.0 / (2 * (div as u32 + 1))),
+            DpllReference::Gclk(gclk) => tree.gclks[u8::from(gclk) as usize],
+        }
+    }
+
+    /// Resolves the loop divider ratio and output frequency for the
+    /// given reference frequency, validating them against the hardware
+    /// limits.
+    fn solve(&self, ref_freq: Hertz) -> Option<(u16, u8, Hertz)> {
+        if let DpllReference::Xosc { div } = self.reference {
+            if div as u32 > DPLL_MAX_XOSC_DIV {
+                return None;
+            }
+        }
+        if ref_freq.0 < DPLL_REF_MIN_FREQ.0 || ref_freq.0 > DPLL_REF_MAX_FREQ.0 {
+            return None;
+        }
+        let (ldr, ldrfrac) = match self.ratio {
+            DpllRatio::Ratio { ldr, ldrfrac } => (ldr, ldrfrac),
+            DpllRatio::OutputFreq(freq) => dpll_ratio(ref_freq, freq)?,
+        };
+        let freq = dpll_output_freq(ref_freq, ldr, ldrfrac);
+        if freq.0 < DPLL_OUT_MIN_FREQ.0 || freq.0 > DPLL_OUT_MAX_FREQ.0 {
+            return None;
+        }
+        Some((ldr, ldrfrac, freq))
+    }
+}
+
+/// A typed token that indicates that the FDPLL has been configured
+/// and has locked.
+/// The effective output frequency is available via the `freq` method,
+/// or by converting the object into a `Hertz` instance.
+#[derive(Debug)]
+pub struct DpllClock {
+    freq: Hertz,
+}
+
+impl DpllClock {
+    /// Returns the output frequency of the FDPLL
+    pub fn freq(&self) -> Hertz {
+        self.freq
+    }
+}
+
+impl Into<Hertz> for DpllClock {
+    fn into(self) -> Hertz {
+        self.freq
+    }
+}
+
+/// Computes the FDPLL loop divider ratio `(ldr, ldrfrac)` that gets
+/// closest to `output` from `reference`.
+/// Returns `None` if the ratio doesn't fit in the hardware registers.
+pub fn dpll_ratio(reference: Hertz, output: Hertz) -> Option<(u16, u8)> {
+    if reference.0 == 0 {
+        return None;
+    }
+    let ref_freq = reference.0 as u64;
+    let ratio = (output.0 as u64 * 16 + ref_freq / 2) / ref_freq;
+    if ratio < 16 || ratio / 16 - 1 > 0x0fff {
+        return None;
+    }
+    Some(((ratio / 16 - 1) as u16, (ratio % 16) as u8))
+}
+
+/// Computes the FDPLL output frequency for a given reference frequency
+/// and loop divider ratio.
+pub fn dpll_output_freq(reference: Hertz, ldr: u16, ldrfrac: u8) -> Hertz {
+    let ratio = (ldr as u64 + 1) * 16 + ldrfrac as u64;
+    Hertz((reference.0 as u64 * ratio / 16) as u32)
+}
+
+/// Errors detected while solving or applying a `ClockPlan`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanError {
+    /// The plan was made for a different 32kHz oscillator than the
+    /// one the `GenericClockController` was created with.
+    BaselineMismatch,
+    /// The clock generator is already configured.
+    AlreadyConfigured(ClockGenId),
+    /// The source of the clock generator is not running, or is not
+    /// part of the plan.
+    SourceUnavailable(ClockGenId),
+    /// The divider needed by the clock generator doesn't fit in its
+    /// division factor register.
+    DividerOutOfRange(ClockGenId, u32),
+    /// The FDPLL is already configured.
+    DpllAlreadyConfigured,
+    /// The FDPLL reference is not running, or the reference or output
+    /// frequency is outside of the range supported by the hardware.
+    InvalidDpll,
+    /// The peripheral channel is routed to a clock generator that is
+    /// neither configured nor part of the plan.
+    GeneratorUnavailable(ClockId),
+    /// The peripheral channel would be clocked faster than it supports.
+    PeripheralTooFast(ClockId, Hertz),
+    /// The peripheral channel is already routed to another clock generator.
+    ChannelInUse(ClockId),
+}
+
+#[derive(Clone, Copy)]
+struct GclkPlan {
+    gclk: ClockGenId,
+    source: ClockSource,
+    target: GclkTarget,
+}
+
+#[derive(Clone, Copy)]
+enum GclkTarget {
+    Freq(Hertz),
+    Divider(u16),
+}
+
+#[derive(Clone, Copy)]
+enum PlanStep {
+    /// Enable the FDPLL with the given loop divider ratio
+    Dpll(u16, u8),
+    Gclk(ClockGenId),
+}
+
+/// A description of the clock tree: oscillators, the FDPLL,
+/// clock generators and the peripheral channels fed from them.
+/// A plan is solved without any hardware access, either against the
+/// clock tree that `GenericClockController` sets up (gclk0 at 48Mhz from
+/// the DFLL48M, gclk1 at 32Khz) with `solve`, or against the tree a
+/// controller has actually configured with `solve_for`.
+/// `GenericClockController::apply_plan` then configures the hardware
+/// accordingly.
+/// The dividers and frequencies are checked against the hardware limits
+/// when the plan is solved; solving it in a unit test on the build host
+/// catches a bad plan before flashing.
+#[derive(Clone, Copy)]
+pub struct ClockPlan {
+    xosc32k_enabled: bool,
+    xosc: Option<(Hertz, XoscMode)>,
+    gclk_in_freqs: [Hertz; NUM_GCLKS],
+    dpll: Option<DpllConfig>,
+    gclks: [Option<GclkPlan>; NUM_GCLKS],
+    improve_duty_cycle: [bool; NUM_GCLKS],
+    channels: [Option<(ClockId, ClockGenId)>; NUM_CHANNELS],
+}
+
+impl ClockPlan {
+    /// Start a plan for a controller created with
+    /// `GenericClockController::with_internal_32kosc`.
+    pub fn with_internal_32kosc() -> Self {
+        Self::new(false)
+    }
+
+    /// Start a plan for a controller created with
+    /// `GenericClockController::with_external_32kosc`.
+    pub fn with_external_32kosc() -> Self {
+        Self::new(true)
+    }
+
+    fn new(xosc32k_enabled: bool) -> Self {
+        Self {
+            xosc32k_enabled,
+            xosc: None,
+            gclk_in_freqs: [Hertz(0); NUM_GCLKS],
+            dpll: None,
+            gclks: [None; NUM_GCLKS],
+            improve_duty_cycle: [false; NUM_GCLKS],
+            channels: [None; NUM_CHANNELS],
+        }
+    }
+
+    /// Enable the external high speed oscillator; see
+    /// `GenericClockController::enable_xosc`.
+    pub fn xosc<F: Into<Hertz>>(mut self, freq: F, mode: XoscMode) -> Self {
+        self.xosc = Some((freq.into(), mode));
+        self
+    }
+
+    /// Declare the frequency of the external clock applied to the
+    /// GCLK_IO pin of a clock generator; see
+    /// `GenericClockController::set_gclk_in_freq`.
+    pub fn gclk_in<F: Into<Hertz>>(mut self, gclk: ClockGenId, freq: F) -> Self {
+        self.gclk_in_freqs[u8::from(gclk) as usize] = freq.into();
+        self
+    }
+
+    /// Configure the FDPLL; see `GenericClockController::configure_dpll`.
+    pub fn dpll(mut self, config: DpllConfig) -> Self {
+        self.dpll = Some(config);
+        self
+    }
+
+    /// Configure a clock generator to run from `source`, using the
+    /// divider that gets closest to `freq`.
+    pub fn gclk<F: Into<Hertz>>(mut self, gclk: ClockGenId, source: ClockSource, freq: F) -> Self {
+        self.gclks[u8::from(gclk) as usize] = Some(GclkPlan {
+            gclk,
+            source,
+            target: GclkTarget::Freq(freq.into()),
+        });
+        self
+    }
+
+    /// Configure a clock generator to run from `source` with an
+    /// explicit divider.
+    pub fn gclk_divider(mut self, gclk: ClockGenId, source: ClockSource, divider: u16) -> Self {
+        self.gclks[u8::from(gclk) as usize] = Some(GclkPlan {
+            gclk,
+            source,
+            target: GclkTarget::Divider(divider),
+        });
+        self
+    }
+
+    /// Enable a 50/50 duty cycle for odd dividers of a planned clock
+    /// generator; see `GclkConfig::improve_duty_cycle`. It is disabled
+    /// by default.
+    pub fn improve_duty_cycle(mut self, gclk: ClockGenId, improve_duty_cycle: bool) -> Self {
+        self.improve_duty_cycle[u8::from(gclk) as usize] = improve_duty_cycle;
+        self
+    }
+
+    /// Route a peripheral channel to a clock generator.
+    pub fn channel(mut self, id: ClockId, gclk: ClockGenId) -> Self {
+        self.channels[u8::from(id) as usize] = Some((id, gclk));
+        self
+    }
+
+    /// Works out the dividers and frequencies of the whole clock tree,
+    /// starting from the tree that `GenericClockController` sets up, and
+    /// checks them against the hardware limits.
+    /// This doesn't access the hardware.
+    pub fn solve(&self) -> Result<ClockSolution, PlanError> {
+        self.solve_from(ClockTree::new(self.xosc32k_enabled))
+    }
+
+    /// Like `solve`, but starts from the clock tree that `clocks` has
+    /// configured so far, so that the plan can build on the generators,
+    /// the FDPLL and the peripheral channels set up outside of it.
+    pub fn solve_for(&self, clocks: &GenericClockController) -> Result<ClockSolution, PlanError> {
+        if self.xosc32k_enabled != clocks.tree.xosc32k_enabled {
+            return Err(PlanError::BaselineMismatch);
+        }
+        let mut tree = clocks.tree;
+        tree.release_dropped_tokens();
+        self.solve_from(tree)
+    }
+
+    fn solve_from(&self, mut tree: ClockTree) -> Result<ClockSolution, PlanError> {
+        for gclk_plan in self.gclks.iter().flatten() {
+            if tree.gclks[u8::from(gclk_plan.gclk) as usize].0 != 0 {
+                return Err(PlanError::AlreadyConfigured(gclk_plan.gclk));
+            }
+        }
+        if let Some(config) = self.dpll {
+            if tree.dpll_freq.0 != 0 {
+                return Err(PlanError::DpllAlreadyConfigured);
+            }
+            if let DpllReference::Gclk(_) = config.reference {
+                if tree.channels[u8::from(FDPLL) as usize].is_some() {
+                    return Err(PlanError::ChannelInUse(FDPLL));
+                }
+            }
+        }
+        for &(id, gclk) in self.channels.iter().flatten() {
+            match tree.channels[u8::from(id) as usize] {
+                Some(current) if current != gclk => return Err(PlanError::ChannelInUse(id)),
+                _ => (),
+            }
+        }
+
+        if let Some((freq, _)) = self.xosc {
+            tree.xosc_freq = freq;
+        }
+        for (idx, freq) in self.gclk_in_freqs.iter().enumerate() {
+            if freq.0 != 0 {
+                tree.gclk_in_freqs[idx] = *freq;
+            }
+        }
+
+        let mut steps = [None; NUM_GCLKS + 1];
+        let mut num_steps = 0;
+        let mut pending = self.gclks;
+        let mut pending_dpll = self.dpll;
+
+        // Resolve the generators and the DPLL in dependency order; each
+        // pass resolves at least one of them, or we are stuck.
+        loop {
+            let mut progress = false;
+            if let Some(config) = pending_dpll {
+                let ref_freq = config.reference_freq(&tree);
+                if ref_freq.0 != 0 {
+                    let (ldr, ldrfrac, freq) = config.solve(ref_freq).ok_or(PlanError::InvalidDpll)?;
+                    if let DpllReference::Gclk(gclk) = config.reference {
+                        tree.channels[u8::from(FDPLL) as usize] = Some(gclk);
+                    }
+                    tree.dpll_freq = freq;
+                    steps[num_steps] = Some(PlanStep::Dpll(ldr, ldrfrac));
+                    num_steps += 1;
+                    pending_dpll = None;
+                    progress = true;
+                }
+            }
+            for (idx, slot) in pending.iter_mut().enumerate() {
+                let gclk_plan = match *slot {
+                    Some(gclk_plan) => gclk_plan,
+                    None => continue,
+                };
+                let src_freq = tree.source_freq(gclk_plan.source, idx);
+                if src_freq.0 == 0 {
+                    continue;
+                }
+                let divider = match gclk_plan.target {
+                    GclkTarget::Divider(divider) => (divider as u32).max(1),
+                    GclkTarget::Freq(freq) => {
+                        let freq = freq.0.max(1);
+                        ((src_freq.0 + freq / 2) / freq).max(1)
+                    }
+                };
+                if divider > gclk_max_divider(gclk_plan.gclk) {
+                    return Err(PlanError::DividerOutOfRange(gclk_plan.gclk, divider));
+                }
+                tree.gclks[idx] = Hertz(src_freq.0 / divider);
+                tree.gclk_configs[idx] = Some(
+                    GclkConfig::new(gclk_plan.source)
+                        .divider(divider as u16)
+                        .improve_duty_cycle(self.improve_duty_cycle[idx]),
+                );
+                steps[num_steps] = Some(PlanStep::Gclk(gclk_plan.gclk));
+                num_steps += 1;
+                *slot = None;
+                progress = true;
+            }
+            if !progress {
+                break;
+            }
+        }
+        if let Some(gclk_plan) = pending.iter().flatten().next() {
+            return Err(PlanError::SourceUnavailable(gclk_plan.gclk));
+        }
+        if pending_dpll.is_some() {
+            return Err(PlanError::InvalidDpll);
+        }
+
+        for &(id, gclk) in self.channels.iter().flatten() {
+            let freq = tree.gclks[u8::from(gclk) as usize];
+            if freq.0 == 0 {
+                return Err(PlanError::GeneratorUnavailable(id));
+            }
+            if freq.0 > peripheral_max_freq(id).0 {
+                return Err(PlanError::PeripheralTooFast(id, freq));
+            }
+            tree.channels[u8::from(id) as usize] = Some(gclk);
+            tree.token_channels &= !(1 << u8::from(id));
+        }
+
+        Ok(ClockSolution { tree, steps })
+    }
+}
+
+/// The result of solving a `ClockPlan`: the clock tree once the plan
+/// has been applied.
+#[derive(Clone, Copy)]
+pub struct ClockSolution {
+    tree: ClockTree,
+    steps: [Option<PlanStep>; NUM_GCLKS + 1],
+}
+
+impl ClockSolution {
+    /// Returns the frequency of a clock generator, or `None` if it is
+    /// not running.
+    pub fn gclk_freq(&self, gclk: ClockGenId) -> Option<Hertz> {
+        let freq = self.tree.gclks[u8::from(gclk) as usize];
+        if freq.0 == 0 {
+            None
+        } else {
+            Some(freq)
+        }
+    }
+
+    /// Returns the divider of a clock generator, or `None` if it is
+    /// not running.
+    pub fn gclk_divider(&self, gclk: ClockGenId) -> Option<GclkDivider> {
+        self.tree.gclk_configs[u8::from(gclk) as usize].map(|config| config.divider)
+    }
+
+    /// Returns the output frequency of the FDPLL, or `None` if it is
+    /// not running.
+    pub fn dpll_freq(&self) -> Option<Hertz> {
+        if self.tree.dpll_freq.0 == 0 {
+            None
+        } else {
+            Some(self.tree.dpll_freq)
+        }
+    }
+}
+
+/// Returns the largest value the division factor of a clock
+/// generator can hold.
+pub fn gclk_max_divider(gclk: ClockGenId) -> u32 {
+    match gclk {
+        GCLK1 => 0xffff,
+        GCLK2 => 0x1f,
+        _ => 0xff,
+    }
+}
+
+/// Returns the highest frequency a peripheral channel may be clocked at.
+pub fn peripheral_max_freq(id: ClockId) -> Hertz {
+    match id {
+        DFLL48 => Hertz(33_000),
+        FDPLL => DPLL_REF_MAX_FREQ,
+        TCC0_TCC1 => DPLL_OUT_MAX_FREQ,
+        AC_ANA => Hertz(64_000),
+        DAC => Hertz(350_000),
+        _ => OSC48M_FREQ,
+    }
+}
+
+/// Selects how the external high speed oscillator (XOSC) is driven.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XoscMode {
+    /// A crystal is connected between XIN and XOUT.
+    Crystal,
+    /// An external clock signal is applied to XIN; XOUT is
+    /// left free for use as a GPIO.
+    ExternalClock,
+}
+
 /// The frequency of the 48Mhz source.
 pub const OSC48M_FREQ: Hertz = Hertz(48_000_000);
 /// The frequency of the 32Khz source.
 pub const OSC32K_FREQ: Hertz = Hertz(32_000);
+/// The exact frequency of the external 32Khz crystal.
+pub const XOSC32K_FREQ: Hertz = Hertz(32_768);
+/// The minimum FDPLL reference frequency.
+pub const DPLL_REF_MIN_FREQ: Hertz = Hertz(32_000);
+/// The maximum FDPLL reference frequency.
+pub const DPLL_REF_MAX_FREQ: Hertz = Hertz(2_000_000);
+/// The largest XOSC divider of the FDPLL reference.
+pub const DPLL_MAX_XOSC_DIV: u32 = 0x7ff;
+/// The minimum FDPLL output frequency.
+pub const DPLL_OUT_MIN_FREQ: Hertz = Hertz(48_000_000);
+/// The maximum FDPLL output frequency.
+pub const DPLL_OUT_MAX_FREQ: Hertz = Hertz(96_000_000);
+
+/// Returns the number of NVM read wait states the CPU needs when it
+/// runs at `freq`, assuming a supply voltage of at least 2.7V.
+pub fn flash_wait_states(freq: Hertz) -> u8 {
+    if freq.0 <= 24_000_000 {
+        0
+    } else {
+        1
+    }
+}
 
-fn set_flash_to_half_auto_wait_state(nvmctrl: &mut NVMCTRL) {
-    nvmctrl.ctrlb.modify(|_, w| w.rws().half());
+fn set_flash_wait_states(nvmctrl: &mut NVMCTRL, wait_states: u8) {
+    nvmctrl.ctrlb.modify(|_, w| unsafe { w.rws().bits(wait_states) });
 }
 
 fn enable_gclk_apb(pm: &mut PM) {
@@ -365,6 +1540,32 @@ fn enable_external_32kosc(sysctrl: &mut SYSCTRL) {
     }
 }
 
+/// Turn on the external high speed oscillator
+fn enable_xosc(sysctrl: &mut SYSCTRL, freq: Hertz, mode: XoscMode) {
+    sysctrl.xosc.write(|w| {
+        match freq.0 {
+            0..=2_000_000 => w.gain()._0(),
+            2_000_001..=4_000_000 => w.gain()._1(),
+            4_000_001..=8_000_000 => w.gain()._2(),
+            8_000_001..=16_000_000 => w.gain()._3(),
+            _ => w.gain()._4(),
+        };
+        if mode == XoscMode::Crystal {
+            unsafe {
+                // 10 here means: use 1024 cycles of OSCULP32k (~31ms)
+                // to start up this oscillator
+                w.startup().bits(10);
+            }
+            w.xtalen().set_bit();
+        }
+        w.ondemand().clear_bit()
+    });
+    sysctrl.xosc.modify(|_, w| w.enable().set_bit());
+    while sysctrl.pclksr.read().xoscrdy().bit_is_clear() {
+        // Wait for the oscillator to stabilize
+    }
+}
+
 fn wait_for_dfllrdy(sysctrl: &mut SYSCTRL) {
     while sysctrl.pclksr.read().dfllrdy().bit_is_clear() {}
 }
@@ -442,3 +1643,126 @@ fn configure_and_enable_dfll48m(sysctrl: &mut SYSCTRL, use_external_crystal: boo
 
     wait_for_dfllrdy(sysctrl);
 }
+
+/// Configure and enable the FDPLL, then wait for it to lock
+fn configure_and_enable_dpll(sysctrl: &mut SYSCTRL, config: &DpllConfig, ldr: u16, ldrfrac: u8) {
+    sysctrl.dpllctrla.write(|w| w.enable().clear_bit());
+    while sysctrl.dpllstatus.read().enable().bit_is_set() {}
+
+    sysctrl.dpllratio.write(|w| unsafe {
+        w.ldr().bits(ldr);
+        w.ldrfrac().bits(ldrfrac)
+    });
+    sysctrl.dpllctrlb.write(|w| {
+        match config.reference {
+            DpllReference::Xosc32k => w.refclk().ref0(),
+            DpllReference::Xosc { div } => {
+                unsafe {
+                    w.div().bits(div);
+                }
+                w.refclk().ref1()
+            }
+            DpllReference::Gclk(_) => w.refclk().gclk(),
+        };
+        w.filter().variant(config.filter);
+        w.lbypass().bit(config.lock_bypass)
+    });
+    sysctrl.dpllctrla.write(|w| {
+        w.ondemand().clear_bit();
+        w.enable().set_bit()
+    });
+
+    while sysctrl.dpllstatus.read().lock().bit_is_clear()
+        || sysctrl.dpllstatus.read().clkrdy().bit_is_clear()
+    {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider_limits() {
+        let plan = ClockPlan::with_internal_32kosc().gclk(GCLK2, DFLL48M, Hertz(1_000));
+        assert_eq!(plan.solve().err(), Some(PlanError::DividerOutOfRange(GCLK2, 48_000)));
+
+        let plan = ClockPlan::with_internal_32kosc().gclk_divider(GCLK3, DFLL48M, 256);
+        assert_eq!(plan.solve().err(), Some(PlanError::DividerOutOfRange(GCLK3, 256)));
+
+        let plan = ClockPlan::with_internal_32kosc().gclk_divider(GCLK3, DFLL48M, 255);
+        let solution = plan.solve().unwrap();
+        assert_eq!(solution.gclk_freq(GCLK3), Some(Hertz(48_000_000 / 255)));
+    }
+
+    #[test]
+    fn improve_duty_cycle_is_planned() {
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk_divider(GCLK3, OSC8M, 3)
+            .gclk_divider(GCLK4, OSC8M, 3)
+            .improve_duty_cycle(GCLK4, true);
+        let tree = plan.solve().unwrap().tree;
+        assert!(!tree.gclk_configs[3].unwrap().improve_duty_cycle);
+        assert!(tree.gclk_configs[4].unwrap().improve_duty_cycle);
+    }
+
+    #[test]
+    fn peripheral_max_freq_is_checked() {
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk(GCLK3, OSC8M, Hertz(8_000_000))
+            .channel(DAC, GCLK3);
+        assert_eq!(
+            plan.solve().err(),
+            Some(PlanError::PeripheralTooFast(DAC, Hertz(8_000_000)))
+        );
+
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk(GCLK3, OSC8M, Hertz(250_000))
+            .channel(DAC, GCLK3);
+        assert_eq!(plan.solve().unwrap().gclk_freq(GCLK3), Some(Hertz(250_000)));
+    }
+
+    #[test]
+    fn unavailable_generator() {
+        let plan = ClockPlan::with_internal_32kosc().channel(SERCOM0_CORE, GCLK4);
+        assert_eq!(
+            plan.solve().err(),
+            Some(PlanError::GeneratorUnavailable(SERCOM0_CORE))
+        );
+
+        // A generator configured outside of the plan can be used
+        let mut tree = ClockTree::new(false);
+        tree.gclks[4] = Hertz(8_000_000);
+        tree.gclk_configs[4] = Some(GclkConfig::new(OSC8M));
+        let solution = plan.solve_from(tree).unwrap();
+        assert_eq!(solution.gclk_freq(GCLK4), Some(Hertz(8_000_000)));
+
+        // but not planned a second time
+        let plan = ClockPlan::with_internal_32kosc().gclk(GCLK4, OSC8M, Hertz(1_000_000));
+        assert_eq!(
+            plan.solve_from(tree).err(),
+            Some(PlanError::AlreadyConfigured(GCLK4))
+        );
+    }
+
+    #[test]
+    fn dpll_from_32k_oscillator() {
+        let plan = ClockPlan::with_external_32kosc().dpll(DpllConfig::with_output_freq(
+            DpllReference::Xosc32k,
+            Hertz(96_000_000),
+        ));
+        let freq = plan.solve().unwrap().dpll_freq().unwrap();
+        assert!(freq.0 <= 96_000_000 && freq.0 > 95_900_000);
+
+        // The XOSC divider doesn't fit in its register
+        let config = DpllConfig::with_ratio(DpllReference::Xosc { div: 0x800 }, 2999, 0);
+        assert!(config.solve(Hertz(32_000)).is_none());
+        let config = DpllConfig::with_ratio(DpllReference::Xosc { div: 0x7ff }, 2999, 0);
+        assert!(config.solve(Hertz(32_000)).is_some());
+    }
+
+    #[test]
+    fn gclk1_runs_from_the_32k_oscillator() {
+        assert_eq!(ClockTree::new(true).gclks[1], XOSC32K_FREQ);
+        assert_eq!(ClockTree::new(false).gclks[1], OSC32K_FREQ);
+    }
+}
@@ -0,0 +1,328 @@
+//! Working with the external interrupt controller
+//!
+//! The EIC detects edges or levels on up to 16 interrupt lines, EXTINT0
+//! to EXTINT15. Each pin that can be sensed is routed to one of the
+//! lines while it is in peripheral function A, and several pins share
+//! each line. `Eic::init` hands out one token per line, and turning a
+//! pin into an `ExtInt` consumes the token of its line, so that no two
+//! pins can use the same line at once.
+//!
+//! The pin keeps the pull configuration it had before it was switched
+//! to function A, so an active-low interrupt signal can be pulled up
+//! with `into_pull_up_input` first.
+//!
+//! A line with `enable_wakeup` wakes the core from sleep. For standby,
+//! the generic clock of the EIC has to keep running, which needs
+//! `GclkConfig::run_standby` on its clock generator.
+use crate::clock;
+use crate::gpio::{
+    Pa0, Pa1, Pa2, Pa3, Pa4, Pa5, Pa6, Pa7, Pa9, Pa10, Pa11, Pa12, Pa13, Pa14, Pa15, Pa16,
+    Pa17, Pa18, Pa19, Pa20, Pa21, Pa22, Pa23, Pa24, Pa25, Pa27, Pa28, Pa30, Pa31, PfA,
+};
+#[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+use crate::gpio::{
+    Pb0, Pb1, Pb2, Pb3, Pb4, Pb5, Pb6, Pb7, Pb8, Pb9, Pb10, Pb11, Pb12, Pb13, Pb14, Pb15,
+    Pb16, Pb17, Pb22, Pb23, Pb30, Pb31,
+};
+use crate::target_device::{EIC, PM};
+
+/// The condition on the pin that raises the interrupt flag of a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sense {
+    /// The line never triggers
+    None = 0,
+    /// A rising edge
+    Rise,
+    /// A falling edge
+    Fall,
+    /// Both edges
+    Both,
+    /// The pin is high
+    High,
+    /// The pin is low
+    Low,
+}
+
+/// One of the 16 interrupt lines of the EIC
+pub trait ExtIntLine {
+    /// The number of the line, from 0 to 15
+    const NUM: usize;
+}
+
+/// A pin that the EIC can sense while it is in peripheral function A
+pub trait EicPin {
+    /// The line the pin is routed to
+    type Line: ExtIntLine;
+}
+
+/// The external interrupt controller, needed to configure its lines
+pub struct Eic {
+    eic: EIC,
+}
+
+macro_rules! lines {
+    ($($Line:ident: ($line_ident:ident, $num:expr),)+) => {
+
+$(
+/// The token for the interrupt line with the matching number
+pub struct $Line {
+    _0: (),
+}
+
+impl ExtIntLine for $Line {
+    const NUM: usize = $num;
+}
+)+
+
+/// The EIC and the tokens of its interrupt lines
+pub struct Parts {
+    pub eic: Eic,
+    $(
+        pub $line_ident: $Line,
+    )+
+}
+
+impl Eic {
+    /// Resets and enables the EIC, with all lines disabled
+    pub fn init(eic: EIC, _clock: &clock::EicClock, pm: &mut PM) -> Parts {
+        pm.apbamask.modify(|_, w| w.eic_().set_bit());
+        eic.ctrl.write(|w| w.swrst().set_bit());
+        while eic.status.read().syncbusy().bit_is_set() {}
+        eic.ctrl.write(|w| w.enable().set_bit());
+        while eic.status.read().syncbusy().bit_is_set() {}
+
+        Parts {
+            eic: Eic { eic },
+            $(
+                $line_ident: $Line { _0: () },
+            )+
+        }
+    }
+}
+
+impl Parts {
+    /// Disables the EIC and releases it, once the tokens of all the
+    /// lines are back in place
+    pub fn free(self) -> EIC {
+        self.eic.eic.ctrl.write(|w| w.enable().clear_bit());
+        while self.eic.eic.status.read().syncbusy().bit_is_set() {}
+        self.eic.eic
+    }
+}
+
+    };
+}
+
+lines! {
+    Line0: (line0, 0),
+    Line1: (line1, 1),
+    Line2: (line2, 2),
+    Line3: (line3, 3),
+    Line4: (line4, 4),
+    Line5: (line5, 5),
+    Line6: (line6, 6),
+    Line7: (line7, 7),
+    Line8: (line8, 8),
+    Line9: (line9, 9),
+    Line10: (line10, 10),
+    Line11: (line11, 11),
+    Line12: (line12, 12),
+    Line13: (line13, 13),
+    Line14: (line14, 14),
+    Line15: (line15, 15),
+}
+
+impl Eic {
+    /// Replaces the 4 configuration bits of `line`
+    fn configure(&mut self, line: usize, mask: u32, bits: u32) {
+        let shift = (line % 8) * 4;
+        self.eic.config[line / 8].modify(|r, w| unsafe {
+            w.bits(r.bits() & !(mask << shift) | (bits & mask) << shift)
+        });
+    }
+}
+
+/// A pin sensed by its interrupt line of the EIC
+pub struct ExtInt<P: EicPin> {
+    pin: P,
+    line: P::Line,
+}
+
+impl<P: EicPin> ExtInt<P> {
+    /// Connects the pin to its interrupt line, which starts out not
+    /// sensing anything
+    pub fn new(pin: P, line: P::Line) -> Self {
+        ExtInt { pin, line }
+    }
+
+    /// The number of the interrupt line
+    pub fn line(&self) -> usize {
+        P::Line::NUM
+    }
+
+    fn mask(&self) -> u32 {
+        1 << P::Line::NUM
+    }
+
+    /// Selects the condition on the pin that raises the interrupt flag
+    pub fn sense(&mut self, eic: &mut Eic, sense: Sense) {
+        eic.configure(P::Line::NUM, 0b0111, sense as u32);
+    }
+
+    /// Enables the majority filter, which only accepts a level that has
+    /// been sampled on at least two out of three clocks of the EIC
+    pub fn filter(&mut self, eic: &mut Eic, enabled: bool) {
+        eic.configure(P::Line::NUM, 0b1000, if enabled { 0b1000 } else { 0 });
+    }
+
+    /// Lets the line wake the core from sleep
+    pub fn enable_wakeup(&mut self, eic: &mut Eic) {
+        let mask = self.mask();
+        eic.eic.wakeup.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+    }
+
+    /// Stops the line from waking the core from sleep
+    pub fn disable_wakeup(&mut self, eic: &mut Eic) {
+        let mask = self.mask();
+        eic.eic.wakeup.modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
+
+    /// Generates an event for the event system when the line triggers,
+    /// for example to start a capture with a timer counter
+    pub fn enable_event(&mut self, eic: &mut Eic) {
+        let mask = self.mask();
+        eic.eic.evctrl.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+    }
+
+    /// Stops generating events
+    pub fn disable_event(&mut self, eic: &mut Eic) {
+        let mask = self.mask();
+        eic.eic.evctrl.modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
+
+    /// Enables the interrupt of the line. It shares the EIC interrupt
+    /// with all the other lines.
+    pub fn enable_interrupt(&mut self) {
+        let mask = self.mask();
+        unsafe { (*EIC::ptr()).intenset.write(|w| w.bits(mask)) };
+    }
+
+    /// Disables the interrupt of the line
+    pub fn disable_interrupt(&mut self) {
+        let mask = self.mask();
+        unsafe { (*EIC::ptr()).intenclr.write(|w| w.bits(mask)) };
+    }
+
+    /// Returns true if the line has triggered since its flag was last
+    /// cleared
+    pub fn is_interrupt(&self) -> bool {
+        unsafe { (*EIC::ptr()).intflag.read().bits() & self.mask() != 0 }
+    }
+
+    /// Clears the interrupt flag of the line
+    pub fn clear_interrupt(&mut self) {
+        let mask = self.mask();
+        // Writing a 1 clears the flag
+        unsafe { (*EIC::ptr()).intflag.write(|w| w.bits(mask)) };
+    }
+
+    /// Stops sensing the pin, and releases it along with the token of
+    /// its line
+    pub fn free(mut self, eic: &mut Eic) -> (P, P::Line) {
+        self.disable_interrupt();
+        self.disable_wakeup(eic);
+        self.disable_event(eic);
+        self.sense(eic, Sense::None);
+        self.filter(eic, false);
+        self.clear_interrupt();
+        (self.pin, self.line)
+    }
+}
+
+macro_rules! eic_pins {
+    ($($(#[$attr:meta])* $Pin:ident: $Line:ident,)+) => {
+        $(
+            $(#[$attr])*
+            impl EicPin for $Pin<PfA> {
+                type Line = $Line;
+            }
+        )+
+    };
+}
+
+// PA08 is routed to the non-maskable interrupt instead of a line
+eic_pins! {
+    Pa0: Line0,
+    Pa1: Line1,
+    Pa2: Line2,
+    Pa3: Line3,
+    Pa4: Line4,
+    Pa5: Line5,
+    Pa6: Line6,
+    Pa7: Line7,
+    Pa9: Line9,
+    Pa10: Line10,
+    Pa11: Line11,
+    Pa12: Line12,
+    Pa13: Line13,
+    Pa14: Line14,
+    Pa15: Line15,
+    Pa16: Line0,
+    Pa17: Line1,
+    Pa18: Line2,
+    Pa19: Line3,
+    Pa20: Line4,
+    Pa21: Line5,
+    Pa22: Line6,
+    Pa23: Line7,
+    Pa24: Line12,
+    Pa25: Line13,
+    Pa27: Line15,
+    Pa28: Line8,
+    Pa30: Line10,
+    Pa31: Line11,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb0: Line0,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb1: Line1,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb2: Line2,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb3: Line3,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb4: Line4,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb5: Line5,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb6: Line6,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb7: Line7,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb8: Line8,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb9: Line9,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb10: Line10,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb11: Line11,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb12: Line12,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb13: Line13,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb14: Line14,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb15: Line15,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb16: Line0,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb17: Line1,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb22: Line6,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb23: Line7,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb30: Line14,
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb31: Line15,
+}
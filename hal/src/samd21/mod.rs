@@ -1,6 +1,9 @@
 pub mod calibration;
+pub mod capture;
 pub mod clock;
+pub mod eic;
 pub mod pwm;
+pub mod rtc;
 pub mod sercom;
 pub mod timer; 
 
@@ -1,18 +1,117 @@
-use crate::gpio::{Pa19, Pa15, PfE};
+use crate::gpio::{Pa14, Pa15, Pa18, Pa19, Pa22, Pa23, Pa24, Pa25, PfE};
+
+#[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+use crate::gpio::{Pb8, Pb9, Pb10, Pb11};
+
+#[cfg(feature = "samd21j18a")]
+use crate::gpio::{Pa20, Pa21, Pb0, Pb1, Pb2, Pb3, Pb12, Pb13, Pb14, Pb15, Pb16, Pb17, Pb22, Pb23};
 use crate::clock;
-use crate::timer::TimerParams;
-use crate::time::Hertz;
+use crate::timer::{TimerParams, TimerParamsError, DIVIDERS, MAX_CYCLES_16};
+use crate::time::{cmp_ratios, Hertz, Period};
 use crate::hal::PwmPin;
 
-use crate::target_device::{TC3, PM};
+use crate::target_device::{PM, TC3, TC4, TC5, TCC0, TCC1, TCC2};
+
+#[cfg(feature = "samd21j18a")]
+use crate::target_device::{TC6, TC7};
+
+/// The pins of TC3 carrying WO[0]
+pub enum TC3Wo0Pinout {
+    Pa14(Pa14<PfE>),
+    Pa18(Pa18<PfE>),
+}
 
+/// The pins of TC3 carrying WO[1]
 pub enum TC3Pinout {
     Pa15(Pa15<PfE>),
     Pa19(Pa19<PfE>),
 }
 
-pub enum Channel { 
-    C0
+/// The pins of TC4 carrying WO[0]
+pub enum TC4Wo0Pinout {
+    Pa22(Pa22<PfE>),
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb8(Pb8<PfE>),
+    #[cfg(feature = "samd21j18a")]
+    Pb12(Pb12<PfE>),
+}
+
+/// The pins of TC4 carrying WO[1]
+pub enum TC4Pinout {
+    Pa23(Pa23<PfE>),
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb9(Pb9<PfE>),
+    #[cfg(feature = "samd21j18a")]
+    Pb13(Pb13<PfE>),
+}
+
+/// The pins of TC5 carrying WO[0]
+pub enum TC5Wo0Pinout {
+    Pa24(Pa24<PfE>),
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb10(Pb10<PfE>),
+    #[cfg(feature = "samd21j18a")]
+    Pb14(Pb14<PfE>),
+}
+
+/// The pins of TC5 carrying WO[1]
+pub enum TC5Pinout {
+    Pa25(Pa25<PfE>),
+    #[cfg(any(feature = "samd21g18a", feature = "samd21j18a"))]
+    Pb11(Pb11<PfE>),
+    #[cfg(feature = "samd21j18a")]
+    Pb15(Pb15<PfE>),
+}
+
+/// The pins of TC6 carrying WO[0]
+#[cfg(feature = "samd21j18a")]
+pub enum TC6Wo0Pinout {
+    Pb2(Pb2<PfE>),
+    Pb16(Pb16<PfE>),
+}
+
+/// The pins of TC6 carrying WO[1]
+#[cfg(feature = "samd21j18a")]
+pub enum TC6Pinout {
+    Pb3(Pb3<PfE>),
+    Pb17(Pb17<PfE>),
+}
+
+/// The pins of TC7 carrying WO[0]
+#[cfg(feature = "samd21j18a")]
+pub enum TC7Wo0Pinout {
+    Pa20(Pa20<PfE>),
+    Pb0(Pb0<PfE>),
+    Pb22(Pb22<PfE>),
+}
+
+/// The pins of TC7 carrying WO[1]
+#[cfg(feature = "samd21j18a")]
+pub enum TC7Pinout {
+    Pa21(Pa21<PfE>),
+    Pb1(Pb1<PfE>),
+    Pb23(Pb23<PfE>),
+}
+
+/// The channels of a TC; channel n drives WO[n]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    C0,
+    C1,
+}
+
+/// The divider selected by the value of a PRESCALER field
+fn prescaler_divider(bits: u8) -> u32 {
+    match bits {
+        0 => 1,
+        1 => 2,
+        2 => 4,
+        3 => 8,
+        4 => 16,
+        5 => 64,
+        6 => 256,
+        _ => 1024,
+    }
 }
 
 macro_rules! pwm {
@@ -29,7 +128,7 @@ pub struct $TYPE {
 }
 
 impl $TYPE {
-    pub fn new<F: Into<Hertz>> (
+    pub fn new<F: Into<Period>> (
         clock: &clock::$clock,
         freq: F,
         tc: $TC,
@@ -39,7 +138,9 @@ impl $TYPE {
         let freq = freq.into();
         {
             let count = tc.count16();
-            let params = TimerParams::new(freq, clock.freq().0);
+            // out of range frequencies are clamped, use `set_period` to
+            // find out about them
+            let params = TimerParams::new_saturating(freq, clock.freq().0, MAX_CYCLES_16);
             pm.$apmask.modify(|_, w| w.$apbits().set_bit());
             count.ctrla.write(|w| w.swrst().set_bit());
             while count.ctrla.read().bits() & 1 != 0 {}
@@ -57,8 +158,8 @@ impl $TYPE {
                     _ => unreachable!(),
                 }
             });
-            count.ctrla.write(|w| w.wavegen().mpwm());
-            count.cc[0].write(|w| unsafe { w.cc().bits(params.cycles as u16) });
+            count.ctrla.modify(|_, w| w.wavegen().mpwm());
+            count.cc[0].write(|w| unsafe { w.cc().bits(params.top() as u16) });
             count.cc[1].write(|w| unsafe { w.cc().bits(0) });
             count.ctrla.modify(|_, w| w.enable().set_bit());
         }
@@ -70,12 +171,15 @@ impl $TYPE {
         }
     }
 
-    pub fn set_period<P>(&mut self, period: P)
+    /// Changes the period of the pwm, given either as a frequency or as a
+    /// span of time, returning the period actually achieved. The current
+    /// period is kept if the requested one is out of range.
+    pub fn set_period<P>(&mut self, period: P) -> Result<Period, TimerParamsError>
     where
-        P: Into<Hertz>
+        P: Into<Period>
     {
         let period = period.into();
-        let params = TimerParams::new(period, self.clock_freq.0);
+        let params = TimerParams::new(period, self.clock_freq.0)?;
         let count = self.tc.count16();
         count.ctrla.modify(|_, w| w.enable().clear_bit());
         count.ctrla.modify(|_, w| {
@@ -92,14 +196,15 @@ impl $TYPE {
                 }
             });
         count.ctrla.modify(|_, w| w.enable().set_bit());
-        count.cc[0].write(|w| unsafe { w.cc().bits(params.cycles as u16) });
+        count.cc[0].write(|w| unsafe { w.cc().bits(params.top() as u16) });
+        Ok(params.period)
     }
 
     pub fn get_period(&self) -> Hertz {
         let count = self.tc.count16();
-        let divisor = count.ctrla.read().prescaler().bits(); 
+        let divisor = prescaler_divider(count.ctrla.read().prescaler().bits());
         let top = count.cc[0].read().cc().bits();
-        Hertz(self.clock_freq.0 / divisor as u32 / (top + 1) as u32)
+        Hertz(self.clock_freq.0 / divisor / (top as u32 + 1))
     }
 }
 
@@ -139,4 +244,798 @@ impl PwmPin for $TYPE {
 
 pwm! {
     Pwm3: (TC3, TC3Pinout, Tcc2Tc3Clock, apbcmask, tc3_, Pwm3Wrapper),
+    Pwm4: (TC4, TC4Pinout, Tc4Tc5Clock, apbcmask, tc4_, Pwm4Wrapper),
+    Pwm5: (TC5, TC5Pinout, Tc4Tc5Clock, apbcmask, tc5_, Pwm5Wrapper),
+}
+
+#[cfg(feature = "samd21j18a")]
+pwm! {
+    Pwm6: (TC6, TC6Pinout, Tc6Tc7Clock, apbcmask, tc6_, Pwm6Wrapper),
+    Pwm7: (TC7, TC7Pinout, Tc6Tc7Clock, apbcmask, tc7_, Pwm7Wrapper),
+}
+
+/// The counter resolution of a two channel TC pwm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcPwmResolution {
+    /// An 8-bit counter with its own period register: the frequency can be
+    /// set finely, at the cost of at most 256 steps of duty cycle
+    Bits8,
+    /// A 16-bit counter that always counts to its maximum: 65536 steps of
+    /// duty cycle, but the frequency can only be set with the prescaler
+    Bits16,
+}
+
+/// Picks the prescaler for a 16-bit counter counting to its maximum
+fn npwm16_divider(period: Period, src_freq: u32) -> Result<u16, TimerParamsError> {
+    if period == Period::Frequency(Hertz(0)) {
+        return Err(TimerParamsError::ZeroFrequency);
+    }
+    // the period is numerator / denominator ticks of the source clock
+    let (numerator, denominator) = period.ticks_ratio(Hertz(src_freq));
+    if numerator < denominator * 0x1_0000 {
+        return Err(TimerParamsError::TooFast);
+    }
+    if numerator > denominator * 0x1_0000 * 1024 {
+        return Err(TimerParamsError::TooSlow);
+    }
+    DIVIDERS
+        .iter()
+        .map(|&divider| TimerParams::from_parts(divider, 0x1_0000, src_freq))
+        .min_by(|x, y| {
+            let (a, b) = x.error(period, src_freq);
+            let (c, d) = y.error(period, src_freq);
+            cmp_ratios(a, b, c, d)
+        })
+        .map(|params| params.divider)
+        .ok_or(TimerParamsError::TooSlow)
+}
+
+macro_rules! pwm_dual {
+    ($($TYPE:ident: ($TC:ident, $wo0:ident, $wo1:ident, $clock:ident, $apmask:ident, $apbits:ident),)+) => {
+        $(
+
+/// A TC generating PWM on both of its outputs, WO[0] from channel 0 and
+/// WO[1] from channel 1
+pub struct $TYPE {
+    /// The frequency of the attached clock, not the period of the pwm.
+    /// Used to calculate the period of the pwm.
+    clock_freq: Hertz,
+    tc: $TC,
+    resolution: TcPwmResolution,
+    /// The duty cycles, kept while a channel is disabled
+    duty: [u16; 2],
+    /// Bit n is set when channel n is enabled
+    enabled: u8,
+    #[allow(dead_code)]
+    pinout: (Option<$wo0>, Option<$wo1>),
+}
+
+impl $TYPE {
+    /// Starts the TC with both channels enabled at a duty cycle of 0.
+    /// A period out of range is clamped; use `set_period` to find out
+    /// about it.
+    pub fn new<F: Into<Period>>(
+        clock: &clock::$clock,
+        freq: F,
+        resolution: TcPwmResolution,
+        tc: $TC,
+        wo0: Option<$wo0>,
+        wo1: Option<$wo1>,
+        pm: &mut PM,
+    ) -> Self {
+        pm.$apmask.modify(|_, w| w.$apbits().set_bit());
+        {
+            let count = tc.count16();
+            count.ctrla.write(|w| w.swrst().set_bit());
+            while count.ctrla.read().bits() & 1 != 0 {}
+        }
+
+        let mut pwm = Self {
+            clock_freq: clock.freq(),
+            tc,
+            resolution,
+            duty: [0; 2],
+            enabled: 0b11,
+            pinout: (wo0, wo1),
+        };
+        if let Err(error) = pwm.set_period(freq) {
+            // clamp to the fastest or slowest period
+            let fastest = pwm.clock_freq.0 / match resolution {
+                TcPwmResolution::Bits8 => 0x100,
+                TcPwmResolution::Bits16 => 0x1_0000,
+            };
+            let clamped = match error {
+                TimerParamsError::TooFast => fastest,
+                _ => (fastest / 1024).max(1),
+            };
+            pwm.set_period(Hertz(clamped)).ok();
+        }
+        pwm
+    }
+
+    /// Changes the period of the pwm, given either as a frequency or as a
+    /// span of time, returning the period actually achieved. The current
+    /// period is kept if the requested one is out of range. The duty
+    /// cycles are not scaled along.
+    pub fn set_period<P>(&mut self, period: P) -> Result<Period, TimerParamsError>
+    where
+        P: Into<Period>
+    {
+        let period = period.into();
+        let (divider, per) = match self.resolution {
+            TcPwmResolution::Bits8 => {
+                let params = TimerParams::solve(period, self.clock_freq.0, 0x100, false)?;
+                (params.divider, params.top())
+            }
+            TcPwmResolution::Bits16 => (npwm16_divider(period, self.clock_freq.0)?, 0xffff),
+        };
+
+        let count = self.tc.count16();
+        count.ctrla.modify(|_, w| w.enable().clear_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+        count.ctrla.modify(|_, w| {
+            match self.resolution {
+                TcPwmResolution::Bits8 => w.mode().count8(),
+                TcPwmResolution::Bits16 => w.mode().count16(),
+            };
+            w.wavegen().npwm();
+            match divider {
+                1 => w.prescaler().div1(),
+                2 => w.prescaler().div2(),
+                4 => w.prescaler().div4(),
+                8 => w.prescaler().div8(),
+                16 => w.prescaler().div16(),
+                64 => w.prescaler().div64(),
+                256 => w.prescaler().div256(),
+                1024 => w.prescaler().div1024(),
+                _ => unreachable!(),
+            }
+        });
+        if let TcPwmResolution::Bits8 = self.resolution {
+            let count = self.tc.count8();
+            count.per.write(|w| unsafe { w.per().bits(per as u8) });
+            while count.status.read().syncbusy().bit_is_set() {}
+        }
+        for channel in 0..2 {
+            self.write_duty(channel);
+        }
+        let count = self.tc.count16();
+        count.ctrla.modify(|_, w| w.enable().set_bit());
+        while count.status.read().syncbusy().bit_is_set() {}
+        let ticks = divider as u64 * (per as u64 + 1);
+        Ok(Period::from_ticks(ticks, self.clock_freq))
+    }
+
+    pub fn get_period(&self) -> Hertz {
+        let count = self.tc.count16();
+        let divisor = prescaler_divider(count.ctrla.read().prescaler().bits());
+        Hertz(self.clock_freq.0 / divisor / (self.get_max_duty() as u32 + 1))
+    }
+
+    /// The duty cycle that keeps a channel on for the whole period
+    pub fn get_max_duty(&self) -> u16 {
+        match self.resolution {
+            TcPwmResolution::Bits8 => self.tc.count8().per.read().per().bits() as u16,
+            TcPwmResolution::Bits16 => 0xffff,
+        }
+    }
+
+    /// Writes the duty cycle of a channel, or 0 if it's disabled
+    fn write_duty(&mut self, channel: usize) {
+        let duty = if self.enabled & (1 << channel) != 0 {
+            self.duty[channel]
+        } else {
+            0
+        };
+        match self.resolution {
+            TcPwmResolution::Bits8 => {
+                // The 8 bit counter only counts up to PER
+                let duty = duty.min(self.get_max_duty()) as u8;
+                let count = self.tc.count8();
+                count.cc[channel].write(|w| unsafe { w.cc().bits(duty) });
+                while count.status.read().syncbusy().bit_is_set() {}
+            }
+            TcPwmResolution::Bits16 => {
+                let count = self.tc.count16();
+                count.cc[channel].write(|w| unsafe { w.cc().bits(duty) });
+                while count.status.read().syncbusy().bit_is_set() {}
+            }
+        }
+    }
+
+    pub fn set_duty(&mut self, channel: Channel, duty: u16) {
+        self.duty[channel as usize] = duty;
+        self.write_duty(channel as usize);
+    }
+
+    pub fn get_duty(&self, channel: Channel) -> u16 {
+        self.duty[channel as usize]
+    }
+
+    /// Disables a channel by holding its output low
+    pub fn disable(&mut self, channel: Channel) {
+        self.enabled &= !(1 << channel as usize);
+        self.write_duty(channel as usize);
+    }
+
+    /// Enables a channel again at its last duty cycle
+    pub fn enable(&mut self, channel: Channel) {
+        self.enabled |= 1 << channel as usize;
+        self.write_duty(channel as usize);
+    }
+}
+
+#[cfg(feature = "unproven")]
+impl crate::hal::Pwm for $TYPE {
+    type Channel = Channel;
+    type Time = Hertz;
+    type Duty = u16;
+
+    fn disable(&mut self, channel: Channel) {
+        $TYPE::disable(self, channel)
+    }
+
+    fn enable(&mut self, channel: Channel) {
+        $TYPE::enable(self, channel)
+    }
+
+    fn get_period(&self) -> Hertz {
+        $TYPE::get_period(self)
+    }
+
+    fn get_duty(&self, channel: Channel) -> u16 {
+        $TYPE::get_duty(self, channel)
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        $TYPE::get_max_duty(self)
+    }
+
+    fn set_duty(&mut self, channel: Channel, duty: u16) {
+        $TYPE::set_duty(self, channel, duty)
+    }
+
+    /// A period out of range is ignored
+    fn set_period<P>(&mut self, period: P)
+    where
+        P: Into<Hertz>
+    {
+        let period: Hertz = period.into();
+        $TYPE::set_period(self, period).ok();
+    }
+}
+
+)+}}
+
+pwm_dual! {
+    TcPwm3: (TC3, TC3Wo0Pinout, TC3Pinout, Tcc2Tc3Clock, apbcmask, tc3_),
+    TcPwm4: (TC4, TC4Wo0Pinout, TC4Pinout, Tc4Tc5Clock, apbcmask, tc4_),
+    TcPwm5: (TC5, TC5Wo0Pinout, TC5Pinout, Tc4Tc5Clock, apbcmask, tc5_),
+}
+
+#[cfg(feature = "samd21j18a")]
+pwm_dual! {
+    TcPwm6: (TC6, TC6Wo0Pinout, TC6Pinout, Tc6Tc7Clock, apbcmask, tc6_),
+    TcPwm7: (TC7, TC7Wo0Pinout, TC7Pinout, Tc6Tc7Clock, apbcmask, tc7_),
+}
+
+/// The compare/capture channels of a TCC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TccChannel {
+    C0,
+    C1,
+    C2,
+    C3,
+}
+
+/// How the counter of a TCC generates the waveform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TccWaveform {
+    /// The counter counts up from zero to the period and wraps around;
+    /// outputs switch on at zero and off at the compare value
+    SingleSlope,
+    /// The counter counts up to the period and back down again, and the
+    /// outputs switch on either side of the compare value. The pulses are
+    /// centred in the period, which is what motor drives usually want.
+    DualSlope,
+}
+
+/// Selects which channel drives each of the outputs of a TCC.
+/// The outputs with dead time insertion are WO[n] and WO[n + 4], and with
+/// the default matrix both follow channel n.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMatrix {
+    /// WO[n] follows channel n, wrapping around the number of channels
+    Default,
+    /// WO[n] follows channel 0 or 1, alternating
+    Alternating,
+    /// All outputs follow channel 0
+    Channel0,
+    /// WO[0] follows channel 0 and the others follow channel 1
+    Channel0And1,
+}
+
+/// The recoverable fault inputs of a TCC. They are fed through the event
+/// system into the match/capture event inputs; routing them is up to the
+/// application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fault A, on match/capture event input 0
+    A,
+    /// Fault B, on match/capture event input 1
+    B,
+}
+
+/// The configuration of a recoverable fault. While the fault is active
+/// the outputs are held inactive.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultConfig {
+    invert: bool,
+    keep: bool,
+    restart: bool,
+    blanking: u8,
+    filter: u8,
+}
+
+impl FaultConfig {
+    /// A fault that is active while the input is high, and releases the
+    /// outputs as soon as the input goes low
+    pub fn new() -> Self {
+        Self {
+            invert: false,
+            keep: false,
+            restart: false,
+            blanking: 0,
+            filter: 0,
+        }
+    }
+
+    /// Makes the fault active while the input is low
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /// Keeps the outputs inactive until the end of the cycle, even if the
+    /// input goes away earlier. This gives cycle-by-cycle current limiting.
+    pub fn keep(mut self, keep: bool) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Restarts the cycle when the fault goes away
+    pub fn restart(mut self, restart: bool) -> Self {
+        self.restart = restart;
+        self
+    }
+
+    /// Ignores the input for `ticks` cycles of the counter's clock after
+    /// each edge of the outputs, masking switching transients
+    pub fn blanking(mut self, ticks: u8) -> Self {
+        self.blanking = ticks;
+        self
+    }
+
+    /// Only acts on the input once it has been stable for `samples`
+    /// cycles of the counter's clock; at most 15
+    pub fn filter(mut self, samples: u8) -> Self {
+        self.filter = samples.min(15);
+        self
+    }
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Computes the divider and the PER value of a TCC
+fn tcc_params(
+    period: Period,
+    src_freq: u32,
+    waveform: TccWaveform,
+    max_per: u32,
+) -> Result<(u16, u32), TimerParamsError> {
+    match waveform {
+        // counting from 0 up to PER takes PER + 1 ticks
+        TccWaveform::SingleSlope => TimerParams::solve(period, src_freq, max_per + 1, false)
+            .map(|params| (params.divider, params.top())),
+        // counting up to PER and back down again takes 2 * PER ticks
+        TccWaveform::DualSlope => {
+            TimerParams::solve(period.half(), src_freq, max_per, false)
+                .map(|params| (params.divider, params.cycles))
+        }
+    }
+}
+
+/// The number of clock ticks in the period produced by a divider and PER
+/// value
+fn tcc_ticks(divider: u32, per: u32, waveform: TccWaveform) -> u64 {
+    match waveform {
+        TccWaveform::SingleSlope => divider as u64 * (per as u64 + 1),
+        TccWaveform::DualSlope => divider as u64 * 2 * per.max(1) as u64,
+    }
+}
+
+/// The frequency produced by a divider and PER value
+fn tcc_freq(src_freq: u32, divider: u32, per: u32, waveform: TccWaveform) -> Hertz {
+    let ticks = tcc_ticks(divider, per, waveform);
+    Hertz(((src_freq as u64 + ticks / 2) / ticks) as u32)
+}
+
+macro_rules! pwm_tcc {
+    ($($TYPE:ident: ($TCC:ident, $clock:ident, $apmask:ident, $apbits:ident, $channels:expr, $max_per:expr),)+) => {
+        $(
+
+/// A TCC generating PWM on all of its channels.
+/// The pins have to be switched to the peripheral function of the TCC
+/// by the application.
+pub struct $TYPE {
+    /// The frequency of the attached clock, not the period of the pwm.
+    /// Used to calculate the period of the pwm.
+    clock_freq: Hertz,
+    tcc: $TCC,
+    waveform: TccWaveform,
+    /// The duty cycles, kept while a channel is disabled
+    duty: [u32; $channels],
+    /// Bit n is set when channel n is enabled
+    enabled: u8,
+}
+
+impl $TYPE {
+    /// Starts the TCC with all channels enabled at a duty cycle of 0.
+    /// A period out of range is clamped; use `set_period` to find out
+    /// about it.
+    pub fn new<F: Into<Period>>(
+        clock: &clock::$clock,
+        freq: F,
+        tcc: $TCC,
+        waveform: TccWaveform,
+        pm: &mut PM,
+    ) -> Self {
+        let (divider, per) = tcc_params(freq.into(), clock.freq().0, waveform, $max_per)
+            .unwrap_or_else(|error| match error {
+                TimerParamsError::TooFast => (1, 1),
+                _ => (1024, $max_per),
+            });
+
+        pm.$apmask.modify(|_, w| w.$apbits().set_bit());
+        tcc.ctrla.write(|w| w.swrst().set_bit());
+        while tcc.syncbusy.read().swrst().bit_is_set() {}
+
+        tcc.wave.write(|w| match waveform {
+            TccWaveform::SingleSlope => w.wavegen().npwm(),
+            TccWaveform::DualSlope => w.wavegen().dsbottom(),
+        });
+        tcc.per().write(|w| unsafe { w.per().bits(per) });
+        for cc in tcc.cc().iter().take($channels) {
+            cc.write(|w| unsafe { w.cc().bits(0) });
+        }
+        while tcc.syncbusy.read().bits() != 0 {}
+
+        let mut pwm = Self {
+            clock_freq: clock.freq(),
+            tcc,
+            waveform,
+            duty: [0; $channels],
+            enabled: (1 << $channels) - 1,
+        };
+        pwm.set_divider(divider);
+        pwm
+    }
+
+    fn set_divider(&mut self, divider: u16) {
+        self.tcc.ctrla.modify(|_, w| w.enable().clear_bit());
+        while self.tcc.syncbusy.read().enable().bit_is_set() {}
+        self.tcc.ctrla.modify(|_, w| {
+            match divider {
+                1 => w.prescaler().div1(),
+                2 => w.prescaler().div2(),
+                4 => w.prescaler().div4(),
+                8 => w.prescaler().div8(),
+                16 => w.prescaler().div16(),
+                64 => w.prescaler().div64(),
+                256 => w.prescaler().div256(),
+                1024 => w.prescaler().div1024(),
+                _ => unreachable!(),
+            };
+            w.enable().set_bit()
+        });
+        while self.tcc.syncbusy.read().enable().bit_is_set() {}
+    }
+
+    /// Modifies a register that can only be written while the TCC is
+    /// disabled
+    fn while_disabled<F: FnOnce(&$TCC)>(&mut self, f: F) {
+        self.tcc.ctrla.modify(|_, w| w.enable().clear_bit());
+        while self.tcc.syncbusy.read().enable().bit_is_set() {}
+        f(&self.tcc);
+        self.tcc.ctrla.modify(|_, w| w.enable().set_bit());
+        while self.tcc.syncbusy.read().enable().bit_is_set() {}
+    }
+
+    /// Changes the period of the pwm, given either as a frequency or as a
+    /// span of time, returning the period actually achieved. The current
+    /// period is kept if the requested one is out of range. The duty
+    /// cycles are not scaled along.
+    pub fn set_period<P>(&mut self, period: P) -> Result<Period, TimerParamsError>
+    where
+        P: Into<Period>
+    {
+        let (divider, per) = tcc_params(period.into(), self.clock_freq.0, self.waveform, $max_per)?;
+        self.tcc.per().write(|w| unsafe { w.per().bits(per) });
+        while self.tcc.syncbusy.read().per().bit_is_set() {}
+        self.set_divider(divider);
+        let ticks = tcc_ticks(divider as u32, per, self.waveform);
+        Ok(Period::from_ticks(ticks, self.clock_freq))
+    }
+
+    pub fn get_period(&self) -> Hertz {
+        let divider = prescaler_divider(self.tcc.ctrla.read().prescaler().bits());
+        let per = self.tcc.per().read().per().bits();
+        tcc_freq(self.clock_freq.0, divider, per, self.waveform)
+    }
+
+    /// The duty cycle that keeps a channel on for the whole period
+    pub fn get_max_duty(&self) -> u32 {
+        self.tcc.per().read().per().bits()
+    }
+
+    /// Sets the duty cycle of a channel. The change takes effect at the end
+    /// of the current period, so the outputs don't glitch.
+    pub fn set_duty(&mut self, channel: TccChannel, duty: u32) {
+        let index = channel as usize;
+        if index >= $channels {
+            return;
+        }
+        self.duty[index] = duty;
+        if self.enabled & (1 << index) != 0 {
+            self.tcc.ccb()[index].write(|w| unsafe { w.ccb().bits(duty) });
+        }
+    }
+
+    pub fn get_duty(&self, channel: TccChannel) -> u32 {
+        self.duty.get(channel as usize).cloned().unwrap_or(0)
+    }
+
+    /// Disables a channel by holding its compare value at zero, which keeps
+    /// its outputs inactive. With dead time insertion the complementary
+    /// output is then held active; where both outputs of a pair need to be
+    /// switched off, use `set_pattern` on the instances that have it.
+    pub fn disable(&mut self, channel: TccChannel) {
+        let index = channel as usize;
+        if index >= $channels {
+            return;
+        }
+        self.enabled &= !(1 << index);
+        self.tcc.ccb()[index].write(|w| unsafe { w.ccb().bits(0) });
+    }
+
+    /// Enables a channel again at its last duty cycle
+    pub fn enable(&mut self, channel: TccChannel) {
+        let index = channel as usize;
+        if index >= $channels {
+            return;
+        }
+        self.enabled |= 1 << index;
+        let duty = self.duty[index];
+        self.tcc.ccb()[index].write(|w| unsafe { w.ccb().bits(duty) });
+    }
+
+    /// Enables a recoverable fault input
+    pub fn enable_fault(&mut self, fault: Fault, config: FaultConfig) {
+        self.while_disabled(|tcc| {
+            tcc.evctrl.modify(|_, w| match fault {
+                Fault::A => w.mcei0().set_bit(),
+                Fault::B => w.mcei1().set_bit(),
+            });
+            // the two fault inputs have identical, but distinct, registers
+            match fault {
+                Fault::A => {
+                    tcc.fctrla.write(|w| {
+                        if config.invert {
+                            w.src().invert();
+                        } else {
+                            w.src().enable();
+                        }
+                        if config.blanking != 0 {
+                            w.blank().both();
+                        }
+                        w.keep().bit(config.keep);
+                        w.restart().bit(config.restart);
+                        w.halt().hw();
+                        unsafe {
+                            w.blankval().bits(config.blanking);
+                            w.filterval().bits(config.filter)
+                        }
+                    })
+                }
+                Fault::B => {
+                    tcc.fctrlb.write(|w| {
+                        if config.invert {
+                            w.src().invert();
+                        } else {
+                            w.src().enable();
+                        }
+                        if config.blanking != 0 {
+                            w.blank().both();
+                        }
+                        w.keep().bit(config.keep);
+                        w.restart().bit(config.restart);
+                        w.halt().hw();
+                        unsafe {
+                            w.blankval().bits(config.blanking);
+                            w.filterval().bits(config.filter)
+                        }
+                    })
+                }
+            }
+        });
+    }
+
+    /// Disables a recoverable fault input
+    pub fn disable_fault(&mut self, fault: Fault) {
+        self.while_disabled(|tcc| match fault {
+            Fault::A => {
+                tcc.evctrl.modify(|_, w| w.mcei0().clear_bit());
+                tcc.fctrla.reset();
+            }
+            Fault::B => {
+                tcc.evctrl.modify(|_, w| w.mcei1().clear_bit());
+                tcc.fctrlb.reset();
+            }
+        });
+    }
+
+    /// Enables the non-recoverable fault on event input 0. When it
+    /// triggers, the outputs set in `outputs` (bit n for WO[n]) are driven
+    /// to the levels in `values` until the fault is cleared by
+    /// `clear_nonrecoverable_fault`.
+    pub fn enable_nonrecoverable_fault(&mut self, invert: bool, outputs: u8, values: u8) {
+        self.while_disabled(|tcc| {
+            tcc.drvctrl.modify(|r, w| unsafe {
+                w.bits((r.bits() & !0xffff) | ((values as u32) << 8) | outputs as u32)
+            });
+            tcc.evctrl.modify(|_, w| {
+                w.evact0().fault();
+                w.tcinv0().bit(invert);
+                w.tcei0().set_bit()
+            });
+        });
+    }
+
+    /// Disables the non-recoverable fault
+    pub fn disable_nonrecoverable_fault(&mut self) {
+        self.while_disabled(|tcc| {
+            tcc.evctrl.modify(|_, w| {
+                w.evact0().off();
+                w.tcei0().clear_bit()
+            });
+            tcc.drvctrl.modify(|r, w| unsafe { w.bits(r.bits() & !0xffff) });
+        });
+    }
+
+    /// Whether the non-recoverable fault has triggered
+    pub fn nonrecoverable_fault(&self) -> bool {
+        self.tcc.status.read().fault0().bit_is_set()
+    }
+
+    /// Releases the outputs after a non-recoverable fault. This only
+    /// succeeds once the fault input has gone away.
+    pub fn clear_nonrecoverable_fault(&mut self) {
+        // Writing a 1 clears the flag
+        self.tcc.status.write(|w| w.fault0().set_bit());
+    }
+}
+
+#[cfg(feature = "unproven")]
+impl crate::hal::Pwm for $TYPE {
+    type Channel = TccChannel;
+    type Time = Hertz;
+    type Duty = u32;
+
+    fn disable(&mut self, channel: TccChannel) {
+        $TYPE::disable(self, channel)
+    }
+
+    fn enable(&mut self, channel: TccChannel) {
+        $TYPE::enable(self, channel)
+    }
+
+    fn get_period(&self) -> Hertz {
+        $TYPE::get_period(self)
+    }
+
+    fn get_duty(&self, channel: TccChannel) -> u32 {
+        $TYPE::get_duty(self, channel)
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        $TYPE::get_max_duty(self)
+    }
+
+    fn set_duty(&mut self, channel: TccChannel, duty: u32) {
+        $TYPE::set_duty(self, channel, duty)
+    }
+
+    /// A period out of range is ignored
+    fn set_period<P>(&mut self, period: P)
+    where
+        P: Into<Hertz>
+    {
+        let period: Hertz = period.into();
+        $TYPE::set_period(self, period).ok();
+    }
+}
+
+)+}}
+
+/// The output matrix and dead time insertion are only present on some of
+/// the TCC instances
+macro_rules! pwm_tcc_dead_time {
+    ($($TYPE:ident,)+) => {
+        $(
+
+impl $TYPE {
+    /// Selects which channel drives each output
+    pub fn set_output_matrix(&mut self, matrix: OutputMatrix) {
+        self.while_disabled(|tcc| {
+            tcc.wexctrl.modify(|_, w| unsafe { w.otmx().bits(matrix as u8) });
+        });
+    }
+
+    /// Turns the outputs WO[n] and WO[n + 4] into a complementary pair with
+    /// dead time for each bit n set in `pairs`, and back into independent
+    /// outputs for the others. WO[n] is the low side and is switched on
+    /// `low_side` cycles after WO[n + 4] switches off, and the other way
+    /// round for `high_side`; both are counted in cycles of the counter's
+    /// clock, before the prescaler.
+    pub fn set_dead_time(&mut self, pairs: u8, low_side: u8, high_side: u8) {
+        self.while_disabled(|tcc| {
+            tcc.wexctrl.modify(|_, w| unsafe {
+                w.dtien0().bit(pairs & 1 != 0);
+                w.dtien1().bit(pairs & 2 != 0);
+                w.dtien2().bit(pairs & 4 != 0);
+                w.dtien3().bit(pairs & 8 != 0);
+                w.dtls().bits(low_side);
+                w.dths().bits(high_side)
+            });
+        });
+    }
+}
+
+)+}}
+
+/// The pattern generator is only present on some of the TCC instances
+macro_rules! pwm_tcc_pattern {
+    ($($TYPE:ident,)+) => {
+        $(
+
+impl $TYPE {
+    /// Overrides the outputs set in `outputs` (bit n for WO[n]) with the
+    /// levels in `values`, while the others keep following their channels.
+    /// The pattern takes effect at the end of the current period, which
+    /// makes it suitable for commutating a brushless motor.
+    pub fn set_pattern(&mut self, outputs: u8, values: u8) {
+        self.tcc.pattb.write(|w| unsafe { w.bits(((values as u16) << 8) | outputs as u16) });
+        while self.tcc.syncbusy.read().pattb().bit_is_set() {}
+    }
+}
+
+)+}}
+
+pwm_tcc! {
+    TccPwm0: (TCC0, Tcc0Tcc1Clock, apbcmask, tcc0_, 4, 0xff_ffff),
+    TccPwm1: (TCC1, Tcc0Tcc1Clock, apbcmask, tcc1_, 2, 0xff_ffff),
+    TccPwm2: (TCC2, Tcc2Tc3Clock, apbcmask, tcc2_, 2, 0xffff),
+}
+
+pwm_tcc_dead_time! {
+    TccPwm0,
+}
+
+pwm_tcc_pattern! {
+    TccPwm0,
+    TccPwm1,
 }
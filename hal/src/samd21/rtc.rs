@@ -0,0 +1,510 @@
+//! Working with the real time counter
+//!
+//! The RTC can run as a 32-bit counter with a compare (`Count32Mode`),
+//! as a 16-bit periodic counter (`Count16Mode`), or as a calendar clock
+//! with an alarm (`ClockMode`).
+//!
+//! The RTC keeps counting in standby as long as its clock does. For
+//! that, the clock generator driving the `RtcClock` has to be configured
+//! with `GclkConfig::run_standby`, and a 32kHz oscillator feeding it has
+//! to be kept running with
+//! `GenericClockController::run_32kosc_in_standby`. `OSCULP32K` always
+//! runs in standby, at the cost of accuracy.
+use core::marker::PhantomData;
+
+use crate::target_device::rtc::{MODE0, MODE1, MODE2};
+use crate::target_device::{PM, RTC};
+use crate::clock::{self, GenericClockController};
+use crate::delay::InterruptDrivenTimer;
+use crate::monotonic::{Duration, Instant, TimeBase};
+use crate::time::{Hertz, Period};
+use hal::timer::{CountDown, Periodic};
+use nb;
+use void::Void;
+
+pub use crate::datetime::{AlarmMatch, Datetime, DatetimeError, BASE_YEAR};
+
+/// The RTC as a 32-bit counter with a compare
+pub enum Count32Mode {}
+/// The RTC as a 16-bit counter with a period
+pub enum Count16Mode {}
+/// The RTC as a calendar clock with an alarm
+pub enum ClockMode {}
+
+/// The real time counter, in one of its modes.
+/// In the counter modes, `Rtc` implements both the `Periodic` and the
+/// `CountDown` embedded_hal timer traits.
+pub struct Rtc<Mode> {
+    rtc: RTC,
+    rtc_clock_freq: Hertz,
+    _mode: PhantomData<Mode>,
+}
+
+impl<Mode> Rtc<Mode> {
+    fn new(rtc: RTC, rtc_clock_freq: Hertz) -> Self {
+        Self {
+            rtc,
+            rtc_clock_freq,
+            _mode: PhantomData,
+        }
+    }
+
+    fn mode0(&self) -> &MODE0 {
+        self.rtc.mode0()
+    }
+
+    fn mode1(&self) -> &MODE1 {
+        self.rtc.mode1()
+    }
+
+    fn mode2(&self) -> &MODE2 {
+        self.rtc.mode2()
+    }
+
+    fn sync(&self) {
+        while self.mode0().status.read().syncbusy().bit_is_set() {}
+    }
+
+    fn disable(&mut self) {
+        self.mode0().ctrl.modify(|_, w| w.enable().clear_bit());
+        self.sync();
+    }
+
+    fn enable(&mut self) {
+        self.mode0().ctrl.modify(|_, w| w.enable().set_bit());
+        self.sync();
+    }
+
+    /// Resets the RTC, which stops it, and selects the new mode
+    fn reset<NewMode>(self) -> Rtc<NewMode> {
+        self.mode0().ctrl.write(|w| w.swrst().set_bit());
+        // the SVD erroneously marks swrst as write-only, so we
+        // need to manually read the bit here
+        while self.mode0().ctrl.read().bits() & 1 != 0 {}
+        Rtc::new(self.rtc, self.rtc_clock_freq)
+    }
+
+    /// Requests a synchronized read of the count or the calendar
+    fn read_request(&self) {
+        self.mode0().readreq.write(|w| w.rreq().set_bit());
+        self.sync();
+    }
+
+    /// The frequency of the attached clock, before the prescaler
+    pub fn rtc_clock_freq(&self) -> Hertz {
+        self.rtc_clock_freq
+    }
+
+    /// Resets the RTC and switches it to a 32-bit counter, counting at
+    /// the frequency of its clock
+    pub fn into_count32_mode(self) -> Rtc<Count32Mode> {
+        let mut rtc = self.reset::<Count32Mode>();
+        rtc.mode0().ctrl.write(|w| {
+            w.mode().count32();
+            w.prescaler().div1()
+        });
+        rtc.sync();
+        rtc.enable();
+        rtc
+    }
+
+    /// Resets the RTC and switches it to a 16-bit periodic counter.
+    /// The period is chosen by `CountDown::start`; until then the
+    /// counter wraps at 0xffff.
+    pub fn into_count16_mode(self) -> Rtc<Count16Mode> {
+        let mut rtc = self.reset::<Count16Mode>();
+        rtc.mode1().ctrl.write(|w| {
+            w.mode().count16();
+            w.prescaler().div1()
+        });
+        rtc.sync();
+        rtc.mode1().per.write(|w| unsafe { w.per().bits(0xffff) });
+        rtc.sync();
+        rtc.enable();
+        rtc
+    }
+
+    /// Resets the RTC and switches it to a calendar clock, starting at
+    /// midnight on the first of January of `BASE_YEAR`.
+    /// The calendar needs a 1Hz tick, so the frequency of the clock
+    /// has to be a power of two no higher than 1.024kHz, for example
+    /// a 32kHz source divided by 32. Otherwise the RTC is handed back
+    /// in its current mode.
+    pub fn into_clock_mode(self) -> Result<Rtc<ClockMode>, Self> {
+        let freq = self.rtc_clock_freq.0;
+        if !freq.is_power_of_two() || freq > 1024 {
+            return Err(self);
+        }
+        let mut rtc = self.reset::<ClockMode>();
+        rtc.mode2().ctrl.write(|w| unsafe {
+            w.mode().clock();
+            // CLK_RTC_CNT = GCLK_RTC / 2^prescaler
+            w.prescaler().bits(freq.trailing_zeros() as u8)
+        });
+        rtc.sync();
+        rtc.set_time(Datetime {
+            year: BASE_YEAR,
+            month: 1,
+            day: 1,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        })
+        .ok();
+        rtc.enable();
+        Ok(rtc)
+    }
+
+    /// Stops the RTC and releases the peripheral
+    pub fn free(mut self) -> RTC {
+        self.disable();
+        self.rtc
+    }
+}
+
+impl Rtc<Count32Mode> {
+    /// Configures the RTC as a 32-bit counter that starts at 0 and
+    /// counts at the frequency of its clock
+    pub fn count32_mode(rtc: RTC, clock: &clock::RtcClock, pm: &mut PM) -> Self {
+        pm.apbamask.modify(|_, w| w.rtc_().set_bit());
+        Rtc::<Count32Mode>::new(rtc, clock.freq()).into_count32_mode()
+    }
+
+    /// Returns the current count
+    pub fn count32(&self) -> u32 {
+        self.read_request();
+        self.mode0().count.read().bits()
+    }
+
+    /// Sets the current count
+    pub fn set_count32(&mut self, count: u32) {
+        self.sync();
+        self.mode0().count.write(|w| unsafe { w.count().bits(count) });
+        self.sync();
+    }
+
+    /// Sets the compare value, which raises the compare match flag when
+    /// the count reaches it
+    pub fn set_compare(&mut self, compare: u32) {
+        self.sync();
+        self.mode0().comp[0].write(|w| unsafe { w.comp().bits(compare) });
+        self.sync();
+    }
+
+    /// Returns true once the count has matched the compare value, and
+    /// clears the flag
+    pub fn compare_matched(&mut self) -> bool {
+        let intflag = &self.mode0().intflag;
+        if intflag.read().cmp0().bit_is_set() {
+            // Writing a 1 clears the flag
+            intflag.write(|w| w.cmp0().set_bit());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Enables the compare match interrupt
+    pub fn enable_interrupt(&mut self) {
+        self.mode0().intenset.write(|w| w.cmp0().set_bit());
+    }
+
+    /// Disables the compare match interrupt
+    pub fn disable_interrupt(&mut self) {
+        self.mode0().intenclr.write(|w| w.cmp0().set_bit());
+    }
+}
+
+impl Periodic for Rtc<Count32Mode> {}
+
+impl InterruptDrivenTimer for Rtc<Count32Mode> {
+    fn enable_interrupt(&mut self) {
+        Rtc::<Count32Mode>::enable_interrupt(self)
+    }
+
+    fn disable_interrupt(&mut self) {
+        Rtc::<Count32Mode>::disable_interrupt(self)
+    }
+}
+
+impl CountDown for Rtc<Count32Mode> {
+    type Time = Period;
+
+    /// Restarts the count at 0 and clears it on every compare match,
+    /// so that the count no longer runs over the full 32 bits
+    fn start<T>(&mut self, timeout: T)
+    where
+        T: Into<Period>,
+    {
+        let ticks = timeout.into().ticks(self.rtc_clock_freq).min(u32::max_value() as u64) as u32;
+        self.disable();
+        self.mode0().ctrl.modify(|_, w| w.matchclr().set_bit());
+        self.sync();
+        self.set_compare(ticks.max(1) - 1);
+        self.set_count32(0);
+        self.mode0().intflag.write(|w| w.cmp0().set_bit());
+        self.enable();
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        if self.compare_matched() {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl Rtc<Count16Mode> {
+    /// Configures the RTC as a 16-bit periodic counter that counts at
+    /// the frequency of its clock
+    pub fn count16_mode(rtc: RTC, clock: &clock::RtcClock, pm: &mut PM) -> Self {
+        pm.apbamask.modify(|_, w| w.rtc_().set_bit());
+        Rtc::<Count16Mode>::new(rtc, clock.freq()).into_count16_mode()
+    }
+
+    /// Returns the current count
+    pub fn count16(&self) -> u16 {
+        self.read_request();
+        self.mode1().count.read().count().bits()
+    }
+
+    /// Enables the interrupt at the end of every period
+    pub fn enable_interrupt(&mut self) {
+        self.mode1().intenset.write(|w| w.ovf().set_bit());
+    }
+
+    /// Disables the interrupt at the end of every period
+    pub fn disable_interrupt(&mut self) {
+        self.mode1().intenclr.write(|w| w.ovf().set_bit());
+    }
+}
+
+impl Periodic for Rtc<Count16Mode> {}
+
+impl InterruptDrivenTimer for Rtc<Count16Mode> {
+    fn enable_interrupt(&mut self) {
+        Rtc::<Count16Mode>::enable_interrupt(self)
+    }
+
+    fn disable_interrupt(&mut self) {
+        Rtc::<Count16Mode>::disable_interrupt(self)
+    }
+}
+
+impl CountDown for Rtc<Count16Mode> {
+    type Time = Period;
+
+    /// Timeouts longer than the counter can reach with its largest
+    /// prescaler are clamped to that
+    fn start<T>(&mut self, timeout: T)
+    where
+        T: Into<Period>,
+    {
+        let ticks = timeout.into().ticks(self.rtc_clock_freq).max(1);
+        // CLK_RTC_CNT = GCLK_RTC / 2^prescaler, for prescalers up to 10
+        let mut prescaler = 0u8;
+        while prescaler < 10 && (ticks >> prescaler) > 0x1_0000 {
+            prescaler += 1;
+        }
+        let period = ((ticks >> prescaler).max(1) - 1).min(0xffff) as u16;
+
+        self.disable();
+        self.mode1()
+            .ctrl
+            .modify(|_, w| unsafe { w.prescaler().bits(prescaler) });
+        self.sync();
+        self.mode1().per.write(|w| unsafe { w.per().bits(period) });
+        self.sync();
+        self.mode1().count.write(|w| unsafe { w.count().bits(0) });
+        self.sync();
+        self.mode1().intflag.write(|w| w.ovf().set_bit());
+        self.enable();
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        let intflag = &self.mode1().intflag;
+        if intflag.read().ovf().bit_is_set() {
+            // Writing a 1 clears the flag
+            intflag.write(|w| w.ovf().set_bit());
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl Rtc<ClockMode> {
+    /// Configures the RTC as a calendar clock, as described for
+    /// `into_clock_mode`.
+    /// The peripheral is handed back if the frequency of the clock
+    /// can't produce the 1Hz tick.
+    pub fn clock_mode(rtc: RTC, clock: &clock::RtcClock, pm: &mut PM) -> Result<Self, RTC> {
+        pm.apbamask.modify(|_, w| w.rtc_().set_bit());
+        Rtc::<Count32Mode>::new(rtc, clock.freq())
+            .into_clock_mode()
+            .map_err(|rtc| rtc.rtc)
+    }
+
+    /// Returns the current date and time
+    pub fn current_time(&self) -> Datetime {
+        self.read_request();
+        Datetime::from_bits(self.mode2().clock.read().bits())
+    }
+
+    /// Sets the current date and time
+    pub fn set_time(&mut self, time: Datetime) -> Result<(), DatetimeError> {
+        time.validate()?;
+        self.sync();
+        self.mode2().clock.write(|w| unsafe { w.bits(time.to_bits()) });
+        self.sync();
+        Ok(())
+    }
+
+    /// Sets the alarm to fire when the fields of the current time
+    /// selected by `matching` are equal to those of `time`
+    pub fn set_alarm(&mut self, time: Datetime, matching: AlarmMatch) -> Result<(), DatetimeError> {
+        time.validate()?;
+        let mode2 = self.mode2();
+        mode2.alarm0.write(|w| unsafe { w.bits(time.to_bits()) });
+        self.sync();
+        mode2.mask0.write(|w| match matching {
+            AlarmMatch::Off => w.sel().off(),
+            AlarmMatch::Seconds => w.sel().ss(),
+            AlarmMatch::Minutes => w.sel().mmss(),
+            AlarmMatch::Hours => w.sel().hhmmss(),
+            AlarmMatch::Day => w.sel().ddhhmmss(),
+            AlarmMatch::Month => w.sel().mmddhhmmss(),
+            AlarmMatch::Year => w.sel().yymmddhhmmss(),
+        });
+        self.sync();
+        Ok(())
+    }
+
+    /// Returns true once the alarm has fired, and clears the flag
+    pub fn alarm_fired(&mut self) -> bool {
+        let intflag = &self.mode2().intflag;
+        if intflag.read().alarm0().bit_is_set() {
+            // Writing a 1 clears the flag
+            intflag.write(|w| w.alarm0().set_bit());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Enables the alarm interrupt, which also wakes the CPU from
+    /// standby
+    pub fn enable_interrupt(&mut self) {
+        self.mode2().intenset.write(|w| w.alarm0().set_bit());
+    }
+
+    /// Disables the alarm interrupt
+    pub fn disable_interrupt(&mut self) {
+        self.mode2().intenclr.write(|w| w.alarm0().set_bit());
+    }
+}
+
+static RTC_TIME_BASE: TimeBase = TimeBase::new();
+
+/// A monotonic time base on the RTC, counting at the frequency of its
+/// clock. See the `monotonic` module; the RTC interrupt handler has to
+/// call `on_interrupt`.
+/// The RTC keeps the time base running in standby, but its coarse
+/// ticks make it a poor fit for short deadlines.
+pub enum RtcMonotonic {}
+
+impl RtcMonotonic {
+    /// Starts the RTC as the time base, counting up from zero.
+    /// The overflow interrupt is enabled, but the interrupt controller
+    /// isn't configured.
+    /// Returns the tick rate of the time base.
+    pub fn start<Mode>(rtc: Rtc<Mode>, clocks: &mut GenericClockController) -> Hertz {
+        let rtc = rtc.into_count32_mode();
+        // Keep the count synchronized, so that reading it doesn't have to
+        // wait for the slow RTC clock
+        rtc.mode0().readreq.write(|w| {
+            w.rcont().set_bit();
+            w.rreq().set_bit()
+        });
+        rtc.sync();
+        rtc.mode0().intenset.write(|w| w.ovf().set_bit());
+        RTC_TIME_BASE.set_freq(rtc.rtc_clock_freq, clocks.gclk_freq(clock::ClockGenId::GCLK0));
+        RTC_TIME_BASE.reset(|| ());
+        rtc.rtc_clock_freq
+    }
+
+    /// Returns the current time
+    pub fn now() -> Instant {
+        RTC_TIME_BASE.now(Self::read, || {
+            let intflag = &Self::mode0().intflag;
+            let overflowed = intflag.read().ovf().bit_is_set();
+            if overflowed {
+                // Writing a 1 clears the flag
+                intflag.write(|w| w.ovf().set_bit());
+            }
+            overflowed
+        })
+    }
+
+    /// Counts an overflow of the RTC; call this from the RTC interrupt
+    /// handler
+    pub fn on_interrupt() {
+        Self::now();
+    }
+
+    /// The tick rate of the time base
+    pub fn freq() -> Hertz {
+        RTC_TIME_BASE.freq()
+    }
+
+    /// Converts a period in milliseconds to ticks of the time base
+    pub fn millis(ms: u32) -> Duration {
+        RTC_TIME_BASE.millis(ms)
+    }
+
+    /// Converts a period in microseconds to ticks of the time base
+    pub fn micros(us: u32) -> Duration {
+        RTC_TIME_BASE.micros(us)
+    }
+
+    fn mode0() -> &'static MODE0 {
+        // The time base owns the RTC from `start` on
+        unsafe { &*RTC::ptr() }.mode0()
+    }
+
+    fn read() -> u32 {
+        Self::mode0().count.read().bits()
+    }
+}
+
+#[cfg(feature = "rtfm")]
+impl rtfm::Monotonic for RtcMonotonic {
+    type Instant = Instant;
+
+    fn ratio() -> rtfm::Fraction {
+        let (numerator, denominator) = RTC_TIME_BASE.ratio();
+        rtfm::Fraction {
+            numerator,
+            denominator,
+        }
+    }
+
+    fn now() -> Instant {
+        RtcMonotonic::now()
+    }
+
+    unsafe fn reset() {
+        RTC_TIME_BASE.reset(|| {
+            let mode0 = Self::mode0();
+            while mode0.status.read().syncbusy().bit_is_set() {}
+            mode0.count.write(|w| w.count().bits(0));
+            while mode0.status.read().syncbusy().bit_is_set() {}
+            mode0.intflag.write(|w| w.ovf().set_bit());
+        });
+    }
+
+    fn zero() -> Instant {
+        Instant::from_ticks(0)
+    }
+}
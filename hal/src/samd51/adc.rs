@@ -4,9 +4,10 @@ use crate::target_device::gclk::genctrl::SRC_A::DFLL;
 use crate::target_device::gclk::pchctrl::GEN_A::GCLK11;
 use crate::clock::GenericClockController;
 use crate::gpio::{
-    Pa2, Pa3, Pa4, Pa5, Pa6, Pa7, Pa8, Pa9, Pa10, Pa11, Pb0, Pb1, Pb2, Pb3, Pb4,
-    Pb5, Pb6, Pb7, Pb8, Pb9, PfB
+    Analog, Pa2, Pa3, Pa4, Pa5, Pa6, Pa7, Pa8, Pa9, Pa10, Pa11, Pb2, Pb3, Pb8, Pb9,
 };
+#[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
+use crate::gpio::{Pb0, Pb1, Pb4, Pb5, Pb6, Pb7};
 
 pub struct Adc<ADC> {
     adc: ADC
@@ -83,11 +84,14 @@ where
     }
 }
 
+/// The pins of each chip variant and the ADC inputs they are wired to.
+/// A pin has to be in `Analog` mode to be read.
 macro_rules! adc_pins {
-    ($($pin:ident: ($ADC:ident, $chan:expr),)+) => {
+    ($($(#[$attr:meta])* $pin:ident: ($ADC:ident, $chan:expr),)+) => {
         $(
 
-impl Channel<$ADC> for $pin<PfB> {
+$(#[$attr])*
+impl Channel<$ADC> for $pin<Analog> {
    type ID = u8;
    fn channel() -> u8 { $chan }
 }
@@ -113,17 +117,23 @@ adc_pins! {
     Pa9:  (ADC0, 9),
     Pa10: (ADC0, 10),
     Pa11: (ADC0, 11),
+    #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
     Pb0:  (ADC0, 12),
+    #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
     Pb1:  (ADC0, 13),
     Pb2:  (ADC0, 14),
     Pb3:  (ADC0, 15),
-    
+
     Pb8:  (ADC1, 0),
     Pb9:  (ADC1, 1),
     Pa8:  (ADC1, 2),
     Pa9:  (ADC1, 3),
+    #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
     Pb4:  (ADC1, 6),
+    #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
     Pb5:  (ADC1, 7),
+    #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
     Pb6:  (ADC1, 8),
+    #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
     Pb7:  (ADC1, 9),
 }
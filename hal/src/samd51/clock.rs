@@ -3,14 +3,49 @@
 //! before you can set up most of the peripherals on the atsamd51 device.
 //! The other types in this module are used to enforce at compile time
 //! that the peripherals have been correctly configured.
+use core::cell::Cell;
+use core::marker::PhantomData;
+use cortex_m::interrupt::{self, Mutex};
 use crate::target_device::gclk::pchctrl::GEN_A::*;
+use crate::freqm::FrequencyMeter;
+use crate::gpio::{self, IntoFunction, Port};
 use crate::target_device::{self, GCLK, NVMCTRL, OSCCTRL, MCLK, OSC32KCTRL};
+use crate::target_device::oscctrl::{DPLLCTRLA, DPLLCTRLB, DPLLRATIO, DPLLSTATUS, DPLLSYNCBUSY};
 use crate::target_device::gclk::genctrl::SRC_A::*;
 use crate::time::{Hertz, MegaHertz};
 
 pub type ClockGenId = target_device::gclk::pchctrl::GEN_A;
 pub type ClockSource = target_device::gclk::genctrl::SRC_A;
+
+/// The number of clock generators
+const NUM_GCLKS: usize = 12;
+/// The number of peripheral clock channels
+const NUM_CHANNELS: usize = 48;
+
+/// The clock generators that a `GClock` is currently alive for
+static LIVE_GCLKS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+/// The peripheral channels that a clock token is currently alive for
+static LIVE_CHANNELS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Marks `idx` as alive in `live`. Returns false if it already was.
+fn claim(live: &Mutex<Cell<u64>>, idx: usize) -> bool {
+    interrupt::free(|cs| {
+        let live = live.borrow(cs);
+        let claimed = live.get() & (1 << idx) == 0;
+        live.set(live.get() | (1 << idx));
+        claimed
+    })
+}
+
+fn release(live: &Mutex<Cell<u64>>, idx: usize) {
+    interrupt::free(|cs| {
+        let live = live.borrow(cs);
+        live.set(live.get() & !(1 << idx));
+    })
+}
+
 #[allow(bad_style)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ClockId {
     DFLL48 = 0,
     FDPLL0,
@@ -73,12 +108,38 @@ use self::ClockId::*;
 /// Its primary purpose is to be passed in to methods
 /// such as `GenericClockController::tcc2_tc3` to configure
 /// the clock for a peripheral.
-//#[derive(Clone, Copy)]
+/// There is at most one `GClock` for each clock generator at a time.
+/// The peripheral clock tokens borrow it, so it can't be handed to
+/// `GenericClockController::reconfigure_gclk` or `disable_gclk` while
+/// any of them is alive. Once it is dropped, it can be obtained again
+/// from the controller.
 pub struct GClock {
     gclk: ClockGenId,
     freq: Hertz,
 }
 
+impl GClock {
+    /// Claims the handle of a clock generator, unless one is alive
+    fn claim(gclk: ClockGenId, freq: Hertz) -> Option<Self> {
+        if claim(&LIVE_GCLKS, u8::from(gclk) as usize) {
+            Some(Self { gclk, freq })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the frequency of the clock generator
+    pub fn freq(&self) -> Hertz {
+        self.freq
+    }
+}
+
+impl Drop for GClock {
+    fn drop(&mut self) {
+        release(&LIVE_GCLKS, u8::from(self.gclk) as usize);
+    }
+}
+
 impl Into<Hertz> for GClock {
     fn into(self) -> Hertz {
         self.freq
@@ -108,14 +169,27 @@ impl State {
         src: ClockSource,
         improve_duty_cycle: bool,
     ) {
+        let config = GclkConfig::new(src)
+            .divider(divider)
+            .improve_duty_cycle(improve_duty_cycle)
+            .output_enable(true);
+        self.configure_gclk(gclk, &config);
+    }
+
+    fn configure_gclk(&mut self, gclk: ClockGenId, config: &GclkConfig) {
+        let (divsel, div) = match config.divider {
+            GclkDivider::Linear(divider) => (false, divider),
+            GclkDivider::Exponential(exponent) => (true, exponent as u16),
+        };
         self.gclk.genctrl[u8::from(gclk) as usize].write(|w| unsafe {
-            w.src().variant(src.into());
-            w.div().bits(divider);
-            // divide directly by divider, rather than 2^(n+1)
-            w.divsel().clear_bit();
-            w.idc().bit(improve_duty_cycle);
-            w.genen().set_bit();
-            w.oe().set_bit()
+            w.src().variant(config.source.into());
+            w.div().bits(div);
+            w.divsel().bit(divsel);
+            w.idc().bit(config.improve_duty_cycle);
+            w.oe().bit(config.output_enable);
+            w.oov().bit(config.output_off_value);
+            w.runstdby().bit(config.run_standby);
+            w.genen().set_bit()
         });
 
         self.wait_for_sync();
@@ -128,6 +202,16 @@ impl State {
         });
         self.wait_for_sync();
     }
+
+    fn disable_clock(&mut self, clock: ClockId) {
+        self.gclk.pchctrl[clock.bits()].modify(|_, w| w.chen().clear_bit());
+        self.wait_for_sync();
+    }
+
+    fn disable_gclk(&mut self, gclk: ClockGenId) {
+        self.gclk.genctrl[u8::from(gclk) as usize].modify(|_, w| w.genen().clear_bit());
+        self.wait_for_sync();
+    }
 }
 
 /// `GenericClockController` encapsulates the GCLK hardware.
@@ -138,8 +222,102 @@ impl State {
 /// signal by 2.5x.
 pub struct GenericClockController {
     state: State,
-    gclks: [Hertz; 12],
-    used_clocks: u64,
+    tree: ClockTree,
+}
+
+/// The generators, peripheral channels and sources that make up the
+/// clock tree. The controller keeps track of the tree it has configured,
+/// and `ClockPlan` solves plans against a copy of it.
+#[derive(Clone, Copy)]
+struct ClockTree {
+    xosc32k_enabled: bool,
+    gclks: [Hertz; NUM_GCLKS],
+    gclk_configs: [Option<GclkConfig>; NUM_GCLKS],
+    channels: [Option<ClockGenId>; NUM_CHANNELS],
+    /// The peripheral channels that have been routed for a clock token
+    token_channels: u64,
+    /// The number of GCLK_IO pins each clock generator is output on
+    gclk_outs: [u8; NUM_GCLKS],
+    xosc_freqs: [Hertz; 2],
+    gclk_in_freqs: [Hertz; NUM_GCLKS],
+    dpll_freqs: [Hertz; 2],
+}
+
+impl ClockTree {
+    /// The clock tree that `GenericClockController` sets up: gclk0 at
+    /// 120MHz from DPLL0, which is referenced from gclk5 at 2MHz from the
+    /// DFLL48M, and gclk1 at 32KHz
+    fn new(xosc32k_enabled: bool) -> Self {
+        let src_32k = if xosc32k_enabled { XOSC32K } else { OSCULP32K };
+        let mut tree = Self {
+            xosc32k_enabled,
+            gclks: [Hertz(0); NUM_GCLKS],
+            gclk_configs: [None; NUM_GCLKS],
+            channels: [None; NUM_CHANNELS],
+            token_channels: 0,
+            gclk_outs: [0; NUM_GCLKS],
+            xosc_freqs: [Hertz(0); 2],
+            gclk_in_freqs: [Hertz(0); NUM_GCLKS],
+            dpll_freqs: [OSC120M_FREQ, Hertz(0)],
+        };
+        tree.gclks[0] = OSC120M_FREQ;
+        tree.gclks[1] = tree.source_freq(src_32k, 1);
+        tree.gclks[5] = MegaHertz(2).into();
+        tree.gclk_configs[0] = Some(GclkConfig::new(DPLL0).output_enable(true));
+        tree.gclk_configs[1] = Some(GclkConfig::new(src_32k).output_enable(true));
+        tree.gclk_configs[5] = Some(GclkConfig::new(DFLL).divider(24));
+        tree.channels[FDPLL0.bits()] = Some(GCLK5);
+        tree
+    }
+
+    /// Returns the frequency of a clock generator source, or zero if
+    /// it isn't running.
+    fn source_freq(&self, src: ClockSource, idx: usize) -> Hertz {
+        match src {
+            XOSC32K if self.xosc32k_enabled => XOSC32K_FREQ,
+            XOSC32K => Hertz(0),
+            OSCULP32K => OSC32K_FREQ,
+            GCLKGEN1 => self.gclks[1],
+            DFLL => OSC48M_FREQ,
+            DPLL0 => self.dpll_freqs[0],
+            XOSC0 => self.xosc_freqs[0],
+            XOSC1 => self.xosc_freqs[1],
+            GCLKIN => self.gclk_in_freqs[idx],
+            DPLL1 => self.dpll_freqs[1],
+        }
+    }
+
+    /// Returns true if a peripheral channel, a GCLK_IO pin or another
+    /// clock generator depends on the clock generator.
+    fn gclk_in_use(&self, gclk: ClockGenId) -> bool {
+        self.channels.contains(&Some(gclk))
+            || self.gclk_outs[u8::from(gclk) as usize] != 0
+            || (gclk == GCLK1
+                && self.gclk_configs.iter().flatten().any(|config| config.source == GCLKGEN1))
+    }
+
+    /// Returns true if retuning DPLL0 would change the frequency under a
+    /// clock generator other than gclk0, or under a peripheral channel
+    /// fed from gclk0.
+    fn dpll0_in_use(&self) -> bool {
+        self.gclk_configs.iter().enumerate().any(|(idx, config)| match config {
+            Some(config) if config.source == DPLL0 => idx != 0 || self.gclk_in_use(GCLK0),
+            _ => false,
+        })
+    }
+
+    /// Forgets the routing of the peripheral channels whose clock token
+    /// has been dropped. They stay routed in hardware, so the peripherals
+    /// keep running, but no longer hold on to their clock generator.
+    fn release_dropped_tokens(&mut self) {
+        let live = interrupt::free(|cs| LIVE_CHANNELS.borrow(cs).get());
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            if self.token_channels & !live & (1 << idx) != 0 {
+                *channel = None;
+            }
+        }
+        self.token_channels &= live;
+    }
 }
 
 impl GenericClockController {
@@ -152,7 +330,7 @@ impl GenericClockController {
         oscctrl: &mut OSCCTRL,
         nvmctrl: &mut NVMCTRL,
     ) -> Self {
-        Self::new(gclk, mclk,  osc32kctrl, oscctrl, nvmctrl, false)
+        Self::new(gclk, mclk,  osc32kctrl, oscctrl, nvmctrl, false, false)
     }
 
     /// Reset the clock controller, configure the system to run
@@ -164,7 +342,29 @@ impl GenericClockController {
         oscctrl: &mut OSCCTRL,
         nvmctrl: &mut NVMCTRL,
     ) -> Self {
-        Self::new(gclk, mclk, osc32kctrl, oscctrl, nvmctrl, true)
+        Self::new(gclk, mclk, osc32kctrl, oscctrl, nvmctrl, true, false)
+    }
+
+    /// Reset the clock controller, configure the system to run
+    /// at 120Mhz and reset various clock dividers, for boards without
+    /// a 32KHz crystal that are attached to USB.
+    /// The DFLL48M is locked to the 1KHz start-of-frame packets sent by
+    /// the USB host, which makes it accurate enough to clock the USB
+    /// peripheral; DPLL0, and thus gclk0, are derived from it via gclk5
+    /// as usual. Until the USB peripheral is enabled and receiving
+    /// start-of-frame packets the DFLL48M runs open loop.
+    /// The USB peripheral itself needs a 48MHz clock generator with the
+    /// `DFLL` source.
+    /// gclk1 runs from the internal 32KHz oscillator, so use
+    /// `ClockPlan::with_internal_32kosc` for plans.
+    pub fn with_usb_clock_recovery(
+        gclk: GCLK,
+        mclk: &mut MCLK,
+        osc32kctrl: &mut OSC32KCTRL,
+        oscctrl: &mut OSCCTRL,
+        nvmctrl: &mut NVMCTRL,
+    ) -> Self {
+        Self::new(gclk, mclk, osc32kctrl, oscctrl, nvmctrl, false, true)
     }
 
     fn new(
@@ -174,10 +374,11 @@ impl GenericClockController {
         oscctrl: &mut OSCCTRL,
         nvmctrl: &mut NVMCTRL,
         use_external_crystal: bool,
+        usb_clock_recovery: bool,
     ) -> Self {
         let mut state = State { gclk };
 
-        set_flash_to_half_auto_wait_state(nvmctrl);
+        set_flash_wait_states(nvmctrl, flash_wait_states(OSC120M_FREQ));
         enable_gclk_apb(mclk);
 
         if use_external_crystal {
@@ -190,6 +391,13 @@ impl GenericClockController {
             state.set_gclk_divider_and_source(GCLK1, 1, OSCULP32K, false);
         }
 
+        if usb_clock_recovery {
+            // Run the CPU from gclk1 while the DFLL48M is reconfigured
+            state.set_gclk_divider_and_source(GCLK0, 1, GCLKGEN1, false);
+            configure_dfll48m_usb_crm(oscctrl);
+            state.set_gclk_divider_and_source(GCLK0, 1, DFLL, false);
+        }
+
         while state.gclk.syncbusy.read().genctrl0().is_gclk0() {}
 
         // GCLK5 set to 2MHz
@@ -203,8 +411,10 @@ impl GenericClockController {
 
         while state.gclk.syncbusy.read().genctrl5().is_gclk5() {}
 
-        configure_and_enable_dpll0(oscctrl, &mut state.gclk);
-        wait_for_dpllrdy(oscctrl);
+        // DPLL0 set to 120MHz, using GCLK5 as its 2MHz reference
+        state.enable_clock_generator(FDPLL0, GCLK5);
+        let dpll0 = DpllConfig::with_ratio(DpllReference::Gclk(GCLK5), 59, 0);
+        configure_and_enable_dpll(&dpll_regs(oscctrl, 0), &dpll0, 59, 0);
 
         unsafe {
             // GCLK0 set to DPLL0 (120MHz)
@@ -224,64 +434,215 @@ impl GenericClockController {
 
         Self {
             state,
-            gclks: [
-                OSC120M_FREQ,
-                OSC32K_FREQ,
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                MegaHertz(2).into(),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-                Hertz(0),
-            ],
-            used_clocks: 1u64 << u8::from(DPLL0),
+            tree: ClockTree::new(use_external_crystal),
         }
     }
 
     /// Returns a `GClock` for gclk0, the 120MHz oscillator.
-    pub fn gclk0(&mut self) -> GClock {
-        GClock {
-            gclk: GCLK0,
-            freq: self.gclks[0],
-        }
+    /// Returns `None` while another `GClock` for gclk0 is alive.
+    pub fn gclk0(&mut self) -> Option<GClock> {
+        self.get_gclk(GCLK0)
     }
 
     /// Returns a `GClock` for gclk1, the 32KHz oscillator.
-    pub fn gclk1(&mut self) -> GClock {
-        GClock {
-            gclk: GCLK1,
-            freq: self.gclks[1],
-        }
+    /// Returns `None` while another `GClock` for gclk1 is alive.
+    pub fn gclk1(&mut self) -> Option<GClock> {
+        self.get_gclk(GCLK1)
     }
 
     /// Returns the `GClock` for the specified clock generator.
-    /// If that clock generator has not yet been configured,
-    /// returns None.
+    /// If that clock generator has not yet been configured, or
+    /// another `GClock` for it is alive, returns None.
     pub fn get_gclk(&mut self, gclk: ClockGenId) -> Option<GClock> {
-        let idx = u8::from(gclk) as usize;
-        if self.gclks[idx].0 == 0 {
+        let freq = self.gclk_freq(gclk);
+        if freq.0 == 0 {
             None
         } else {
-            Some(GClock {
-                gclk,
-                freq: self.gclks[idx],
-            })
+            GClock::claim(gclk, freq)
+        }
+    }
+
+    /// Returns the frequency of the specified clock generator, or zero
+    /// if it is not running. Unlike `get_gclk`, this works while the
+    /// `GClock` for the generator is alive.
+    pub fn gclk_freq(&self, gclk: ClockGenId) -> Hertz {
+        self.tree.gclks[u8::from(gclk) as usize]
+    }
+
+    /// Enables the external crystal oscillator XOSC0 so that it
+    /// can be used as the `XOSC0` source of a clock generator.
+    /// `freq` is the frequency of the crystal or clock signal attached
+    /// to XIN0; the oscillator current and startup time are selected
+    /// from it.
+    pub fn enable_xosc0<F: Into<Hertz>>(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        freq: F,
+        mode: XoscMode,
+    ) {
+        let freq = freq.into();
+        enable_xosc(oscctrl, 0, freq, mode);
+        self.tree.xosc_freqs[0] = freq;
+    }
+
+    /// Enables the external crystal oscillator XOSC1 so that it
+    /// can be used as the `XOSC1` source of a clock generator.
+    /// `freq` is the frequency of the crystal or clock signal attached
+    /// to XIN1; the oscillator current and startup time are selected
+    /// from it.
+    pub fn enable_xosc1<F: Into<Hertz>>(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        freq: F,
+        mode: XoscMode,
+    ) {
+        let freq = freq.into();
+        enable_xosc(oscctrl, 1, freq, mode);
+        self.tree.xosc_freqs[1] = freq;
+    }
+
+    /// Keeps the external 32kHz oscillator running in standby, if the
+    /// controller was created with it, so that the RTC and clock
+    /// generators configured with `GclkConfig::run_standby` can keep
+    /// running from it. The internal ultra low power oscillator
+    /// always runs in standby.
+    pub fn run_32kosc_in_standby(&mut self, osc32kctrl: &mut OSC32KCTRL, run_standby: bool) {
+        if self.tree.xosc32k_enabled {
+            osc32kctrl
+                .xosc32k
+                .modify(|_, w| w.runstdby().bit(run_standby));
+        }
+    }
+
+    /// Records the frequency of the external clock applied to the
+    /// GCLK_IO pin of the specified clock generator, so that it can
+    /// be used as the `GCLKIN` source of that generator.
+    /// The pin itself must be switched to the GCLK_IO peripheral function
+    /// by the application.
+    pub fn set_gclk_in_freq<F: Into<Hertz>>(&mut self, gclk: ClockGenId, freq: F) {
+        self.tree.gclk_in_freqs[u8::from(gclk) as usize] = freq.into();
+    }
+
+    /// Reconfigures DPLL0, which drives gclk0 and thus the CPU, and waits
+    /// for it to lock.
+    /// While the PLL is being reconfigured gclk0 is temporarily switched
+    /// over to the DFLL48M. If gclk0 has been switched to another source
+    /// via `reconfigure_gclk`, it is left alone.
+    /// The NVM read wait states are adjusted to the new CPU frequency.
+    /// Returns `None` if a clock generator other than gclk0 is fed from
+    /// DPLL0, if gclk0 is fed from DPLL0 and is in use by a peripheral
+    /// channel (see `reconfigure_gclk`), if the selected reference is not
+    /// running, if the XOSC divider doesn't fit in its register, if the
+    /// reference or output frequency is outside of the range supported by
+    /// the hardware, or if the output frequency is faster than the
+    /// 120MHz that the CPU supports.
+    pub fn configure_dpll0(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        nvmctrl: &mut NVMCTRL,
+        config: DpllConfig,
+    ) -> Option<DpllClock> {
+        self.tree.release_dropped_tokens();
+        if self.tree.dpll0_in_use() {
+            return None;
+        }
+        let (ldr, ldrfrac, freq) = config.solve(config.reference_freq(&self.tree))?;
+        if freq.0 > OSC120M_FREQ.0 {
+            return None;
+        }
+        self.retune_dpll0(oscctrl, nvmctrl, &config, ldr, ldrfrac, freq);
+        Some(DpllClock { freq })
+    }
+
+    /// Retunes DPLL0, once the configuration has been checked
+    fn retune_dpll0(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        nvmctrl: &mut NVMCTRL,
+        config: &DpllConfig,
+        ldr: u16,
+        ldrfrac: u8,
+        freq: Hertz,
+    ) {
+        let gclk0 = self.tree.gclk_configs[0].filter(|config| config.source == DPLL0);
+        if let Some(gclk0) = gclk0 {
+            let new_freq = gclk0.divider.divide(freq);
+            let max_freq = new_freq.0.max(self.tree.gclks[0].0).max(OSC48M_FREQ.0);
+            set_flash_wait_states(nvmctrl, flash_wait_states(Hertz(max_freq)));
+            self.state.set_gclk_divider_and_source(GCLK0, 1, DFLL, false);
+            self.tree.gclks[0] = OSC48M_FREQ;
+        }
+        if let DpllReference::Gclk(gclk) = config.reference {
+            self.state.enable_clock_generator(FDPLL0, gclk);
+            self.tree.channels[FDPLL0.bits()] = Some(gclk);
+        }
+        configure_and_enable_dpll(&dpll_regs(oscctrl, 0), config, ldr, ldrfrac);
+        if let Some(gclk0) = gclk0 {
+            self.state.configure_gclk(GCLK0, &gclk0);
+            self.tree.gclks[0] = gclk0.divider.divide(freq);
+            set_flash_wait_states(nvmctrl, flash_wait_states(self.tree.gclks[0]));
+        }
+        self.tree.dpll_freqs[0] = freq;
+    }
+
+    /// Configures and enables DPLL1, then waits for it to lock.
+    /// The resulting `DpllClock` carries the exact output frequency, which
+    /// is also used for any clock generator configured with the `DPLL1`
+    /// source.
+    /// Returns `None` if DPLL1 has already been configured, if the
+    /// selected reference is not running, if the XOSC divider doesn't fit
+    /// in its register, or if the reference or output frequency is outside
+    /// of the range supported by the hardware.
+    pub fn configure_dpll1(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        config: DpllConfig,
+    ) -> Option<DpllClock> {
+        if self.tree.dpll_freqs[1].0 != 0 {
+            return None;
         }
+        let (ldr, ldrfrac, freq) = config.solve(config.reference_freq(&self.tree))?;
+        if let DpllReference::Gclk(_) = config.reference {
+            if self.tree.channels[FDPLL1.bits()].is_some() {
+                return None;
+            }
+        }
+        self.enable_dpll1(oscctrl, &config, ldr, ldrfrac, freq);
+        Some(DpllClock { freq })
+    }
+
+    /// Routes the reference to DPLL1 and enables it, once the
+    /// configuration has been checked
+    fn enable_dpll1(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        config: &DpllConfig,
+        ldr: u16,
+        ldrfrac: u8,
+        freq: Hertz,
+    ) {
+        if let DpllReference::Gclk(gclk) = config.reference {
+            self.tree.channels[FDPLL1.bits()] = Some(gclk);
+            self.state.enable_clock_generator(FDPLL1, gclk);
+        }
+        configure_and_enable_dpll(&dpll_regs(oscctrl, 1), config, ldr, ldrfrac);
+        self.tree.dpll_freqs[1] = freq;
     }
 
     /// Configures a clock generator with the specified divider and
     /// source.
     /// `divider` is a linear divider to be applied to the clock
-    /// source.  While the hardware also supports an exponential divider,
-    /// this function doesn't expose that functionality at this time.
+    /// source; use `configure_gclk` for the exponential divider.
     /// `improve_duty_cycle` is a boolean that, when set to true, enables
     /// a 50/50 duty cycle for odd divider values.
+    /// The generator output is enabled, so it shows up on its GCLK_IO
+    /// pin if that pin is switched to the GCLK_IO function.
     /// Returns a `GClock` for the configured clock generator.
-    /// Returns `None` if the clock generator has already been configured.
+    /// Returns `None` if the clock generator has already been configured,
+    /// if `src` is `XOSC0`, `XOSC1` or `GCLKIN` and its frequency has
+    /// not been set up via `enable_xosc0`, `enable_xosc1` or
+    /// `set_gclk_in_freq`, or if the divider doesn't fit in the division
+    /// factor of the generator.
     pub fn configure_gclk_divider_and_source(
         &mut self,
         gclk: ClockGenId,
@@ -289,22 +650,434 @@ impl GenericClockController {
         src: ClockSource,
         improve_duty_cycle: bool,
     ) -> Option<GClock> {
-        let idx = u8::from(gclk) as usize;
-        if self.gclks[idx].0 != 0 {
+        let config = GclkConfig::new(src)
+            .divider(divider)
+            .improve_duty_cycle(improve_duty_cycle)
+            .output_enable(true);
+        self.configure_gclk(gclk, config)
+    }
+
+    /// Configures a clock generator from a `GclkConfig`, which also gives
+    /// access to the exponential divider, the GCLK_IO output and running
+    /// in standby.
+    /// Returns `None` under the same conditions as
+    /// `configure_gclk_divider_and_source`.
+    pub fn configure_gclk(&mut self, gclk: ClockGenId, config: GclkConfig) -> Option<GClock> {
+        if self.gclk_freq(gclk).0 != 0 {
             return None;
         }
-        self.state
-            .set_gclk_divider_and_source(gclk, divider, src, improve_duty_cycle);
-        let freq: Hertz = match src {
-            XOSC32K | OSCULP32K => OSC32K_FREQ,
-            GCLKGEN1 => self.gclks[1],
-            DFLL => OSC48M_FREQ,
-            DPLL0 => OSC120M_FREQ,
-            XOSC0 | XOSC1 | GCLKIN | DPLL1 => unimplemented!(),
+        let freq = self.apply_gclk_config(gclk, config)?;
+        GClock::claim(gclk, freq)
+    }
+
+    /// Retunes a clock generator that is already running, for example
+    /// to switch it to a low power source before going to sleep.
+    /// When retuning gclk0, the NVM read wait states are adjusted to the
+    /// new CPU frequency.
+    /// The peripheral clock tokens borrow the `GClock`, so none of them
+    /// can be alive; the peripheral channels of dropped tokens stay routed
+    /// and follow the new frequency. Channels routed by a `ClockPlan` or
+    /// to clock a DPLL, an output enabled on a pin (see
+    /// `disable_gclk_out`), and if `gclk` is gclk1 other generators fed
+    /// from it, are checked at run time. gclk0 must not end up faster
+    /// than the 120MHz that the CPU supports.
+    /// Returns the updated `GClock` on success; otherwise the passed in
+    /// `GClock` is handed back unchanged.
+    pub fn reconfigure_gclk(
+        &mut self,
+        nvmctrl: &mut NVMCTRL,
+        mut gclk: GClock,
+        config: GclkConfig,
+    ) -> Result<GClock, GClock> {
+        self.tree.release_dropped_tokens();
+        if self.tree.gclk_in_use(gclk.gclk) || (gclk.gclk == GCLK1 && config.source == GCLKGEN1) {
+            return Err(gclk);
+        }
+        let freq = if gclk.gclk == GCLK0 {
+            let freq = config.divider.divide(self.tree.source_freq(config.source, 0));
+            if freq.0 > OSC120M_FREQ.0 {
+                return Err(gclk);
+            }
+            // Use enough wait states for both the old and the new frequency
+            // while switching over, then settle on those for the new one.
+            let old_freq = self.tree.gclks[0];
+            set_flash_wait_states(nvmctrl, flash_wait_states(Hertz(freq.0.max(old_freq.0))));
+            let freq = self.apply_gclk_config(GCLK0, config);
+            set_flash_wait_states(nvmctrl, flash_wait_states(self.tree.gclks[0]));
+            freq
+        } else {
+            self.apply_gclk_config(gclk.gclk, config)
+        };
+        match freq {
+            Some(freq) => {
+                gclk.freq = freq;
+                Ok(gclk)
+            }
+            None => Err(gclk),
+        }
+    }
+
+    /// Stops a clock generator so that it no longer draws power.
+    /// gclk0 drives the CPU and can't be stopped. The other conditions
+    /// are the same as for `reconfigure_gclk`.
+    /// On failure the passed in `GClock` is handed back.
+    pub fn disable_gclk(&mut self, gclk: GClock) -> Result<(), GClock> {
+        self.tree.release_dropped_tokens();
+        if gclk.gclk == GCLK0 || self.tree.gclk_in_use(gclk.gclk) {
+            return Err(gclk);
+        }
+        let idx = u8::from(gclk.gclk) as usize;
+        self.state.disable_gclk(gclk.gclk);
+        self.tree.gclks[idx] = Hertz(0);
+        self.tree.gclk_configs[idx] = None;
+        Ok(())
+    }
+
+    /// Measures the frequency of a clock generator with the frequency
+    /// meter, and from then on uses the measured frequency instead of the
+    /// nominal one for the generator and the clock tokens configured
+    /// from it. This is useful for generators fed from an external
+    /// clock or an uncalibrated oscillator.
+    /// If the measurement fails (see `FrequencyMeter::measure_gclk`) or
+    /// the generator turns out to be stopped, the passed in `GClock` is
+    /// handed back.
+    pub fn calibrate_gclk(&mut self, mut gclk: GClock, meter: &mut FrequencyMeter) -> Result<GClock, GClock> {
+        match meter.measure_gclk(self, &gclk) {
+            Some(freq) if freq.0 != 0 => {
+                self.tree.gclks[u8::from(gclk.gclk) as usize] = freq;
+                gclk.freq = freq;
+                Ok(gclk)
+            }
+            _ => Err(gclk),
+        }
+    }
+
+    /// Switches a pin to the GCLK_IO function and enables the output
+    /// of the clock generator on it, for example to clock an external
+    /// ADC or audio codec.
+    /// The pin is handed back if it is not connected to the GCLK_IO
+    /// signal of the generator.
+    /// The generator can't be reconfigured or disabled until the output
+    /// has been handed back to `disable_gclk_out`.
+    pub fn enable_gclk_out<P: GclkIo>(
+        &mut self,
+        gclk: &GClock,
+        pin: P,
+        port: &mut Port,
+    ) -> Result<GclkOut<P::Pin>, P> {
+        let idx = u8::from(gclk.gclk) as usize;
+        let config = match self.tree.gclk_configs[idx] {
+            Some(config) if pin.gclk() == gclk.gclk => config.output_enable(true),
+            _ => return Err(pin),
         };
-        self.gclks[idx] = Hertz(freq.0 / divider as u32);
-        Some(GClock { gclk, freq })
+        self.state.configure_gclk(gclk.gclk, &config);
+        self.tree.gclk_configs[idx] = Some(config);
+        self.tree.gclk_outs[idx] += 1;
+        Ok(GclkOut {
+            gclk: gclk.gclk,
+            pin: pin.into_gclk_io(port),
+        })
+    }
+
+    /// Disables the output of a clock generator once it is no longer
+    /// output on any pin; the pin then drives the output off value of
+    /// the generator. Returns the pin.
+    pub fn disable_gclk_out<PIN>(&mut self, out: GclkOut<PIN>) -> PIN {
+        let idx = u8::from(out.gclk) as usize;
+        self.tree.gclk_outs[idx] -= 1;
+        if self.tree.gclk_outs[idx] == 0 {
+            if let Some(config) = self.tree.gclk_configs[idx] {
+                let config = config.output_enable(false);
+                self.state.configure_gclk(out.gclk, &config);
+                self.tree.gclk_configs[idx] = Some(config);
+            }
+        }
+        out.pin
+    }
+
+    /// Disables the peripheral channel of a clock token and unroutes
+    /// it from its clock generator, so that the channel can be routed
+    /// to another generator and the generator can be reconfigured.
+    /// The peripheral(s) clocked from the channel stop working until
+    /// a new clock token has been obtained for them.
+    pub fn disable_clock<C: PeripheralClock>(&mut self, clock: C) {
+        let id = clock.id();
+        self.state.disable_clock(id);
+        self.tree.channels[id.bits()] = None;
+        self.tree.token_channels &= !(1 << id.bits());
+    }
+
+    /// Routes the `FREQM_MSR` channel to a clock generator for the
+    /// duration of `measure`, then restores the routing it had before.
+    /// Returns `None` if a `FreqmMsrClock` token is alive.
+    pub(crate) fn with_freqm_msr<R, F: FnOnce(&FreqmMsrClock) -> R>(
+        &mut self,
+        gclk: &GClock,
+        measure: F,
+    ) -> Option<R> {
+        if !claim(&LIVE_CHANNELS, FREQM_MSR.bits()) {
+            return None;
+        }
+        self.state.enable_clock_generator(FREQM_MSR, gclk.gclk);
+        let result = measure(&FreqmMsrClock {
+            freq: self.tree.gclks[u8::from(gclk.gclk) as usize],
+            _gclk: PhantomData,
+        });
+        match self.tree.channels[FREQM_MSR.bits()] {
+            Some(prior) => self.state.enable_clock_generator(FREQM_MSR, prior),
+            None => self.state.disable_clock(FREQM_MSR),
+        }
+        Some(result)
+    }
+
+    /// Configures a clock generator and returns its new frequency
+    fn apply_gclk_config(&mut self, gclk: ClockGenId, config: GclkConfig) -> Option<Hertz> {
+        let idx = u8::from(gclk) as usize;
+        let freq = self.tree.source_freq(config.source, idx);
+        if freq.0 == 0 || !config.divider.fits(gclk) {
+            return None;
+        }
+        self.state.configure_gclk(gclk, &config);
+        let freq = config.divider.divide(freq);
+        self.tree.gclks[idx] = freq;
+        self.tree.gclk_configs[idx] = Some(config);
+        Some(freq)
     }
+
+    /// Applies a `ClockPlan`: enables the planned oscillators and DPLLs,
+    /// configures the planned clock generators and routes the planned
+    /// peripheral channels to them.
+    /// The plan is solved against the clock tree the controller has
+    /// configured so far (see `ClockPlan::solve_for`) before any hardware
+    /// is touched, so on error nothing has been changed.
+    /// The peripheral clock tokens for the planned channels can then be
+    /// obtained as usual, passing in the planned `GClock`.
+    pub fn apply_plan(
+        &mut self,
+        oscctrl: &mut OSCCTRL,
+        nvmctrl: &mut NVMCTRL,
+        plan: &ClockPlan,
+    ) -> Result<ClockSolution, PlanError> {
+        self.tree.release_dropped_tokens();
+        let solution = plan.solve_for(self)?;
+
+        // Everything has been checked, nothing below can fail
+        if let Some((freq, mode)) = plan.xoscs[0] {
+            self.enable_xosc0(oscctrl, freq, mode);
+        }
+        if let Some((freq, mode)) = plan.xoscs[1] {
+            self.enable_xosc1(oscctrl, freq, mode);
+        }
+        for step in solution.steps.iter().flatten() {
+            match *step {
+                PlanStep::Dpll(0, ldr, ldrfrac) => {
+                    if let Some(config) = plan.dplls[0] {
+                        let freq = solution.tree.dpll_freqs[0];
+                        self.retune_dpll0(oscctrl, nvmctrl, &config, ldr, ldrfrac, freq);
+                    }
+                }
+                PlanStep::Dpll(_, ldr, ldrfrac) => {
+                    if let Some(config) = plan.dplls[1] {
+                        let freq = solution.tree.dpll_freqs[1];
+                        self.enable_dpll1(oscctrl, &config, ldr, ldrfrac, freq);
+                    }
+                }
+                PlanStep::Gclk(gclk) => {
+                    if let Some(config) = solution.tree.gclk_configs[u8::from(gclk) as usize] {
+                        self.state.configure_gclk(gclk, &config);
+                    }
+                }
+            }
+        }
+        for &(id, gclk) in plan.channels.iter().flatten() {
+            if self.tree.channels[id.bits()].is_none() {
+                self.state.enable_clock_generator(id, gclk);
+            }
+        }
+        self.tree = solution.tree;
+
+        Ok(solution)
+    }
+}
+
+/// The division factor of a clock generator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GclkDivider {
+    /// Divide the source by the given factor. A factor of 0 is treated
+    /// the same as 1.
+    Linear(u16),
+    /// Divide the source by 2^(n+1). This allows for larger division
+    /// factors than the linear divider, but only in powers of two.
+    Exponential(u8),
+}
+
+impl GclkDivider {
+    /// Returns the frequency that a source frequency is divided down to.
+    pub fn divide(self, freq: Hertz) -> Hertz {
+        match self {
+            GclkDivider::Linear(divider) => Hertz(freq.0 / (divider as u32).max(1)),
+            GclkDivider::Exponential(exponent) => {
+                Hertz(freq.0.checked_shr(exponent as u32 + 1).unwrap_or(0))
+            }
+        }
+    }
+
+    /// Returns true if the divider fits in the division factor of the
+    /// clock generator.
+    fn fits(self, gclk: ClockGenId) -> bool {
+        let max = gclk_max_divider(gclk);
+        match self {
+            GclkDivider::Linear(divider) => divider as u32 <= max,
+            // The exponent is limited to the number of division factor bits
+            GclkDivider::Exponential(exponent) => exponent as u32 <= 32 - max.leading_zeros(),
+        }
+    }
+}
+
+/// The configuration of a clock generator, for use with
+/// `GenericClockController::configure_gclk` and
+/// `GenericClockController::reconfigure_gclk`.
+/// By default the source is not divided, the duty cycle is not
+/// improved, the GCLK_IO output is disabled and driven low, and the
+/// generator stops in standby.
+#[derive(Clone, Copy, Debug)]
+pub struct GclkConfig {
+    source: ClockSource,
+    divider: GclkDivider,
+    improve_duty_cycle: bool,
+    output_enable: bool,
+    output_off_value: bool,
+    run_standby: bool,
+}
+
+impl GclkConfig {
+    /// Clock generator configuration that runs from `source`.
+    pub fn new(source: ClockSource) -> Self {
+        Self {
+            source,
+            divider: GclkDivider::Linear(1),
+            improve_duty_cycle: false,
+            output_enable: false,
+            output_off_value: false,
+            run_standby: false,
+        }
+    }
+
+    /// Divide the source linearly by `divider`.
+    pub fn divider(mut self, divider: u16) -> Self {
+        self.divider = GclkDivider::Linear(divider);
+        self
+    }
+
+    /// Divide the source by 2^(exponent+1).
+    pub fn exponential_divider(mut self, exponent: u8) -> Self {
+        self.divider = GclkDivider::Exponential(exponent);
+        self
+    }
+
+    /// When set, odd linear divider values produce a 50/50 duty cycle.
+    pub fn improve_duty_cycle(mut self, improve_duty_cycle: bool) -> Self {
+        self.improve_duty_cycle = improve_duty_cycle;
+        self
+    }
+
+    /// When set, the generator drives its GCLK_IO pin, if that pin is
+    /// switched to the GCLK_IO function. See also
+    /// `GenericClockController::enable_gclk_out`.
+    pub fn output_enable(mut self, output_enable: bool) -> Self {
+        self.output_enable = output_enable;
+        self
+    }
+
+    /// The level of the GCLK_IO pin while the generator is stopped
+    /// or its output is disabled.
+    pub fn output_off_value(mut self, high: bool) -> Self {
+        self.output_off_value = high;
+        self
+    }
+
+    /// When set, the generator keeps running in standby sleep mode.
+    pub fn run_standby(mut self, run_standby: bool) -> Self {
+        self.run_standby = run_standby;
+        self
+    }
+}
+
+/// Implemented for the pins that can carry the GCLK_IO signal of a
+/// clock generator. You should not implement this trait yourself; only
+/// the implementations in the clock module make sense.
+pub trait GclkIo {
+    /// The pin switched to the GCLK_IO function
+    type Pin;
+    /// Returns the clock generator whose GCLK_IO signal is on this pin
+    fn gclk(&self) -> ClockGenId;
+    /// Switch the pin to the GCLK_IO function
+    fn into_gclk_io(self, port: &mut Port) -> Self::Pin;
+}
+
+/// A clock generator output on its GCLK_IO pin, as returned by
+/// `GenericClockController::enable_gclk_out`.
+pub struct GclkOut<PIN> {
+    gclk: ClockGenId,
+    pin: PIN,
+}
+
+impl<PIN> GclkOut<PIN> {
+    /// Returns the clock generator that drives the pin
+    pub fn gclk(&self) -> ClockGenId {
+        self.gclk
+    }
+}
+
+macro_rules! gclk_io {
+    ($($(#[$attr:meta])* $PinType:ident: ($gclk:ident, $Pf:ident),)+) => {
+        $(
+        $(#[$attr])*
+        impl<MODE> GclkIo for gpio::$PinType<MODE> {
+            type Pin = gpio::$PinType<gpio::$Pf>;
+
+            fn gclk(&self) -> ClockGenId {
+                $gclk
+            }
+
+            fn into_gclk_io(self, port: &mut Port) -> Self::Pin {
+                self.into_function(port)
+            }
+        }
+        )+
+    };
+}
+
+gclk_io!(
+    Pa10: (GCLK4, PfM),
+    Pa11: (GCLK5, PfM),
+    Pa14: (GCLK0, PfM),
+    Pa15: (GCLK1, PfM),
+    Pa16: (GCLK2, PfM),
+    Pa17: (GCLK3, PfM),
+    Pa27: (GCLK1, PfM),
+    Pa30: (GCLK0, PfM),
+    Pb10: (GCLK4, PfM),
+    Pb11: (GCLK5, PfM),
+    Pb12: (GCLK6, PfM),
+    Pb13: (GCLK7, PfM),
+    Pb14: (GCLK0, PfM),
+    Pb15: (GCLK1, PfM),
+    Pb16: (GCLK2, PfM),
+    Pb17: (GCLK3, PfM),
+    Pb18: (GCLK4, PfM),
+    Pb19: (GCLK5, PfM),
+    Pb20: (GCLK6, PfM),
+    Pb21: (GCLK7, PfM),
+    Pb22: (GCLK0, PfM),
+    Pb23: (GCLK1, PfM),
+);
+
+/// Implemented by the peripheral clock tokens, so that they can be
+/// handed back to `GenericClockController::disable_clock`.
+pub trait PeripheralClock {
+    /// Returns the peripheral channel of the token
+    fn id(&self) -> ClockId;
 }
 
 macro_rules! clock_generator {
@@ -318,22 +1091,36 @@ $(
 /// The peripheral initialization code will typically require passing
 /// in this object to prove at compile time that the clock has been
 /// correctly initialized.
+/// The token borrows the `GClock` it was configured from. Dropping it
+/// leaves the peripheral(s) clocked, but lets the controller route the
+/// channel to another clock generator and reconfigure this one.
 #[derive(Debug)]
-pub struct $Type {
+pub struct $Type<'a> {
     freq: Hertz,
+    _gclk: PhantomData<&'a GClock>,
 }
 
-impl $Type {
+impl<'a> $Type<'a> {
     /// Returns the frequency of the configured clock
     pub fn freq(&self) -> Hertz {
         self.freq
     }
 }
-impl Into<Hertz> for $Type {
+impl<'a> Into<Hertz> for $Type<'a> {
     fn into(self) -> Hertz {
         self.freq
     }
 }
+impl<'a> PeripheralClock for $Type<'a> {
+    fn id(&self) -> ClockId {
+        $clock
+    }
+}
+impl<'a> Drop for $Type<'a> {
+    fn drop(&mut self) {
+        release(&LIVE_CHANNELS, $clock.bits());
+    }
+}
 )+
 
 impl GenericClockController {
@@ -348,18 +1135,26 @@ impl GenericClockController {
     /// the peripheral initialization code will typically require that this
     /// clock token be passed in to ensure that the clock has been initialized
     /// appropriately.
-    /// Returns `None` is the specified generic clock has already been
-    /// configured.
-    pub fn $id(&mut self, generator: &GClock) -> Option<$Type> {
-        let bits : u64 = 1<<$clock.bits() as u64;
-        if (self.used_clocks & bits) != 0 {
+    /// Returns `None` while a token for the specified generic clock is
+    /// alive, or if it has been routed to a different clock generator
+    /// by a `ClockPlan` or by a token that is still alive.
+    pub fn $id<'a>(&mut self, generator: &'a GClock) -> Option<$Type<'a>> {
+        let idx = $clock.bits();
+        self.tree.release_dropped_tokens();
+        match self.tree.channels[idx] {
+            Some(gclk) if gclk != generator.gclk => return None,
+            _ => (),
+        }
+        if !claim(&LIVE_CHANNELS, idx) {
             return None;
         }
-        self.used_clocks |= bits;
-
-        self.state.enable_clock_generator($clock, generator.gclk);
-        let freq = self.gclks[u8::from(generator.gclk) as usize];
-        Some($Type{freq})
+        if self.tree.channels[idx].is_none() {
+            self.state.enable_clock_generator($clock, generator.gclk);
+            self.tree.channels[idx] = Some(generator.gclk);
+            self.tree.token_channels |= 1 << idx;
+        }
+        let freq = self.tree.gclks[u8::from(generator.gclk) as usize];
+        Some($Type{freq, _gclk: PhantomData})
     }
     )+
 }
@@ -380,18 +1175,648 @@ clock_generator!(
     (usb, UsbClock, USB),
     (adc0, Adc0Clock, ADC0),
     (adc1, Adc1Clock, ADC1),
+    (slow_32k, Slow32kClock, SLOW_32K),
+    (eic, EicClock, EIC),
+    (freqm_msr, FreqmMsrClock, FREQM_MSR),
+    (freqm_ref, FreqmRefClock, FREQM_REF),
+    (evsys0, Evsys0Clock, EVSYS0),
+    (evsys1, Evsys1Clock, EVSYS1),
+    (evsys2, Evsys2Clock, EVSYS2),
+    (evsys3, Evsys3Clock, EVSYS3),
+    (evsys4, Evsys4Clock, EVSYS4),
+    (evsys5, Evsys5Clock, EVSYS5),
+    (evsys6, Evsys6Clock, EVSYS6),
+    (evsys7, Evsys7Clock, EVSYS7),
+    (evsys8, Evsys8Clock, EVSYS8),
+    (evsys9, Evsys9Clock, EVSYS9),
+    (evsys10, Evsys10Clock, EVSYS10),
+    (evsys11, Evsys11Clock, EVSYS11),
+    (tcc0_tcc1, Tcc0Tcc1Clock, TCC0_TCC1),
+    (tcc2_tcc3, Tcc2Tcc3Clock, TCC2_TCC3),
+    (tcc4, Tcc4Clock, TCC4),
+    (pdec, PdecClock, PDEC),
+    (ac, AcClock, AC),
+    (ccl, CclClock, CCL),
+    (sercom6_core, Sercom6CoreClock, SERCOM6_CORE),
+    (sercom7_core, Sercom7CoreClock, SERCOM7_CORE),
+    (dac, DacClock, DAC),
+    (i2s0, I2S0Clock, I2S0),
+    (i2s1, I2S1Clock, I2S1),
+    (sdhc0, Sdhc0Clock, SDHC0),
+    (sdhc1, Sdhc1Clock, SDHC1),
+    (cm4_trace, Cm4TraceClock, CM4_TRACE),
 );
 
+/// The reference clock of a fractional digital phase locked loop (DPLL).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DpllReference {
+    /// The external 32kHz crystal oscillator; only available when the
+    /// controller was created with `with_external_32kosc`.
+    Xosc32k,
+    /// The XOSC0 oscillator, divided by `2 * (div + 1)`. The divider
+    /// is 11 bits wide, so `div` can be at most 2047.
+    Xosc0 { div: u16 },
+    /// The XOSC1 oscillator, divided by `2 * (div + 1)`, with the same
+    /// limit on `div`.
+    Xosc1 { div: u16 },
+    /// The output of an already configured clock generator.
+    Gclk(ClockGenId),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum DpllRatio {
+    Ratio { ldr: u16, ldrfrac: u8 },
+    OutputFreq(Hertz),
+}
+
+/// Describes how a DPLL should be configured.
+/// The output frequency is `f_ref * (ldr + 1 + ldrfrac / 32)`; it can
+/// either be set explicitly via `with_ratio` or computed from the desired
+/// output frequency via `with_output_freq`.
+#[derive(Clone, Copy, Debug)]
+pub struct DpllConfig {
+    reference: DpllReference,
+    ratio: DpllRatio,
+    filter: u8,
+    lock_bypass: bool,
+}
+
+impl DpllConfig {
+    /// Configure the DPLL with an explicit integer (`ldr`) and
+    /// fractional (`ldrfrac`, in 1/32ths) loop divider ratio.
+    pub fn with_ratio(reference: DpllReference, ldr: u16, ldrfrac: u8) -> Self {
+        Self {
+            reference,
+            ratio: DpllRatio::Ratio { ldr, ldrfrac },
+            filter: 0,
+            lock_bypass: false,
+        }
+    }
+
+    /// Configure the DPLL to run as close as possible to `freq`.
+    pub fn with_output_freq<F: Into<Hertz>>(reference: DpllReference, freq: F) -> Self {
+        Self {
+            reference,
+            ratio: DpllRatio::OutputFreq(freq.into()),
+            filter: 0,
+            lock_bypass: false,
+        }
+    }
+
+    /// Select the proportional integral loop filter; see the
+    /// DPLLCTRLB.FILTER table in the datasheet for the available
+    /// bandwidth and damping combinations.
+    pub fn filter(mut self, filter: u8) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// When set, the DPLL output is always enabled, even before the
+    /// loop has locked.
+    pub fn lock_bypass(mut self, lock_bypass: bool) -> Self {
+        self.lock_bypass = lock_bypass;
+        self
+    }
+
+    /// Returns the frequency of the reference clock, or zero if
+    /// the reference is not running.
+    fn reference_freq(&self, tree: &ClockTree) -> Hertz {
+        match self.reference {
+            DpllReference::Xosc32k if tree.xosc32k_enabled => XOSC32K_FREQ,
+            DpllReference::Xosc32k => Hertz(0),
+            DpllReference::Xosc0 { div } => Hertz(tree.xosc_freqs[0].0 / (2 * (div as u32 + 1))),
+            DpllReference::Xosc1 { div } => Hertz(tree.xosc_freqs[1].0 / (2 * (div as u32 + 1))),
+            DpllReference::Gclk(gclk) => tree.gclks[u8::from(gclk) as usize],
+        }
+    }
+
+    /// Resolves the loop divider ratio and output frequency for the
+    /// given reference frequency, validating them against the hardware
+    /// limits.
+    fn solve(&self, ref_freq: Hertz) -> Option<(u16, u8, Hertz)> {
+        match self.reference {
+            DpllReference::Xosc0 { div } | DpllReference::Xosc1 { div }
+                if div as u32 > DPLL_MAX_XOSC_DIV =>
+            {
+                return None
+            }
+            _ => (),
+        }
+        if ref_freq.0 < DPLL_REF_MIN_FREQ.0 || ref_freq.0 > DPLL_REF_MAX_FREQ.0 {
+            return None;
+        }
+        let (ldr, ldrfrac) = match self.ratio {
+            DpllRatio::Ratio { ldr, ldrfrac } => (ldr, ldrfrac),
+            DpllRatio::OutputFreq(freq) => dpll_ratio(ref_freq, freq)?,
+        };
+        let freq = dpll_output_freq(ref_freq, ldr, ldrfrac);
+        if freq.0 < DPLL_OUT_MIN_FREQ.0 || freq.0 > DPLL_OUT_MAX_FREQ.0 {
+            return None;
+        }
+        Some((ldr, ldrfrac, freq))
+    }
+}
+
+/// A typed token that indicates that a DPLL has been configured
+/// and has locked.
+/// The effective output frequency is available via the `freq` method,
+/// or by converting the object into a `Hertz` instance.
+#[derive(Debug)]
+pub struct DpllClock {
+    freq: Hertz,
+}
+
+impl DpllClock {
+    /// Returns the output frequency of the DPLL
+    pub fn freq(&self) -> Hertz {
+        self.freq
+    }
+}
+
+impl Into<Hertz> for DpllClock {
+    fn into(self) -> Hertz {
+        self.freq
+    }
+}
+
+/// Computes the DPLL loop divider ratio `(ldr, ldrfrac)` that gets
+/// closest to `output` from `reference`.
+/// Returns `None` if the ratio doesn't fit in the hardware registers.
+pub fn dpll_ratio(reference: Hertz, output: Hertz) -> Option<(u16, u8)> {
+    if reference.0 == 0 {
+        return None;
+    }
+    let ref_freq = reference.0 as u64;
+    let ratio = (output.0 as u64 * 32 + ref_freq / 2) / ref_freq;
+    if ratio < 32 || ratio / 32 - 1 > 0x1fff {
+        return None;
+    }
+    Some(((ratio / 32 - 1) as u16, (ratio % 32) as u8))
+}
+
+/// Computes the DPLL output frequency for a given reference frequency
+/// and loop divider ratio.
+pub fn dpll_output_freq(reference: Hertz, ldr: u16, ldrfrac: u8) -> Hertz {
+    let ratio = (ldr as u64 + 1) * 32 + ldrfrac as u64;
+    Hertz((reference.0 as u64 * ratio / 32) as u32)
+}
+
+/// Errors detected while solving or applying a `ClockPlan`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanError {
+    /// The plan was made for a different 32kHz oscillator than the
+    /// one the `GenericClockController` was created with.
+    BaselineMismatch,
+    /// The clock generator is already configured.
+    AlreadyConfigured(ClockGenId),
+    /// The source of the clock generator is not running, or is not
+    /// part of the plan.
+    SourceUnavailable(ClockGenId),
+    /// The divider needed by the clock generator doesn't fit in its
+    /// division factor register.
+    DividerOutOfRange(ClockGenId, u32),
+    /// The DPLL with the given index can't be (re)configured because
+    /// it is already in use.
+    DpllInUse(u8),
+    /// The reference of the DPLL with the given index is not running,
+    /// or the reference or output frequency is outside of the range
+    /// supported by the hardware.
+    InvalidDpll(u8),
+    /// gclk0 would run faster than the 120MHz that the CPU supports.
+    CpuTooFast(Hertz),
+    /// The peripheral channel is routed to a clock generator that is
+    /// neither configured nor part of the plan.
+    GeneratorUnavailable(ClockId),
+    /// The peripheral channel would be clocked faster than it supports.
+    PeripheralTooFast(ClockId, Hertz),
+    /// The peripheral channel is already routed to another clock generator.
+    ChannelInUse(ClockId),
+}
+
+#[derive(Clone, Copy)]
+struct GclkPlan {
+    gclk: ClockGenId,
+    source: ClockSource,
+    target: GclkTarget,
+}
+
+#[derive(Clone, Copy)]
+enum GclkTarget {
+    Freq(Hertz),
+    Divider(u16),
+}
+
+#[derive(Clone, Copy)]
+enum PlanStep {
+    /// Enable the DPLL with the given index and loop divider ratio
+    Dpll(u8, u16, u8),
+    Gclk(ClockGenId),
+}
+
+/// A description of the clock tree: oscillators, DPLLs,
+/// clock generators and the peripheral channels fed from them.
+/// A plan is solved without any hardware access, either against the
+/// clock tree that `GenericClockController` sets up (gclk0 at 120MHz from
+/// DPLL0, gclk1 at 32KHz and gclk5 at 2MHz from the DFLL48M) with `solve`,
+/// or against the tree a controller has actually configured with
+/// `solve_for`. `GenericClockController::apply_plan` then configures the
+/// hardware accordingly.
+/// The dividers and frequencies are checked against the hardware limits
+/// when the plan is solved; solving it in a unit test on the build host
+/// catches a bad plan before flashing.
+#[derive(Clone, Copy)]
+pub struct ClockPlan {
+    xosc32k_enabled: bool,
+    xoscs: [Option<(Hertz, XoscMode)>; 2],
+    gclk_in_freqs: [Hertz; NUM_GCLKS],
+    dplls: [Option<DpllConfig>; 2],
+    gclks: [Option<GclkPlan>; NUM_GCLKS],
+    improve_duty_cycle: [bool; NUM_GCLKS],
+    channels: [Option<(ClockId, ClockGenId)>; NUM_CHANNELS],
+}
+
+impl ClockPlan {
+    /// Start a plan for a controller created with
+    /// `GenericClockController::with_internal_32kosc`.
+    pub fn with_internal_32kosc() -> Self {
+        Self::new(false)
+    }
+
+    /// Start a plan for a controller created with
+    /// `GenericClockController::with_external_32kosc`.
+    pub fn with_external_32kosc() -> Self {
+        Self::new(true)
+    }
+
+    fn new(xosc32k_enabled: bool) -> Self {
+        Self {
+            xosc32k_enabled,
+            xoscs: [None; 2],
+            gclk_in_freqs: [Hertz(0); NUM_GCLKS],
+            dplls: [None; 2],
+            gclks: [None; NUM_GCLKS],
+            improve_duty_cycle: [false; NUM_GCLKS],
+            channels: [None; NUM_CHANNELS],
+        }
+    }
+
+    /// Enable the XOSC0 oscillator; see
+    /// `GenericClockController::enable_xosc0`.
+    pub fn xosc0<F: Into<Hertz>>(mut self, freq: F, mode: XoscMode) -> Self {
+        self.xoscs[0] = Some((freq.into(), mode));
+        self
+    }
+
+    /// Enable the XOSC1 oscillator; see
+    /// `GenericClockController::enable_xosc1`.
+    pub fn xosc1<F: Into<Hertz>>(mut self, freq: F, mode: XoscMode) -> Self {
+        self.xoscs[1] = Some((freq.into(), mode));
+        self
+    }
+
+    /// Declare the frequency of the external clock applied to the
+    /// GCLK_IO pin of a clock generator; see
+    /// `GenericClockController::set_gclk_in_freq`.
+    pub fn gclk_in<F: Into<Hertz>>(mut self, gclk: ClockGenId, freq: F) -> Self {
+        self.gclk_in_freqs[u8::from(gclk) as usize] = freq.into();
+        self
+    }
+
+    /// Reconfigure DPLL0, and with it gclk0; see
+    /// `GenericClockController::configure_dpll0`.
+    pub fn dpll0(mut self, config: DpllConfig) -> Self {
+        self.dplls[0] = Some(config);
+        self
+    }
+
+    /// Configure DPLL1; see `GenericClockController::configure_dpll1`.
+    pub fn dpll1(mut self, config: DpllConfig) -> Self {
+        self.dplls[1] = Some(config);
+        self
+    }
+
+    /// Configure a clock generator to run from `source`, using the
+    /// divider that gets closest to `freq`.
+    pub fn gclk<F: Into<Hertz>>(mut self, gclk: ClockGenId, source: ClockSource, freq: F) -> Self {
+        self.gclks[u8::from(gclk) as usize] = Some(GclkPlan {
+            gclk,
+            source,
+            target: GclkTarget::Freq(freq.into()),
+        });
+        self
+    }
+
+    /// Configure a clock generator to run from `source` with an
+    /// explicit divider.
+    pub fn gclk_divider(mut self, gclk: ClockGenId, source: ClockSource, divider: u16) -> Self {
+        self.gclks[u8::from(gclk) as usize] = Some(GclkPlan {
+            gclk,
+            source,
+            target: GclkTarget::Divider(divider),
+        });
+        self
+    }
+
+    /// Enable a 50/50 duty cycle for odd dividers of a planned clock
+    /// generator; see `GclkConfig::improve_duty_cycle`. It is disabled
+    /// by default.
+    pub fn improve_duty_cycle(mut self, gclk: ClockGenId, improve_duty_cycle: bool) -> Self {
+        self.improve_duty_cycle[u8::from(gclk) as usize] = improve_duty_cycle;
+        self
+    }
+
+    /// Route a peripheral channel to a clock generator.
+    pub fn channel(mut self, id: ClockId, gclk: ClockGenId) -> Self {
+        self.channels[id.bits()] = Some((id, gclk));
+        self
+    }
+
+    /// Works out the dividers and frequencies of the whole clock tree,
+    /// starting from the tree that `GenericClockController` sets up, and
+    /// checks them against the hardware limits.
+    /// This doesn't access the hardware.
+    pub fn solve(&self) -> Result<ClockSolution, PlanError> {
+        self.solve_from(ClockTree::new(self.xosc32k_enabled))
+    }
+
+    /// Like `solve`, but starts from the clock tree that `clocks` has
+    /// configured so far, so that the plan can build on the generators,
+    /// the DPLLs and the peripheral channels set up outside of it.
+    pub fn solve_for(&self, clocks: &GenericClockController) -> Result<ClockSolution, PlanError> {
+        if self.xosc32k_enabled != clocks.tree.xosc32k_enabled {
+            return Err(PlanError::BaselineMismatch);
+        }
+        let mut tree = clocks.tree;
+        tree.release_dropped_tokens();
+        self.solve_from(tree)
+    }
+
+    fn solve_from(&self, mut tree: ClockTree) -> Result<ClockSolution, PlanError> {
+        for gclk_plan in self.gclks.iter().flatten() {
+            if tree.gclks[u8::from(gclk_plan.gclk) as usize].0 != 0 {
+                return Err(PlanError::AlreadyConfigured(gclk_plan.gclk));
+            }
+        }
+        if self.dplls[0].is_some() && tree.dpll0_in_use() {
+            return Err(PlanError::DpllInUse(0));
+        }
+        if let Some(config) = self.dplls[1] {
+            if tree.dpll_freqs[1].0 != 0 {
+                return Err(PlanError::DpllInUse(1));
+            }
+            if let DpllReference::Gclk(_) = config.reference {
+                if tree.channels[FDPLL1.bits()].is_some() {
+                    return Err(PlanError::ChannelInUse(FDPLL1));
+                }
+            }
+        }
+        for &(id, gclk) in self.channels.iter().flatten() {
+            match tree.channels[id.bits()] {
+                Some(current) if current != gclk => return Err(PlanError::ChannelInUse(id)),
+                _ => (),
+            }
+        }
+
+        for (tree_freq, xosc) in tree.xosc_freqs.iter_mut().zip(self.xoscs.iter()) {
+            if let Some((freq, _)) = xosc {
+                *tree_freq = *freq;
+            }
+        }
+        for (idx, freq) in self.gclk_in_freqs.iter().enumerate() {
+            if freq.0 != 0 {
+                tree.gclk_in_freqs[idx] = *freq;
+            }
+        }
+        // A retuned DPLL0 isn't available to the generators until it has
+        // been resolved, and neither is gclk0 if it runs from it
+        let gclk0_from_dpll0 = tree.gclk_configs[0].filter(|config| config.source == DPLL0);
+        if self.dplls[0].is_some() {
+            tree.dpll_freqs[0] = Hertz(0);
+            if gclk0_from_dpll0.is_some() {
+                tree.gclks[0] = Hertz(0);
+            }
+        }
+
+        let mut steps = [None; NUM_GCLKS + 2];
+        let mut num_steps = 0;
+        let mut pending = self.gclks;
+        let mut pending_dplls = self.dplls;
+
+        // Resolve the generators and the DPLLs in dependency order; each
+        // pass resolves at least one of them, or we are stuck.
+        loop {
+            let mut progress = false;
+            for (idx, slot) in pending_dplls.iter_mut().enumerate() {
+                let config = match *slot {
+                    Some(config) => config,
+                    None => continue,
+                };
+                let ref_freq = config.reference_freq(&tree);
+                if ref_freq.0 == 0 {
+                    continue;
+                }
+                let (ldr, ldrfrac, freq) = config
+                    .solve(ref_freq)
+                    .ok_or(PlanError::InvalidDpll(idx as u8))?;
+                if idx == 0 {
+                    // DPLL0 drives gclk0, and thus the CPU
+                    if freq.0 > OSC120M_FREQ.0 {
+                        return Err(PlanError::CpuTooFast(freq));
+                    }
+                    if let Some(gclk0) = gclk0_from_dpll0 {
+                        tree.gclks[0] = gclk0.divider.divide(freq);
+                    }
+                }
+                if let DpllReference::Gclk(gclk) = config.reference {
+                    tree.channels[if idx == 0 { FDPLL0 } else { FDPLL1 }.bits()] = Some(gclk);
+                }
+                tree.dpll_freqs[idx] = freq;
+                steps[num_steps] = Some(PlanStep::Dpll(idx as u8, ldr, ldrfrac));
+                num_steps += 1;
+                *slot = None;
+                progress = true;
+            }
+            for (idx, slot) in pending.iter_mut().enumerate() {
+                let gclk_plan = match *slot {
+                    Some(gclk_plan) => gclk_plan,
+                    None => continue,
+                };
+                let src_freq = tree.source_freq(gclk_plan.source, idx);
+                if src_freq.0 == 0 {
+                    continue;
+                }
+                let divider = match gclk_plan.target {
+                    GclkTarget::Divider(divider) => (divider as u32).max(1),
+                    GclkTarget::Freq(freq) => {
+                        let freq = freq.0.max(1);
+                        ((src_freq.0 + freq / 2) / freq).max(1)
+                    }
+                };
+                if divider > gclk_max_divider(gclk_plan.gclk) {
+                    return Err(PlanError::DividerOutOfRange(gclk_plan.gclk, divider));
+                }
+                tree.gclks[idx] = Hertz(src_freq.0 / divider);
+                tree.gclk_configs[idx] = Some(
+                    GclkConfig::new(gclk_plan.source)
+                        .divider(divider as u16)
+                        .improve_duty_cycle(self.improve_duty_cycle[idx])
+                        .output_enable(true),
+                );
+                steps[num_steps] = Some(PlanStep::Gclk(gclk_plan.gclk));
+                num_steps += 1;
+                *slot = None;
+                progress = true;
+            }
+            if !progress {
+                break;
+            }
+        }
+        if let Some(gclk_plan) = pending.iter().flatten().next() {
+            return Err(PlanError::SourceUnavailable(gclk_plan.gclk));
+        }
+        if let Some(idx) = pending_dplls.iter().position(|dpll| dpll.is_some()) {
+            return Err(PlanError::InvalidDpll(idx as u8));
+        }
+
+        for &(id, gclk) in self.channels.iter().flatten() {
+            let freq = tree.gclks[u8::from(gclk) as usize];
+            if freq.0 == 0 {
+                return Err(PlanError::GeneratorUnavailable(id));
+            }
+            if freq.0 > peripheral_max_freq(id).0 {
+                return Err(PlanError::PeripheralTooFast(id, freq));
+            }
+            tree.channels[id.bits()] = Some(gclk);
+            tree.token_channels &= !(1 << id.bits());
+        }
+
+        Ok(ClockSolution { tree, steps })
+    }
+}
+
+/// The result of solving a `ClockPlan`: the clock tree once the plan
+/// has been applied.
+#[derive(Clone, Copy)]
+pub struct ClockSolution {
+    tree: ClockTree,
+    steps: [Option<PlanStep>; NUM_GCLKS + 2],
+}
+
+impl ClockSolution {
+    /// Returns the frequency of a clock generator, or `None` if it is
+    /// not running.
+    pub fn gclk_freq(&self, gclk: ClockGenId) -> Option<Hertz> {
+        let freq = self.tree.gclks[u8::from(gclk) as usize];
+        if freq.0 == 0 {
+            None
+        } else {
+            Some(freq)
+        }
+    }
+
+    /// Returns the divider of a clock generator, or `None` if it is
+    /// not running.
+    pub fn gclk_divider(&self, gclk: ClockGenId) -> Option<GclkDivider> {
+        self.tree.gclk_configs[u8::from(gclk) as usize].map(|config| config.divider)
+    }
+
+    /// Returns the output frequency of DPLL0, or `None` if it is not
+    /// running.
+    pub fn dpll0_freq(&self) -> Option<Hertz> {
+        if self.tree.dpll_freqs[0].0 == 0 {
+            None
+        } else {
+            Some(self.tree.dpll_freqs[0])
+        }
+    }
+
+    /// Returns the output frequency of DPLL1, or `None` if it is not
+    /// running.
+    pub fn dpll1_freq(&self) -> Option<Hertz> {
+        if self.tree.dpll_freqs[1].0 == 0 {
+            None
+        } else {
+            Some(self.tree.dpll_freqs[1])
+        }
+    }
+}
+
+/// Returns the largest value the division factor of a clock
+/// generator can hold.
+pub fn gclk_max_divider(gclk: ClockGenId) -> u32 {
+    match gclk {
+        GCLK1 => 0xffff,
+        _ => 0xff,
+    }
+}
+
+/// Returns the highest frequency a peripheral channel may be clocked at.
+pub fn peripheral_max_freq(id: ClockId) -> Hertz {
+    match id {
+        DFLL48 => Hertz(33_000),
+        FDPLL0 | FDPLL1 => DPLL_REF_MAX_FREQ,
+        TC0_TC1 | TC2_TC3 | TC4_TC5 | TC6_TC7 | TCC0_TCC1 | TCC2_TCC3 | TCC4 | PDEC
+        | FREQM_MSR => DPLL_OUT_MAX_FREQ,
+        DAC => Hertz(12_000_000),
+        _ => Hertz(100_000_000),
+    }
+}
+
+/// Selects how an external crystal oscillator (XOSC0/XOSC1) is driven.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XoscMode {
+    /// A crystal is connected between XIN and XOUT.
+    Crystal,
+    /// An external clock signal is applied to XIN; XOUT is
+    /// left free for use as a GPIO.
+    ExternalClock,
+}
+
 /// The frequency of the 48Mhz source.
 pub const OSC48M_FREQ: Hertz = Hertz(48_000_000);
 /// The frequency of the 32Khz source.
 pub const OSC32K_FREQ: Hertz = Hertz(32_000);
 /// The frequency of the 120Mhz source.
 pub const OSC120M_FREQ: Hertz = Hertz(120_000_000);
+/// The exact frequency of the external 32Khz crystal.
+pub const XOSC32K_FREQ: Hertz = Hertz(32_768);
+/// The minimum DPLL reference frequency.
+pub const DPLL_REF_MIN_FREQ: Hertz = Hertz(32_000);
+/// The maximum DPLL reference frequency.
+pub const DPLL_REF_MAX_FREQ: Hertz = Hertz(3_200_000);
+/// The largest XOSC divider of the DPLL reference.
+pub const DPLL_MAX_XOSC_DIV: u32 = 0x7ff;
+/// The minimum DPLL output frequency.
+pub const DPLL_OUT_MIN_FREQ: Hertz = Hertz(96_000_000);
+/// The maximum DPLL output frequency.
+pub const DPLL_OUT_MAX_FREQ: Hertz = Hertz(200_000_000);
 
 
-fn set_flash_to_half_auto_wait_state(nvmctrl: &mut NVMCTRL) {
-    nvmctrl.ctrla.modify(|_, w| w.rws().half());
+/// Returns the number of NVM read wait states the CPU needs when it
+/// runs at `freq`.
+pub fn flash_wait_states(freq: Hertz) -> u8 {
+    match freq.0 {
+        0..=24_000_000 => 0,
+        24_000_001..=51_000_000 => 1,
+        51_000_001..=77_000_000 => 2,
+        77_000_001..=101_000_000 => 3,
+        101_000_001..=119_000_000 => 4,
+        _ => 5,
+    }
+}
+
+/// Programs the NVM read wait states. Automatic wait state generation
+/// is turned off, so that the programmed number is the one in effect.
+fn set_flash_wait_states(nvmctrl: &mut NVMCTRL, wait_states: u8) {
+    nvmctrl.ctrla.modify(|_, w| {
+        // AUTOWS is deliberately left off: the controller knows the exact
+        // gclk0 frequency every time it changes it, including when it is
+        // clocked down, and programs RWS to match. That keeps the number
+        // of wait states in effect the one `flash_wait_states` documents,
+        // and lets it be lowered again along with the clock.
+        w.autows().clear_bit();
+        unsafe { w.rws().bits(wait_states) }
+    });
 }
 
 fn enable_gclk_apb(mclk: &mut MCLK) {
@@ -426,29 +1851,275 @@ fn enable_external_32kosc(osc32kctrl: &mut OSC32KCTRL) {
     while osc32kctrl.status.read().xosc32krdy().bit_is_clear() {}
 }
 
-fn wait_for_dpllrdy(oscctrl: &mut OSCCTRL) {
-    while oscctrl.dpllstatus0.read().lock().bit_is_clear() ||
-        oscctrl.dpllstatus0.read().clkrdy().bit_is_clear() {}
+/// Lock the DFLL48M to the USB start-of-frame packets.
+/// The DFLL48M must not be clocking the CPU while this runs.
+fn configure_dfll48m_usb_crm(oscctrl: &mut OSCCTRL) {
+    // Turn it off while we configure it; this also clears on-demand mode
+    oscctrl.dfllctrla.write(|w| w.enable().clear_bit());
+    while oscctrl.dfllsync.read().enable().bit_is_set() {}
+
+    oscctrl.dfllmul.write(|w| unsafe {
+        w.cstep().bits(1);
+        w.fstep().bits(1);
+        // scaling factor between the 1KHz start-of-frame and 48MHz
+        w.mul().bits((48_000_000u32 / 1000) as u16)
+    });
+    while oscctrl.dfllsync.read().dfllmul().bit_is_set() {}
+
+    oscctrl.dfllctrlb.reset();
+    while oscctrl.dfllsync.read().dfllctrlb().bit_is_set() {}
+
+    oscctrl.dfllctrla.write(|w| w.enable().set_bit());
+    while oscctrl.dfllsync.read().enable().bit_is_set() {}
+
+    // Start out from the factory calibration
+    let dfllval = oscctrl.dfllval.read().bits();
+    oscctrl.dfllval.write(|w| unsafe { w.bits(dfllval) });
+    while oscctrl.dfllsync.read().dfllval().bit_is_set() {}
+
+    oscctrl.dfllctrlb.write(|w| {
+        // closed loop mode
+        w.mode().set_bit();
+
+        // chill cycle disable
+        w.ccdis().set_bit();
+
+        // usb correction
+        w.usbcrm().set_bit()
+    });
+    while oscctrl.dfllsync.read().dfllctrlb().bit_is_set() {}
+
+    while oscctrl.status.read().dfllrdy().bit_is_clear() {}
 }
 
-/// Configure the dpll0 to run at 120MHz
-fn configure_and_enable_dpll0(oscctrl: &mut OSCCTRL, gclk: &mut GCLK) {
-   gclk.pchctrl[FDPLL0 as usize].write(|w| {
-        w.chen().set_bit();
-        w.gen().gclk5()
+/// Turn on one of the external crystal oscillators
+fn enable_xosc(oscctrl: &mut OSCCTRL, index: usize, freq: Hertz, mode: XoscMode) {
+    // Oscillator current settings recommended by the datasheet
+    let (iptat, imult) = match freq.0 {
+        0..=8_000_000 => (2, 3),
+        8_000_001..=16_000_000 => (3, 4),
+        16_000_001..=24_000_000 => (3, 5),
+        _ => (3, 6),
+    };
+    oscctrl.xoscctrl[index].write(|w| {
+        if mode == XoscMode::Crystal {
+            unsafe {
+                w.iptat().bits(iptat);
+                w.imult().bits(imult);
+                // 10 here means: use 1024 cycles of OSCULP32k (~31ms)
+                // to start up this oscillator
+                w.startup().bits(10);
+            }
+            // Automatic loop control, to reduce power consumption
+            w.enalc().set_bit();
+            w.xtalen().set_bit();
+        }
+        w.ondemand().clear_bit();
+        w.enable().set_bit()
     });
+    // Wait for the oscillator to stabilize
+    if index == 0 {
+        while oscctrl.status.read().xoscrdy0().bit_is_clear() {}
+    } else {
+        while oscctrl.status.read().xoscrdy1().bit_is_clear() {}
+    }
+}
+
+/// The registers that control one of the two DPLL instances
+struct DpllRegs<'a> {
+    ctrla: &'a DPLLCTRLA,
+    ratio: &'a DPLLRATIO,
+    ctrlb: &'a DPLLCTRLB,
+    syncbusy: &'a DPLLSYNCBUSY,
+    status: &'a DPLLSTATUS,
+}
+
+fn dpll_regs(oscctrl: &OSCCTRL, index: usize) -> DpllRegs<'_> {
+    if index == 0 {
+        DpllRegs {
+            ctrla: &oscctrl.dpllctrla0,
+            ratio: &oscctrl.dpllratio0,
+            ctrlb: &oscctrl.dpllctrlb0,
+            syncbusy: &oscctrl.dpllsyncbusy0,
+            status: &oscctrl.dpllstatus0,
+        }
+    } else {
+        DpllRegs {
+            ctrla: &oscctrl.dpllctrla1,
+            ratio: &oscctrl.dpllratio1,
+            ctrlb: &oscctrl.dpllctrlb1,
+            syncbusy: &oscctrl.dpllsyncbusy1,
+            status: &oscctrl.dpllstatus1,
+        }
+    }
+}
+
+/// Configure and enable a dpll, then wait for it to lock
+fn configure_and_enable_dpll(regs: &DpllRegs, config: &DpllConfig, ldr: u16, ldrfrac: u8) {
+    regs.ctrla.write(|w| w.enable().clear_bit());
+    while regs.syncbusy.read().enable().bit_is_set() {}
+
     unsafe {
-        oscctrl.dpllratio0.write(|w| {
-            w.ldr().bits(59);
-            w.ldrfrac().bits(0)
+        regs.ratio.write(|w| {
+            w.ldr().bits(ldr);
+            w.ldrfrac().bits(ldrfrac)
         });
     }
-    oscctrl.dpllctrlb0.write(|w| {
-        w.refclk().gclk()
+    while regs.syncbusy.read().dpllratio().bit_is_set() {}
+
+    regs.ctrlb.write(|w| {
+        match config.reference {
+            DpllReference::Xosc32k => w.refclk().xosc32(),
+            DpllReference::Xosc0 { div } => {
+                unsafe {
+                    w.div().bits(div);
+                }
+                w.refclk().xosc0()
+            }
+            DpllReference::Xosc1 { div } => {
+                unsafe {
+                    w.div().bits(div);
+                }
+                w.refclk().xosc1()
+            }
+            DpllReference::Gclk(_) => w.refclk().gclk(),
+        };
+        unsafe {
+            w.filter().bits(config.filter);
+        }
+        w.lbypass().bit(config.lock_bypass)
     });
-    oscctrl.dpllctrla0.write(|w| {
+    regs.ctrla.write(|w| {
         w.enable().set_bit();
         w.ondemand().clear_bit()
     });
+    while regs.syncbusy.read().enable().bit_is_set() {}
 
+    while regs.status.read().lock().bit_is_clear()
+        || regs.status.read().clkrdy().bit_is_clear()
+    {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider_limits() {
+        let plan = ClockPlan::with_internal_32kosc().gclk(GCLK2, DFLL, Hertz(100_000));
+        assert_eq!(plan.solve().err(), Some(PlanError::DividerOutOfRange(GCLK2, 480)));
+
+        let plan = ClockPlan::with_internal_32kosc().gclk_divider(GCLK3, DFLL, 256);
+        assert_eq!(plan.solve().err(), Some(PlanError::DividerOutOfRange(GCLK3, 256)));
+
+        let plan = ClockPlan::with_internal_32kosc().gclk_divider(GCLK3, DFLL, 255);
+        let solution = plan.solve().unwrap();
+        assert_eq!(solution.gclk_freq(GCLK3), Some(Hertz(48_000_000 / 255)));
+    }
+
+    #[test]
+    fn improve_duty_cycle_is_planned() {
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk_divider(GCLK3, DFLL, 3)
+            .gclk_divider(GCLK4, DFLL, 3)
+            .improve_duty_cycle(GCLK4, true);
+        let tree = plan.solve().unwrap().tree;
+        assert!(!tree.gclk_configs[3].unwrap().improve_duty_cycle);
+        assert!(tree.gclk_configs[4].unwrap().improve_duty_cycle);
+    }
+
+    #[test]
+    fn peripheral_max_freq_is_checked() {
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk(GCLK3, DFLL, Hertz(48_000_000))
+            .channel(DAC, GCLK3);
+        assert_eq!(
+            plan.solve().err(),
+            Some(PlanError::PeripheralTooFast(DAC, Hertz(48_000_000)))
+        );
+
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk(GCLK3, DFLL, Hertz(12_000_000))
+            .channel(DAC, GCLK3);
+        assert_eq!(plan.solve().unwrap().gclk_freq(GCLK3), Some(Hertz(12_000_000)));
+    }
+
+    #[test]
+    fn unavailable_generator() {
+        let plan = ClockPlan::with_internal_32kosc().channel(SERCOM0_CORE, GCLK4);
+        assert_eq!(
+            plan.solve().err(),
+            Some(PlanError::GeneratorUnavailable(SERCOM0_CORE))
+        );
+
+        // A generator configured outside of the plan can be used
+        let mut tree = ClockTree::new(false);
+        tree.gclks[4] = Hertz(48_000_000);
+        tree.gclk_configs[4] = Some(GclkConfig::new(DFLL));
+        let solution = plan.solve_from(tree).unwrap();
+        assert_eq!(solution.gclk_freq(GCLK4), Some(Hertz(48_000_000)));
+
+        // but not planned a second time
+        let plan = ClockPlan::with_internal_32kosc().gclk(GCLK4, DFLL, Hertz(1_000_000));
+        assert_eq!(
+            plan.solve_from(tree).err(),
+            Some(PlanError::AlreadyConfigured(GCLK4))
+        );
+    }
+
+    #[test]
+    fn cpu_too_fast() {
+        let plan = ClockPlan::with_internal_32kosc().dpll0(DpllConfig::with_output_freq(
+            DpllReference::Gclk(GCLK5),
+            Hertz(150_000_000),
+        ));
+        assert_eq!(
+            plan.solve().err(),
+            Some(PlanError::CpuTooFast(Hertz(150_000_000)))
+        );
+
+        let plan = ClockPlan::with_internal_32kosc().dpll0(DpllConfig::with_output_freq(
+            DpllReference::Gclk(GCLK5),
+            Hertz(100_000_000),
+        ));
+        let solution = plan.solve().unwrap();
+        assert_eq!(solution.gclk_freq(GCLK0), Some(Hertz(100_000_000)));
+    }
+
+    #[test]
+    fn dpll0_in_use() {
+        // gclk2 runs from DPLL0, so it can't be retuned
+        let plan = ClockPlan::with_internal_32kosc()
+            .gclk(GCLK2, DPLL0, Hertz(60_000_000))
+            .dpll0(DpllConfig::with_output_freq(
+                DpllReference::Gclk(GCLK5),
+                Hertz(100_000_000),
+            ));
+        let solution = plan.solve().unwrap();
+        assert_eq!(solution.gclk_freq(GCLK2), Some(Hertz(50_000_000)));
+        let plan = ClockPlan::with_internal_32kosc().dpll0(DpllConfig::with_output_freq(
+            DpllReference::Gclk(GCLK5),
+            Hertz(100_000_000),
+        ));
+        assert_eq!(plan.solve_from(solution.tree).err(), Some(PlanError::DpllInUse(0)));
+
+        // and neither can it when a peripheral runs from gclk0
+        let mut tree = ClockTree::new(false);
+        tree.channels[SERCOM0_CORE.bits()] = Some(GCLK0);
+        assert_eq!(plan.solve_from(tree).err(), Some(PlanError::DpllInUse(0)));
+    }
+
+    #[test]
+    fn dpll_xosc_divider() {
+        let config = DpllConfig::with_ratio(DpllReference::Xosc0 { div: 0x800 }, 3124, 0);
+        assert!(config.solve(Hertz(32_000)).is_none());
+        let config = DpllConfig::with_ratio(DpllReference::Xosc0 { div: 0x7ff }, 3124, 0);
+        assert!(config.solve(Hertz(32_000)).is_some());
+    }
+
+    #[test]
+    fn gclk1_runs_from_the_32k_oscillator() {
+        assert_eq!(ClockTree::new(true).gclks[1], XOSC32K_FREQ);
+        assert_eq!(ClockTree::new(false).gclks[1], OSC32K_FREQ);
+    }
 }
@@ -0,0 +1,82 @@
+//! Frequency meter
+//!
+//! The FREQM peripheral counts the periods of the measured clock (the
+//! `FREQM_MSR` peripheral channel) during a number of periods of the
+//! reference clock (the `FREQM_REF` peripheral channel).
+//! This can be used to verify that an oscillator or PLL really runs at
+//! its nominal frequency, or to measure an external signal fed in through
+//! the GCLK_IO pin of a clock generator.
+use crate::clock::{FreqmMsrClock, FreqmRefClock, GClock, GenericClockController};
+use crate::target_device::{FREQM, MCLK};
+use crate::time::Hertz;
+
+/// The frequency meter
+pub struct FrequencyMeter<'a> {
+    freqm: FREQM,
+    reference: FreqmRefClock<'a>,
+    refnum: u8,
+}
+
+impl<'a> FrequencyMeter<'a> {
+    /// Enables the frequency meter.
+    /// Each measurement lasts `refnum` periods of the reference clock;
+    /// longer measurements are more accurate, as the result is
+    /// quantized to `reference / refnum`. A `refnum` of 0 is treated
+    /// as 1.
+    /// The reference clock must be running, otherwise measurements
+    /// never complete; the meter holds on to its clock token until it
+    /// is freed.
+    pub fn new(mclk: &mut MCLK, freqm: FREQM, reference: FreqmRefClock<'a>, refnum: u8) -> Self {
+        mclk.apbamask.modify(|_, w| w.freqm_().set_bit());
+
+        freqm.ctrla.write(|w| w.swrst().set_bit());
+        while freqm.syncbusy.read().swrst().bit_is_set() {}
+
+        let refnum = refnum.max(1);
+        freqm.cfga.write(|w| unsafe { w.refnum().bits(refnum) });
+        freqm.ctrla.write(|w| w.enable().set_bit());
+        while freqm.syncbusy.read().enable().bit_is_set() {}
+
+        Self {
+            freqm,
+            reference,
+            refnum,
+        }
+    }
+
+    /// Measures the frequency of the clock generator that the
+    /// `FREQM_MSR` channel is routed to.
+    /// Returns `None` if the measured clock overflowed the counter,
+    /// which happens when it is more than about 2^24 / `refnum` times
+    /// faster than the reference. A stopped clock measures as 0Hz.
+    pub fn measure(&mut self, _msr: &FreqmMsrClock) -> Option<Hertz> {
+        self.freqm.status.write(|w| w.ovf().set_bit());
+        self.freqm.intflag.write(|w| w.done().set_bit());
+        self.freqm.ctrlb.write(|w| w.start().set_bit());
+        while self.freqm.intflag.read().done().bit_is_clear() {}
+
+        if self.freqm.status.read().ovf().bit_is_set() {
+            return None;
+        }
+        let value = self.freqm.value.read().value().bits() as u64;
+        let freq = value * self.reference.freq().0 as u64 / self.refnum as u64;
+        Some(Hertz(freq as u32))
+    }
+
+    /// Measures the frequency of a clock generator.
+    /// The `FREQM_MSR` channel is routed to the generator for the duration
+    /// of the measurement, after which its previous routing is restored.
+    /// Returns `None` if a `FreqmMsrClock` token is alive, or if the
+    /// measurement overflowed.
+    pub fn measure_gclk(&mut self, clocks: &mut GenericClockController, gclk: &GClock) -> Option<Hertz> {
+        clocks.with_freqm_msr(gclk, |msr| self.measure(msr))?
+    }
+
+    /// Disables the frequency meter and returns the underlying peripheral
+    /// and the reference clock token
+    pub fn free(self) -> (FREQM, FreqmRefClock<'a>) {
+        self.freqm.ctrla.write(|w| w.enable().clear_bit());
+        while self.freqm.syncbusy.read().enable().bit_is_set() {}
+        (self.freqm, self.reference)
+    }
+}
@@ -1,5 +1,9 @@
+pub mod capture;
 pub mod clock;
+pub mod eic;
+pub mod freqm;
 pub mod pwm;
+pub mod rtc;
 pub mod sercom;
 pub mod timer;
 pub mod trng;
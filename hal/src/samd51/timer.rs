@@ -1,28 +1,32 @@
 //! Working with timer counter hardware
 use crate::hal::timer::{CountDown, Periodic};
-use crate::target_device::tc0::COUNT16;
+use crate::target_device::tc0::{COUNT16, COUNT32};
 #[allow(unused)]
-use crate::target_device::{MCLK, TC2, TC3};
+use crate::target_device::{MCLK, TC0, TC1, TC2, TC3};
 
 // Only the G variants are missing these timers
 #[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
 use crate::target_device::{TC4, TC5};
 
-use crate::clock;
+use crate::clock::{self, GenericClockController};
+use crate::delay::InterruptDrivenTimer;
+use crate::monotonic::{Duration, Instant, TimeBase};
 use nb;
-use crate::time::Hertz;
+use crate::time::{cmp_ratios, Hertz, Period};
+use core::cmp::Ordering;
 use void::Void;
 
 // Note:
-// TC3 + TC4 can be paired to make a 32-bit counter
-// TC5 + TC6 can be paired to make a 32-bit counter
+// TC0 + TC1 can be paired to make a 32-bit counter
+// TC2 + TC3 can be paired to make a 32-bit counter
+// TC4 + TC5 can be paired to make a 32-bit counter
 
 /// A generic hardware timer counter.
-/// The counters are exposed in 16-bit mode only.
-/// The hardware allows configuring the 8-bit mode
-/// and pairing up some instances to run in 32-bit
-/// mode, but that functionality is not currently
-/// exposed by this hal implementation.
+/// The counters are exposed in 16-bit mode.
+/// The hardware also allows configuring the 8-bit mode,
+/// but that functionality is not currently exposed by this
+/// hal implementation. Pairs of counters running in 32-bit
+/// mode are exposed as `TimerCounter32`.
 /// TimerCounter implements both the `Periodic` and
 /// the `CountDown` embedded_hal timer traits.
 /// Before a hardware timer can be used, it must first
@@ -30,6 +34,33 @@ use void::Void;
 pub struct TimerCounter<TC> {
     freq: Hertz,
     tc: TC,
+    oneshot: bool,
+    direction: Direction,
+}
+
+/// The direction a timer counter counts in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// The compare channels of a timer counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareChannel {
+    C0,
+    C1,
+}
+
+/// The interrupt flags of a timer counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerFlag {
+    /// The counter wrapped around at the end of a period
+    Overflow,
+    /// The counter matched compare channel 0
+    Match0,
+    /// The counter matched compare channel 1
+    Match1,
 }
 
 /// This is a helper trait to make it easier to make most of the
@@ -44,15 +75,77 @@ impl<TC> CountDown for TimerCounter<TC>
 where
     TC: Count16,
 {
-    type Time = Hertz;
+    type Time = Period;
 
     fn start<T>(&mut self, timeout: T)
+    where
+        T: Into<Period>,
+    {
+        // `CountDown` has no way to report a timeout that the counter
+        // can't reach, so clamp it; `try_start` reports it instead.
+        let params = TimerParams::new_saturating(timeout, self.freq.0, MAX_CYCLES_16);
+        self.configure(params.divider, Some(params.top() as u16));
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        let count = self.tc.count_16();
+        if count.intflag.read().ovf().bit_is_set() {
+            // Writing a 1 clears the flag
+            count.intflag.write(|w| w.ovf().set_bit());
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<TC> InterruptDrivenTimer for TimerCounter<TC>
+where
+    TC: Count16,
+{
+    fn enable_interrupt(&mut self) {
+        TimerCounter::enable_interrupt(self)
+    }
+
+    fn disable_interrupt(&mut self) {
+        TimerCounter::disable_interrupt(self)
+    }
+}
+
+impl<TC> TimerCounter<TC>
+where
+    TC: Count16,
+{
+    /// Starts the timer like `CountDown::start`, but fails instead of
+    /// clamping when the counter can't reach the timeout.
+    /// Returns the period the timer actually runs at, which differs from
+    /// the requested one when it isn't a whole number of prescaled clock
+    /// ticks.
+    pub fn try_start<T>(&mut self, timeout: T) -> Result<Period, TimerParamsError>
+    where
+        T: Into<Period>,
+    {
+        let params = TimerParams::new(timeout, self.freq.0)?;
+        self.configure(params.divider, Some(params.top() as u16));
+        Ok(params.period)
+    }
+
+    /// Starts the timer counting through its whole 16-bit range, leaving
+    /// both compare channels free to schedule deadlines with.
+    /// `resolution` is the desired tick rate; the closest rate the
+    /// prescaler can produce at or below it is used, and returned.
+    pub fn start_free_running<T>(&mut self, resolution: T) -> Hertz
     where
         T: Into<Hertz>,
     {
-        let params = TimerParams::new(timeout, self.freq.0);
-        let divider = params.divider;
-        let cycles = params.cycles;
+        let divider = divider_for(self.freq.0, resolution.into());
+        self.configure(divider, None);
+        Hertz(self.freq.0 / divider as u32)
+    }
+
+    /// (Re)starts the counter in match frequency mode with `top` as its
+    /// TOP value, or free running without one
+    fn configure(&mut self, divider: u16, top: Option<u16>) {
         let count = self.tc.count_16();
 
         // Disable the timer while we reconfigure it
@@ -68,19 +161,25 @@ where
         // need to manually read the bit here
         while count.ctrla.read().bits() & 1 != 0 {}
 
+        let direction = self.direction;
+        let oneshot = self.oneshot;
         count.ctrlbset.write(|w| {
             // Count up when the direction bit is zero
-            w.dir().clear_bit();
-            // Periodic
-            w.oneshot().clear_bit()
+            w.dir().bit(direction == Direction::Down);
+            // Periodic, unless one-shot was asked for
+            w.oneshot().bit(oneshot)
         });
 
         // Set TOP value for mfrq mode
-        count.cc[0].write(|w| unsafe { w.cc().bits(cycles as u16) });
+        if let Some(top) = top {
+            count.cc[0].write(|w| unsafe { w.cc().bits(top) });
+        }
 
-        // Enable Match Frequency Waveform generation
-        count.wave.modify(|_, w| {
-            w.wavegen().mfrq()
+        // Enable Match Frequency Waveform generation, or count
+        // through the whole range when free running
+        count.wave.modify(|_, w| match top {
+            Some(_) => w.wavegen().mfrq(),
+            None => w.wavegen().nfrq(),
         });
 
         count.ctrla.modify(|_, w| {
@@ -99,22 +198,108 @@ where
         });
     }
 
-    fn wait(&mut self) -> nb::Result<(), Void> {
+    /// Makes the timer stop at the end of the period instead of wrapping
+    /// around; `retrigger` starts it again. Also applies to later calls
+    /// to `start`.
+    pub fn set_oneshot(&mut self, oneshot: bool) {
+        self.oneshot = oneshot;
         let count = self.tc.count_16();
-        if count.intflag.read().ovf().bit_is_set() {
-            // Writing a 1 clears the flag
-            count.intflag.modify(|_, w| w.ovf().set_bit());
-            Ok(())
+        if oneshot {
+            count.ctrlbset.write(|w| w.oneshot().set_bit());
         } else {
-            Err(nb::Error::WouldBlock)
+            count.ctrlbclr.write(|w| w.oneshot().set_bit());
         }
+        while count.syncbusy.read().ctrlb().bit_is_set() {}
+    }
+
+    /// Sets the direction the timer counts in. Counting down, a period
+    /// runs from TOP down to zero. Also applies to later calls to `start`.
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+        let count = self.tc.count_16();
+        match direction {
+            Direction::Up => count.ctrlbclr.write(|w| w.dir().set_bit()),
+            Direction::Down => count.ctrlbset.write(|w| w.dir().set_bit()),
+        }
+        while count.syncbusy.read().ctrlb().bit_is_set() {}
+    }
+
+    /// Stops the timer; `retrigger` starts it again
+    pub fn stop(&mut self) {
+        let count = self.tc.count_16();
+        count.ctrlbset.write(|w| w.cmd().stop());
+        while count.syncbusy.read().ctrlb().bit_is_set() {}
+    }
+
+    /// Restarts the current period from the beginning, starting the timer
+    /// if it was stopped
+    pub fn retrigger(&mut self) {
+        let count = self.tc.count_16();
+        count.ctrlbset.write(|w| w.cmd().retrigger());
+        while count.syncbusy.read().ctrlb().bit_is_set() {}
+    }
+
+    /// Reads the current value of the counter
+    pub fn count(&self) -> u16 {
+        let count = self.tc.count_16();
+        // COUNT has to be synchronized from the timer's clock domain
+        // before it can be read
+        count.ctrlbset.write(|w| w.cmd().readsync());
+        while count.syncbusy.read().ctrlb().bit_is_set() {}
+        while count.syncbusy.read().count().bit_is_set() {}
+        count.count.read().count().bits()
+    }
+
+    /// Sets the value compare channel `channel` matches on.
+    /// Outside of `start_free_running`, compare channel 0 holds the TOP
+    /// value that sets the period, so only channel 1 is free.
+    pub fn set_compare(&mut self, channel: CompareChannel, value: u16) {
+        let count = self.tc.count_16();
+        count.cc[channel as usize].write(|w| unsafe { w.cc().bits(value) });
+        while count.syncbusy.read().bits() != 0 {}
+    }
+
+    /// Enables the interrupt for `flag`.
+    /// This method only sets the clock configuration to trigger
+    /// the interrupt; it does not configure the interrupt controller
+    /// or define an interrupt handler.
+    pub fn enable_flag_interrupt(&mut self, flag: TimerFlag) {
+        self.tc.count_16().intenset.write(|w| match flag {
+            TimerFlag::Overflow => w.ovf().set_bit(),
+            TimerFlag::Match0 => w.mc0().set_bit(),
+            TimerFlag::Match1 => w.mc1().set_bit(),
+        });
+    }
+
+    /// Disables the interrupt for `flag`
+    pub fn disable_flag_interrupt(&mut self, flag: TimerFlag) {
+        self.tc.count_16().intenclr.write(|w| match flag {
+            TimerFlag::Overflow => w.ovf().set_bit(),
+            TimerFlag::Match0 => w.mc0().set_bit(),
+            TimerFlag::Match1 => w.mc1().set_bit(),
+        });
+    }
+
+    /// Whether `flag` is set
+    pub fn flag(&self, flag: TimerFlag) -> bool {
+        let flags = self.tc.count_16().intflag.read();
+        match flag {
+            TimerFlag::Overflow => flags.ovf().bit_is_set(),
+            TimerFlag::Match0 => flags.mc0().bit_is_set(),
+            TimerFlag::Match1 => flags.mc1().bit_is_set(),
+        }
+    }
+
+    /// Clears `flag`
+    pub fn clear_flag(&mut self, flag: TimerFlag) {
+        // Writing a 1 clears the flag
+        self.tc.count_16().intflag.write(|w| match flag {
+            TimerFlag::Overflow => w.ovf().set_bit(),
+            TimerFlag::Match0 => w.mc0().set_bit(),
+            TimerFlag::Match1 => w.mc1().set_bit(),
+        });
     }
-}
 
-impl<TC> TimerCounter<TC>
-where
-    TC: Count16,
-{
     /// Enable the interrupt generation for this hardware timer.
     /// This method only sets the clock configuration to trigger
     /// the interrupt; it does not configure the interrupt controller
@@ -164,6 +349,8 @@ impl TimerCounter<$TC>
         Self {
             freq: clock.freq(),
             tc,
+            oneshot: false,
+            direction: Direction::Up,
         }
     }
 }
@@ -171,49 +358,495 @@ impl TimerCounter<$TC>
     }
 }
 
-/// Helper type for computing cycles and divider given frequency
+/// A pair of hardware timer counters running as a single 32-bit counter.
+/// The even numbered counter is the master; its registers control the
+/// pair, while the odd numbered slave counter is owned but otherwise
+/// left alone. The pair shares a single clock.
+/// With 32 bits the counter covers long timeouts without having to
+/// resort to a coarse prescaler.
+/// TimerCounter32 implements both the `Periodic` and
+/// the `CountDown` embedded_hal timer traits.
+pub struct TimerCounter32<MASTER, SLAVE> {
+    freq: Hertz,
+    tc: MASTER,
+    _slave: SLAVE,
+}
+
+/// This is a helper trait to make it easier to make most of the
+/// TimerCounter32 impl generic.  It doesn't make too much sense to
+/// to try to implement this trait outside of this module.
+pub trait Count32 {
+    fn count_32(&self) -> &COUNT32;
+}
+
+impl<MASTER, SLAVE> Periodic for TimerCounter32<MASTER, SLAVE> {}
+impl<MASTER, SLAVE> CountDown for TimerCounter32<MASTER, SLAVE>
+where
+    MASTER: Count32,
+{
+    type Time = Period;
+
+    fn start<T>(&mut self, timeout: T)
+    where
+        T: Into<Period>,
+    {
+        // `CountDown` has no way to report a timeout that the counter
+        // can't reach, so clamp it; `try_start` reports it instead.
+        let params = TimerParams::new_saturating(timeout, self.freq.0, MAX_CYCLES_32);
+        self.configure(params.divider, Some(params.top()));
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        let count = self.tc.count_32();
+        if count.intflag.read().ovf().bit_is_set() {
+            // Writing a 1 clears the flag
+            count.intflag.write(|w| w.ovf().set_bit());
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<MASTER, SLAVE> InterruptDrivenTimer for TimerCounter32<MASTER, SLAVE>
+where
+    MASTER: Count32,
+{
+    fn enable_interrupt(&mut self) {
+        TimerCounter32::enable_interrupt(self)
+    }
+
+    fn disable_interrupt(&mut self) {
+        TimerCounter32::disable_interrupt(self)
+    }
+}
+
+impl<MASTER, SLAVE> TimerCounter32<MASTER, SLAVE>
+where
+    MASTER: Count32,
+{
+    /// Starts the timer like `CountDown::start`, but fails instead of
+    /// clamping when the counter can't reach the timeout.
+    /// Returns the period the timer actually runs at.
+    pub fn try_start<T>(&mut self, timeout: T) -> Result<Period, TimerParamsError>
+    where
+        T: Into<Period>,
+    {
+        let params = TimerParams::new_32bit(timeout, self.freq.0)?;
+        self.configure(params.divider, Some(params.top()));
+        Ok(params.period)
+    }
+
+    /// Starts the timer counting through its whole 32-bit range.
+    /// `resolution` is the desired tick rate; the closest rate the
+    /// prescaler can produce at or below it is used, and returned.
+    pub fn start_free_running<T>(&mut self, resolution: T) -> Hertz
+    where
+        T: Into<Hertz>,
+    {
+        let divider = divider_for(self.freq.0, resolution.into());
+        self.configure(divider, None);
+        Hertz(self.freq.0 / divider as u32)
+    }
+
+    /// Reads the current value of the counter
+    pub fn count(&self) -> u32 {
+        read_count32(self.tc.count_32())
+    }
+
+    /// (Re)starts the counter in match frequency mode with `top` as its
+    /// TOP value, or free running without one
+    fn configure(&mut self, divider: u16, top: Option<u32>) {
+        let count = self.tc.count_32();
+
+        // Disable the timer while we reconfigure it
+        count.ctrla.modify(|_, w| w.enable().clear_bit());
+        while count.syncbusy.read().enable().bit_is_set() {}
+
+        // Now that we have a clock routed to the peripheral, we
+        // can ask it to perform a reset.
+        count.ctrla.write(|w| w.swrst().set_bit());
+        while count.syncbusy.read().swrst().bit_is_set() {}
+
+        count.ctrlbset.write(|w| {
+            // Count up when the direction bit is zero
+            w.dir().clear_bit();
+            // Periodic
+            w.oneshot().clear_bit()
+        });
+
+        // Set TOP value for mfrq mode
+        if let Some(top) = top {
+            count.cc[0].write(|w| unsafe { w.cc().bits(top) });
+        }
+
+        // Enable Match Frequency Waveform generation, or count
+        // through the whole range when free running
+        count.wave.modify(|_, w| match top {
+            Some(_) => w.wavegen().mfrq(),
+            None => w.wavegen().nfrq(),
+        });
+
+        count.ctrla.modify(|_, w| {
+            w.mode().count32();
+            match divider {
+                1 => w.prescaler().div1(),
+                2 => w.prescaler().div2(),
+                4 => w.prescaler().div4(),
+                8 => w.prescaler().div8(),
+                16 => w.prescaler().div16(),
+                64 => w.prescaler().div64(),
+                256 => w.prescaler().div256(),
+                1024 => w.prescaler().div1024(),
+                _ => unreachable!(),
+            };
+            w.enable().set_bit()
+        });
+    }
+
+    /// Enable the interrupt generation for this hardware timer.
+    /// This method only sets the clock configuration to trigger
+    /// the interrupt; it does not configure the interrupt controller
+    /// or define an interrupt handler.
+    /// The interrupt is raised by the master counter.
+    pub fn enable_interrupt(&mut self) {
+        self.tc.count_32().intenset.write(|w| w.ovf().set_bit());
+    }
+
+    /// Disables interrupt generation for this hardware timer.
+    /// This method only sets the clock configuration to prevent
+    /// triggering the interrupt; it does not configure the interrupt
+    /// controller.
+    pub fn disable_interrupt(&mut self) {
+        self.tc.count_32().intenclr.write(|w| w.ovf().set_bit());
+    }
+}
+
+macro_rules! tc32 {
+    ($($TYPE:ident: ($MASTER:ident, $SLAVE:ident, $ctor:ident, $mclk_master:ident, $mclk_slave:ident, $clock:ident, $apmask:ident),)+) => {
+        $(
+pub type $TYPE = TimerCounter32<$MASTER, $SLAVE>;
+
+impl Count32 for $MASTER {
+    fn count_32(&self) -> &COUNT32 {
+        self.count32()
+    }
+}
+
+impl TimerCounter32<$MASTER, $SLAVE>
+{
+    /// Configure this pair of timer counter instances as a 32-bit counter.
+    /// The clock is obtained from the `GenericClockController` instance
+    /// and its frequency impacts the resolution and maximum range of
+    /// the timeout values that can be passed to the `start` method.
+    pub fn $ctor(clock: &clock::$clock, master: $MASTER, slave: $SLAVE, mclk: &mut MCLK) -> Self {
+        // both halves of the pair need their bus clock
+        mclk.$apmask.modify(|_, w| {
+            w.$mclk_master().set_bit();
+            w.$mclk_slave().set_bit()
+        });
+        {
+            let count = master.count_32();
+
+            // Disable the timer while we reconfigure it
+            count.ctrla.modify(|_, w| w.enable().clear_bit());
+            while count.syncbusy.read().enable().bit_is_set() {}
+        }
+        Self {
+            freq: clock.freq(),
+            tc: master,
+            _slave: slave,
+        }
+    }
+}
+        )+
+    }
+}
+
+/// Reads COUNT, which has to be synchronized from the timer's clock
+/// domain first
+fn read_count32(count: &COUNT32) -> u32 {
+    count.ctrlbset.write(|w| w.cmd().readsync());
+    while count.syncbusy.read().ctrlb().bit_is_set() {}
+    while count.syncbusy.read().count().bit_is_set() {}
+    count.count.read().count().bits()
+}
+
+macro_rules! tc_monotonic {
+    ($($TYPE:ident: ($MASTER:ident, $SLAVE:ident, $TIME_BASE:ident),)+) => {
+        $(
+
+static $TIME_BASE: TimeBase = TimeBase::new();
+
+/// A monotonic time base on a free running pair of timer counters.
+/// See the `monotonic` module; the interrupt handler of the master
+/// counter has to call `on_interrupt`.
+pub enum $TYPE {}
+
+impl $TYPE {
+    /// Starts the pair of timer counters as the time base, counting up
+    /// from zero. `resolution` is the desired tick rate; the closest
+    /// rate the prescaler can produce at or below it is used, and
+    /// returned. The overflow interrupt is enabled, but the interrupt
+    /// controller isn't configured.
+    pub fn start<T>(
+        mut timer: TimerCounter32<$MASTER, $SLAVE>,
+        resolution: T,
+        clocks: &mut GenericClockController,
+    ) -> Hertz
+    where
+        T: Into<Hertz>,
+    {
+        let freq = timer.start_free_running(resolution);
+        timer.enable_interrupt();
+        $TIME_BASE.set_freq(freq, clocks.gclk_freq(clock::ClockGenId::GCLK0));
+        $TIME_BASE.reset(|| ());
+        freq
+    }
+
+    /// Returns the current time
+    pub fn now() -> Instant {
+        $TIME_BASE.now(
+            || read_count32(Self::count_32()),
+            || {
+                let intflag = &Self::count_32().intflag;
+                let overflowed = intflag.read().ovf().bit_is_set();
+                if overflowed {
+                    // Writing a 1 clears the flag
+                    intflag.write(|w| w.ovf().set_bit());
+                }
+                overflowed
+            },
+        )
+    }
+
+    /// Counts an overflow of the timer; call this from the interrupt
+    /// handler of the master counter
+    pub fn on_interrupt() {
+        Self::now();
+    }
+
+    /// The tick rate of the time base
+    pub fn freq() -> Hertz {
+        $TIME_BASE.freq()
+    }
+
+    /// Converts a period in milliseconds to ticks of the time base
+    pub fn millis(ms: u32) -> Duration {
+        $TIME_BASE.millis(ms)
+    }
+
+    /// Converts a period in microseconds to ticks of the time base
+    pub fn micros(us: u32) -> Duration {
+        $TIME_BASE.micros(us)
+    }
+
+    fn count_32() -> &'static COUNT32 {
+        // The time base owns the timer from `start` on
+        unsafe { &*$MASTER::ptr() }.count32()
+    }
+}
+
+#[cfg(feature = "rtfm")]
+impl rtfm::Monotonic for $TYPE {
+    type Instant = Instant;
+
+    fn ratio() -> rtfm::Fraction {
+        let (numerator, denominator) = $TIME_BASE.ratio();
+        rtfm::Fraction {
+            numerator,
+            denominator,
+        }
+    }
+
+    fn now() -> Instant {
+        $TYPE::now()
+    }
+
+    unsafe fn reset() {
+        $TIME_BASE.reset(|| {
+            let count = Self::count_32();
+            count.count.write(|w| w.count().bits(0));
+            while count.syncbusy.read().count().bit_is_set() {}
+            count.intflag.write(|w| w.ovf().set_bit());
+        });
+    }
+
+    fn zero() -> Instant {
+        Instant::from_ticks(0)
+    }
+}
+        )+
+    }
+}
+
+/// The number of cycles in the longest period of a 16-bit counter
+pub(crate) const MAX_CYCLES_16: u32 = 0x1_0000;
+/// The number of cycles in the longest period of a 32-bit counter.
+/// That's one short of the hardware limit, which doesn't fit a `u32`.
+pub(crate) const MAX_CYCLES_32: u32 = 0xffff_ffff;
+
+/// The prescaler settings supported by the timer counters
+pub(crate) const DIVIDERS: [u16; 8] = [1, 2, 4, 8, 16, 64, 256, 1024];
+
+/// The reasons a timer counter can't produce a requested frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerParamsError {
+    /// A frequency of 0Hz was requested
+    ZeroFrequency,
+    /// The requested period is shorter than a tick of the clock of the
+    /// counter
+    TooFast,
+    /// The period doesn't fit the counter, even with the largest prescaler
+    TooSlow,
+}
+
+/// Picks the prescaler giving the fastest tick at or below `resolution`
+pub(crate) fn divider_for(src_freq: u32, resolution: Hertz) -> u16 {
+    DIVIDERS
+        .iter()
+        .cloned()
+        .find(|&divider| src_freq / divider as u32 <= resolution.0)
+        .unwrap_or(1024)
+}
+
+/// Helper type for computing cycles and divider given a period or a
+/// frequency.
+/// `cycles` is the number of prescaled clock ticks in one period, so
+/// the counter is programmed with a TOP value of `cycles - 1`.
 #[derive(Debug, Clone, Copy)]
 pub struct TimerParams {
     pub divider: u16,
     pub cycles: u32,
+    /// The frequency these parameters achieve, rounded to the nearest Hz.
+    /// Periods longer than 2s round to 0Hz; `period` is exact.
+    pub freq: Hertz,
+    /// The period these parameters achieve
+    pub period: Period,
 }
 
 impl TimerParams {
-    pub fn new<T> (timeout: T, src_freq: u32) -> Self
+    /// Computes the parameters for a 16-bit counter.
+    /// The smallest prescaler that fits the period is used, as that
+    /// gives the finest resolution; the period is rounded to the
+    /// nearest tick.
+    pub fn new<T>(timeout: T, src_freq: u32) -> Result<Self, TimerParamsError>
     where
-        T: Into<Hertz>,
+        T: Into<Period>,
     {
-        let timeout = timeout.into();
-        let ticks: u32 = src_freq/timeout.0.max(1);
-        let divider = ((ticks >> 16) + 1).next_power_of_two();
-        let divider = match divider {
-            1 | 2 | 4 | 8 | 16 | 64 | 256 | 1024 => divider,
-            // There are a couple of gaps, so we round up to the next largest
-            // divider; we'll need to count twice as many but it will work.
-            32 => 64,
-            128 => 256,
-            512 => 1024,
-            // Catch all case; this is lame.  Would be great to detect this
-            // and fail at compile time.
-            _ => 1024,
-        };
-
-        let cycles: u32 = ticks / divider as u32;
-
-        if cycles > u16::max_value() as u32 {
-            panic!(
-                "cycles {} is out of range for a 16 bit counter (timeout={})",
-                cycles, timeout.0
-            );
+        Self::solve(timeout.into(), src_freq, MAX_CYCLES_16, false)
+    }
+
+    /// Computes the parameters for a 16-bit counter, trying every
+    /// prescaler and keeping the one that gets closest to the requested
+    /// period or frequency.
+    pub fn new_exact<T>(timeout: T, src_freq: u32) -> Result<Self, TimerParamsError>
+    where
+        T: Into<Period>,
+    {
+        Self::solve(timeout.into(), src_freq, MAX_CYCLES_16, true)
+    }
+
+    /// Computes the parameters for a 32-bit counter, which only needs a
+    /// prescaler for periods longer than 2^32 clock ticks.
+    pub fn new_32bit<T>(timeout: T, src_freq: u32) -> Result<Self, TimerParamsError>
+    where
+        T: Into<Period>,
+    {
+        Self::solve(timeout.into(), src_freq, MAX_CYCLES_32, false)
+    }
+
+    /// Computes the parameters like `new`, but clamps timeouts the
+    /// counter can't reach to its shortest or longest period.
+    pub(crate) fn new_saturating<T>(timeout: T, src_freq: u32, max_cycles: u32) -> Self
+    where
+        T: Into<Period>,
+    {
+        match Self::solve(timeout.into(), src_freq, max_cycles, false) {
+            Ok(params) => params,
+            Err(TimerParamsError::TooFast) => Self::from_parts(1, 1, src_freq),
+            Err(_) => Self::from_parts(1024, max_cycles, src_freq),
         }
+    }
+
+    /// The TOP value that makes the counter wrap around every `cycles`
+    /// ticks
+    pub fn top(&self) -> u32 {
+        self.cycles - 1
+    }
 
+    pub(crate) fn from_parts(divider: u16, cycles: u32, src_freq: u32) -> Self {
+        let ticks = divider as u64 * cycles as u64;
+        let freq = (src_freq as u64 + ticks / 2) / ticks;
         TimerParams {
-            divider: divider as u16,
+            divider,
             cycles,
+            freq: Hertz(freq as u32),
+            period: Period::from_ticks(ticks, Hertz(src_freq)),
         }
     }
+
+    /// How far the achieved period is from the requested one, as a
+    /// fraction so that candidates can be compared exactly with
+    /// `cmp_ratios`. Frequencies are matched by their frequency error,
+    /// spans of time by their period error.
+    pub(crate) fn error(&self, timeout: Period, src_freq: u32) -> (u64, u64) {
+        let ticks = self.divider as u64 * self.cycles as u64;
+        let (numerator, denominator) = timeout.ticks_ratio(Hertz(src_freq));
+        let difference = numerator.abs_diff(denominator * ticks);
+        match timeout {
+            Period::Frequency(_) => (difference, ticks),
+            Period::Nanoseconds(_) => (difference, 1),
+        }
+    }
+
+    pub(crate) fn solve(
+        timeout: Period,
+        src_freq: u32,
+        max_cycles: u32,
+        exhaustive: bool,
+    ) -> Result<Self, TimerParamsError> {
+        if timeout == Period::Frequency(Hertz(0)) {
+            return Err(TimerParamsError::ZeroFrequency);
+        }
+        // The period is numerator / denominator clock ticks long
+        let (numerator, denominator) = timeout.ticks_ratio(Hertz(src_freq));
+        if numerator < denominator {
+            return Err(TimerParamsError::TooFast);
+        }
+
+        let mut best: Option<TimerParams> = None;
+        for &divider in DIVIDERS.iter() {
+            let ticks = divider as u64 * denominator;
+            // Round to the nearest whole number of ticks
+            let cycles = numerator.saturating_add(ticks / 2) / ticks;
+            if cycles == 0 || cycles > max_cycles as u64 {
+                continue;
+            }
+
+            let params = Self::from_parts(divider, cycles as u32, src_freq);
+            if !exhaustive {
+                return Ok(params);
+            }
+            best = match best {
+                Some(best) => {
+                    let (a, b) = best.error(timeout, src_freq);
+                    let (c, d) = params.error(timeout, src_freq);
+                    // Keep the smaller prescaler on a tie
+                    if cmp_ratios(a, b, c, d) != Ordering::Greater {
+                        Some(best)
+                    } else {
+                        Some(params)
+                    }
+                }
+                None => Some(params),
+            };
+        }
+        best.ok_or(TimerParamsError::TooSlow)
+    }
 }
 
+
 tc! {
     TimerCounter2: (TC2, tc2_, Tc2Tc3Clock, apbbmask),
     TimerCounter3: (TC3, tc3_, Tc2Tc3Clock, apbbmask),
@@ -225,3 +858,107 @@ tc! {
     TimerCounter4: (TC4, tc4_, Tc4Tc5Clock, apbcmask),
     TimerCounter5: (TC5, tc5_, Tc4Tc5Clock, apbcmask),
 }
+
+tc32! {
+    TimerCounter0_1: (TC0, TC1, tc0_tc1, tc0_, tc1_, Tc0Tc1Clock, apbamask),
+    TimerCounter2_3: (TC2, TC3, tc2_tc3, tc2_, tc3_, Tc2Tc3Clock, apbbmask),
+}
+
+// Only the G variants are missing these timers
+#[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
+tc32! {
+    TimerCounter4_5: (TC4, TC5, tc4_tc5, tc4_, tc5_, Tc4Tc5Clock, apbcmask),
+}
+
+tc_monotonic! {
+    Tc0Tc1Monotonic: (TC0, TC1, TC0_TC1_TIME_BASE),
+    Tc2Tc3Monotonic: (TC2, TC3, TC2_TC3_TIME_BASE),
+}
+
+// Only the G variants are missing these timers
+#[cfg(all(not(feature = "samd51g19a"), not(feature = "samd51g18a")))]
+tc_monotonic! {
+    Tc4Tc5Monotonic: (TC4, TC5, TC4_TC5_TIME_BASE),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{Microseconds, Milliseconds, Seconds};
+
+    #[test]
+    fn unreachable_periods() {
+        assert_eq!(
+            TimerParams::new(Hertz(0), 48_000_000).err(),
+            Some(TimerParamsError::ZeroFrequency)
+        );
+        assert_eq!(
+            TimerParams::new(Hertz(96_000_000), 48_000_000).err(),
+            Some(TimerParamsError::TooFast)
+        );
+        // 96M ticks don't fit 16 bits, even with the 1024 prescaler
+        assert_eq!(
+            TimerParams::new(Milliseconds(2_000), 48_000_000).err(),
+            Some(TimerParamsError::TooSlow)
+        );
+        assert!(TimerParams::new_32bit(Milliseconds(2_000), 48_000_000).is_ok());
+    }
+
+    #[test]
+    fn smallest_prescaler_that_fits() {
+        // With a 1MHz clock every microsecond is a tick
+        for &(fits, gap, next) in [(16, 32, 64), (64, 128, 256), (256, 512, 1024)].iter() {
+            let params = TimerParams::new(Microseconds(fits * MAX_CYCLES_16), 1_000_000).unwrap();
+            assert_eq!((params.divider as u32, params.cycles), (fits, MAX_CYCLES_16));
+
+            let params = TimerParams::new(Microseconds(gap * MAX_CYCLES_16), 1_000_000).unwrap();
+            assert_eq!(
+                (params.divider as u32, params.cycles),
+                (next, MAX_CYCLES_16 / 2)
+            );
+        }
+    }
+
+    #[test]
+    fn top_is_one_less_than_cycles() {
+        let params = TimerParams::new(Hertz(1_000), 48_000_000).unwrap();
+        assert_eq!((params.divider, params.cycles, params.top()), (1, 48_000, 47_999));
+        assert_eq!(params.freq, Hertz(1_000));
+
+        let params = TimerParams::new(Microseconds(MAX_CYCLES_16), 1_000_000).unwrap();
+        assert_eq!(params.top(), u16::max_value() as u32);
+    }
+
+    #[test]
+    fn exact_search_beats_smallest_prescaler() {
+        // 48MHz / 45134Hz is just short of 1063.5 ticks
+        let params = TimerParams::new(Hertz(45_134), 48_000_000).unwrap();
+        assert_eq!((params.divider, params.cycles), (1, 1063));
+        let exact = TimerParams::new_exact(Hertz(45_134), 48_000_000).unwrap();
+        assert_eq!((exact.divider, exact.cycles), (2, 532));
+
+        let (a, b) = params.error(Hertz(45_134).into(), 48_000_000);
+        let (c, d) = exact.error(Hertz(45_134).into(), 48_000_000);
+        assert_eq!(cmp_ratios(c, d, a, b), Ordering::Less);
+    }
+
+    #[test]
+    fn achieved_period_of_long_timeouts() {
+        // Too long to be expressed in Hertz
+        let params = TimerParams::new_32bit(Seconds(600), 48_000_000).unwrap();
+        assert_eq!(params.freq, Hertz(0));
+        assert_eq!(params.period, Period::Nanoseconds(600_000_000_000));
+
+        let params = TimerParams::new(Hertz(45_134), 48_000_000).unwrap();
+        // 1063 ticks at 48MHz
+        assert_eq!(params.period, Period::Nanoseconds(22_146));
+    }
+
+    #[test]
+    fn ratios_compare_without_overflow() {
+        let max = u64::max_value();
+        assert_eq!(cmp_ratios(max - 1, max, max - 2, max - 1), Ordering::Greater);
+        assert_eq!(cmp_ratios(max, 3, max / 3, 1), Ordering::Equal);
+        assert_eq!(cmp_ratios(1, 3, 2, 5), Ordering::Less);
+    }
+}